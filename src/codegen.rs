@@ -0,0 +1,61 @@
+mod c;
+#[cfg(feature = "html")]
+mod html;
+mod js;
+mod python;
+
+#[cfg(feature = "html")]
+pub use self::html::HtmlCodeGen;
+pub use self::{
+    c::CCodeGen,
+    js::JsCodeGen,
+    python::PythonCodeGen,
+};
+
+/// Settings shared by the code generators.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeGenConfig {
+    /// The string written once per indentation level. Defaults to a tab.
+    pub indent: String,
+    /// Input to bake into the generated program instead of reading from stdin.
+    ///
+    /// Reads past the end of the input produce 0, like `DefaultHandler`.
+    /// `JsCodeGen` ignores it, since its function takes the input as an argument.
+    pub input: Option<Vec<u8>>,
+    /// The size of the cells. Defaults to 8 bits.
+    pub cell_width: CellWidth,
+}
+
+impl Default for CodeGenConfig {
+    fn default() -> Self {
+        Self {
+            indent: String::from("\t"),
+            input: None,
+            cell_width: CellWidth::U8,
+        }
+    }
+}
+
+/// The size of the cells in generated code. Output always writes the low byte of a cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CellWidth {
+    #[default]
+    U8,
+    U16,
+    U32,
+}
+
+impl CellWidth {
+    pub fn bits(self) -> u32 {
+        match self {
+            CellWidth::U8 => 8,
+            CellWidth::U16 => 16,
+            CellWidth::U32 => 32,
+        }
+    }
+
+    /// The number of values a cell can hold.
+    pub fn modulus(self) -> u64 {
+        1 << self.bits()
+    }
+}
@@ -0,0 +1,395 @@
+use super::{
+    CellWidth,
+    CodeGenConfig,
+};
+use crate::parser::Expr;
+use std::collections::HashMap;
+
+pub struct CCodeGen {
+    pub output: String,
+    tab_index: usize,
+    newline: bool,
+    pub config: CodeGenConfig,
+
+    // Output that is known at compile time but not written yet, so it can be batched into one `fwrite`.
+    pending: Vec<u8>,
+    // Known cell values, keyed by offset from the pointer at the last point the pointer was unknown.
+    known: HashMap<isize, u8>,
+    offset: isize,
+}
+
+impl Default for CCodeGen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CCodeGen {
+    pub fn new() -> Self {
+        Self {
+            output: String::new(),
+            tab_index: 0,
+            newline: true,
+            config: CodeGenConfig::default(),
+
+            pending: Vec::new(),
+            known: HashMap::new(),
+            offset: 0,
+        }
+    }
+
+    /// Bake `input` into the generated program instead of reading from stdin. Sets `config.input`.
+    pub fn with_input(&mut self, input: &[u8]) {
+        self.config.input = Some(input.to_vec());
+    }
+
+    pub fn write(&mut self, s: &str) {
+        for c in s.chars() {
+            if self.newline {
+                for _ in 0..self.tab_index {
+                    self.output.push_str(&self.config.indent);
+                }
+                self.newline = false;
+            }
+
+            if c == '\n' {
+                self.newline = true;
+            }
+            self.output.push(c);
+        }
+    }
+
+    pub fn write_preamble(&mut self, tape_size: usize) {
+        self.write(&format!(
+            "static uint{}_t tape[{}];\n",
+            self.config.cell_width.bits(),
+            tape_size
+        ));
+    }
+
+    fn write_input_preamble(&mut self, input: &[u8]) {
+        let bytes: Vec<String> = input.iter().map(|b| b.to_string()).collect();
+        self.write(&format!(
+            "static const uint8_t input_data[] = {{{}}};\n",
+            if bytes.is_empty() {
+                String::from("0")
+            } else {
+                bytes.join(", ")
+            }
+        ));
+        self.write(&format!(
+            "static const size_t input_len = {};\n",
+            input.len()
+        ));
+        self.write("static size_t input_index = 0;\n");
+    }
+
+    pub fn gen(&mut self, expr: &Expr) {
+        self.write("#include <stdint.h>\n");
+        self.write("#include <stdio.h>\n");
+        self.write("\n");
+
+        if expr.uses_memory() {
+            let tape_size = expr
+                .max_absolute_index()
+                .map_or(10000, |index| 10000.max(index + 1));
+            self.write_preamble(tape_size);
+        }
+
+        if let Some(input) = self.config.input.take() {
+            if expr.contains_read() {
+                self.write_input_preamble(&input);
+            }
+            self.config.input = Some(input);
+        }
+
+        self.write("\n");
+        self.write("int main(void) {\n");
+        self.tab_index += 1;
+
+        if expr.uses_memory() {
+            self.write("size_t cell_index = 0;\n");
+        }
+
+        self.forget();
+        self.gen_expr(expr);
+        self.flush();
+
+        self.write("return 0;\n");
+        self.tab_index -= 1;
+        self.write("}\n");
+    }
+
+    fn forget(&mut self) {
+        self.known.clear();
+        self.offset = 0;
+    }
+
+    fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let pending = std::mem::take(&mut self.pending);
+        self.write(&format!(
+            "fwrite(\"{}\", 1, {}, stdout);\n",
+            escape(&pending),
+            pending.len()
+        ));
+    }
+
+    fn add(&mut self, num: u8) {
+        // Known values are tracked as bytes, so they only stay correct for 8 bit cells
+        if self.config.cell_width != CellWidth::U8 {
+            self.known.remove(&self.offset);
+        }
+
+        if let Some(value) = self.known.get_mut(&self.offset) {
+            *value = value.wrapping_add(num);
+        }
+    }
+
+    fn gen_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Block { exprs } => {
+                for expr in exprs {
+                    self.gen_expr(expr);
+                }
+            }
+            Expr::Increment { num } => {
+                let num = *num as u64 % self.config.cell_width.modulus();
+                self.write(&format!("tape[cell_index] += {};\n", num));
+                self.add(num as u8);
+            }
+            Expr::Decrement { num } => {
+                let num = *num as u64 % self.config.cell_width.modulus();
+                self.write(&format!("tape[cell_index] -= {};\n", num));
+                self.add(0u8.wrapping_sub(num as u8));
+            }
+            Expr::ShiftRight { num } => {
+                self.write(&format!("cell_index += {};\n", num));
+                self.offset += *num as isize;
+            }
+            Expr::ShiftLeft { num } => {
+                self.write(&format!("cell_index -= {};\n", num));
+                self.offset -= *num as isize;
+            }
+            Expr::Loop { expr, .. } => {
+                self.flush();
+                self.write("while (tape[cell_index]) {\n");
+                self.tab_index += 1;
+                self.forget();
+                self.gen_expr(expr);
+                self.flush();
+                self.tab_index -= 1;
+                self.write("}\n");
+
+                self.forget();
+                self.known.insert(0, 0);
+            }
+            Expr::ReadChar => {
+                self.flush();
+                if self.config.input.is_some() {
+                    self.write("tape[cell_index] = input_index < input_len ? input_data[input_index++] : 0;\n");
+                } else {
+                    self.write("{\n");
+                    self.tab_index += 1;
+                    self.write("int c = getchar();\n");
+                    self.write("tape[cell_index] = c == EOF ? 0 : c;\n");
+                    self.tab_index -= 1;
+                    self.write("}\n");
+                }
+                self.known.remove(&self.offset);
+            }
+            Expr::PrintChar => match self.known.get(&self.offset) {
+                Some(value) => self.pending.push(*value),
+                None => {
+                    self.flush();
+                    self.write("putchar(tape[cell_index]);\n");
+                }
+            },
+            Expr::Assign { index, value } => {
+                self.write(&format!("tape[{}] = {};\n", index, value));
+                self.forget();
+            }
+            Expr::AssignCurrent { value } => {
+                self.write(&format!("tape[cell_index] = {};\n", value));
+                self.known.insert(self.offset, *value);
+            }
+            Expr::SetCellPointer { value } => {
+                self.write(&format!("cell_index = {};\n", value));
+                self.forget();
+            }
+            Expr::PrintString { value } => {
+                self.pending.extend(value.bytes());
+            }
+            Expr::ReadCharForget => {
+                self.flush();
+                if self.config.input.is_some() {
+                    self.write("input_index++;\n");
+                } else {
+                    self.write("getchar();\n");
+                }
+            }
+            Expr::DivMod => {
+                self.gen_expr(&Expr::div_mod_loop());
+            }
+            Expr::Comment { .. } | Expr::Breakpoint => {}
+        }
+    }
+}
+
+/// Escape bytes for a C string literal.
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::new();
+    for &b in bytes {
+        match b {
+            b'\\' => escaped.push_str("\\\\"),
+            b'"' => escaped.push_str("\\\""),
+            // Avoid trigraphs
+            b'?' => escaped.push_str("\\?"),
+            b'\n' => escaped.push_str("\\n"),
+            b' '..=b'~' => escaped.push(char::from(b)),
+            _ => escaped.push_str(&format!("\\{:03o}", b)),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        test_util::parse,
+        *,
+    };
+    use std::process::{
+        Command,
+        Stdio,
+    };
+
+    /// Compile and run C source, returning `None` if no C compiler is available.
+    fn run_c(name: &str, source: &str, input: &[u8]) -> Option<Vec<u8>> {
+        use std::io::Write;
+
+        let dir = std::env::temp_dir().join(format!("bf-c-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source_path = dir.join("main.c");
+        let exe_path = dir.join("main");
+        std::fs::write(&source_path, source).unwrap();
+
+        let status = Command::new("cc")
+            .arg("-o")
+            .arg(&exe_path)
+            .arg(&source_path)
+            .status()
+            .ok()?;
+        assert!(status.success(), "failed to compile:\n{}", source);
+
+        let mut child = Command::new(&exe_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // Programs that never read may exit before the input is written
+        let _ = child.stdin.take().unwrap().write_all(input);
+        let output = child.wait_with_output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(output.status.success());
+        Some(output.stdout)
+    }
+
+    #[test]
+    fn c_print_string_batched() {
+        let mut o = Optimizer::new(parse(include_str!("../../test_data/hello_world1.bf")));
+        o.add_pass(ZeroLoopOptimizer);
+        o.add_pass(SpecExecOptimizer::default());
+        o.optimize();
+
+        let mut codegen = CCodeGen::new();
+        codegen.gen(&o.expr);
+
+        assert_eq!(codegen.output.matches("fwrite(").count(), 1);
+        assert!(codegen
+            .output
+            .contains("fwrite(\"Hello World!\\n\", 1, 13, stdout);\n"));
+        assert!(!codegen.output.contains("putchar"));
+
+        if let Some(output) = run_c("print_string", &codegen.output, b"") {
+            assert_eq!(output, b"Hello World!\n");
+        }
+    }
+
+    #[test]
+    fn c_known_cells_batched() {
+        let mut codegen = CCodeGen::new();
+        codegen.gen(&parse("[-]+++.+.>.[.-]"));
+
+        assert!(codegen
+            .output
+            .contains("fwrite(\"\\003\\004\", 1, 2, stdout);\n"));
+        assert_eq!(codegen.output.matches("putchar").count(), 2);
+    }
+
+    #[test]
+    fn c_cell_width() {
+        let mut codegen = CCodeGen::new();
+        codegen.config.cell_width = CellWidth::U16;
+        codegen.gen(&parse(include_str!("../../test_data/factorial.bf")));
+        assert!(codegen.output.contains("static uint16_t tape[10000];\n"));
+
+        let expected = crate::reference::run_with_cell_width(
+            include_str!("../../test_data/factorial.bf"),
+            b"",
+            CellWidth::U16,
+        );
+        assert_ne!(
+            expected,
+            crate::reference::run(include_str!("../../test_data/factorial.bf"), b"")
+        );
+        if let Some(output) = run_c("factorial16", &codegen.output, b"") {
+            assert_eq!(output, expected);
+        }
+
+        // 256 only wraps to 0 with 8 bit cells
+        let src = format!("{}[>{}.<[-]]", "+".repeat(256), "+".repeat(65));
+        let mut codegen = CCodeGen::new();
+        codegen.config.cell_width = CellWidth::U16;
+        codegen.gen(&parse(&src));
+        assert!(codegen.output.contains("tape[cell_index] += 256;\n"));
+        if let Some(output) = run_c("wide16", &codegen.output, b"") {
+            assert_eq!(output, b"A");
+        }
+    }
+
+    #[test]
+    fn c_matches_interpreter() {
+        let programs = [
+            include_str!("../../test_data/hello_world1.bf"),
+            include_str!("../../test_data/count_down.bf"),
+            include_str!("../../test_data/squares.bf"),
+            ",[.,]",
+        ];
+
+        for (i, program) in programs.iter().enumerate() {
+            let mut vm = Interpreter::new(RecordingHandler::new());
+            vm.run_with_input(&parse(program), b"echo").unwrap();
+            let expected = vm.handler.output();
+
+            let mut codegen = CCodeGen::new();
+            codegen.gen(&parse(program));
+            if let Some(output) = run_c(&format!("program{}", i), &codegen.output, b"echo") {
+                assert_eq!(output, expected);
+            }
+
+            let mut codegen = CCodeGen::new();
+            codegen.with_input(b"echo");
+            codegen.gen(&parse(program));
+            if let Some(output) = run_c(&format!("program{}_input", i), &codegen.output, b"") {
+                assert_eq!(output, expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use super::js::JsCodeGen;
+use crate::parser::Expr;
+
+/// Generates a self-contained HTML page that runs the program in the browser.
+///
+/// The page has a textarea for input and a button that runs the program with it.
+pub struct HtmlCodeGen {
+    pub output: String,
+    title: String,
+}
+
+impl Default for HtmlCodeGen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HtmlCodeGen {
+    pub fn new() -> Self {
+        Self {
+            output: String::new(),
+            title: String::from("Brainfuck"),
+        }
+    }
+
+    /// Set the title of the page. Defaults to `Brainfuck`.
+    pub fn set_title(&mut self, title: &str) {
+        self.title = title.to_string();
+    }
+
+    pub fn gen(&mut self, expr: &Expr) {
+        let mut js = JsCodeGen::new();
+        js.gen(expr);
+
+        let title = escape(&self.title);
+        self.output.push_str("<!DOCTYPE html>\n");
+        self.output.push_str("<html>\n");
+        self.output.push_str("<head>\n");
+        self.output.push_str("<meta charset=\"utf-8\">\n");
+        self.output.push_str(&format!("<title>{}</title>\n", title));
+        self.output.push_str("</head>\n");
+        self.output.push_str("<body>\n");
+        self.output.push_str(&format!("<h1>{}</h1>\n", title));
+        self.output
+            .push_str("<textarea id=\"input\" rows=\"8\" cols=\"80\"></textarea>\n");
+        self.output.push_str("<br>\n");
+        self.output
+            .push_str("<button id=\"run\" type=\"button\">Run</button>\n");
+        self.output.push_str("<pre id=\"output\"></pre>\n");
+        self.output.push_str("<script>\n");
+        self.output.push_str(&js.output);
+        self.output
+            .push_str("document.getElementById('run').addEventListener('click', function () {\n");
+        self.output.push_str(&format!(
+            "\tdocument.getElementById('output').textContent = {}(document.getElementById('input').value);\n",
+            js.function_name()
+        ));
+        self.output.push_str("});\n");
+        self.output.push_str("</script>\n");
+        self.output.push_str("</body>\n");
+        self.output.push_str("</html>\n");
+    }
+}
+
+/// Escape text for HTML element content.
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn html_count_down() {
+        let mut l = Lexer::new(include_str!("../../test_data/count_down.bf"));
+        l.lex().unwrap();
+        let exprs = Parser::new(l.tokens).parse().unwrap();
+
+        let mut js = JsCodeGen::new();
+        js.gen(&exprs);
+
+        let mut codegen = HtmlCodeGen::new();
+        codegen.gen(&exprs);
+
+        assert!(codegen.output.starts_with("<!DOCTYPE html>\n"));
+        assert!(codegen.output.contains("<script>\n"));
+        assert!(codegen.output.contains(&js.output));
+        assert!(codegen.output.contains("<textarea id=\"input\""));
+        assert!(codegen.output.contains("<button id=\"run\""));
+    }
+}
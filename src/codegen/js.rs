@@ -0,0 +1,210 @@
+use super::CodeGenConfig;
+use crate::parser::Expr;
+
+/// Generates a JavaScript function that takes the program input as a string and returns its output.
+pub struct JsCodeGen {
+    pub output: String,
+    tab_index: usize,
+    newline: bool,
+    pub config: CodeGenConfig,
+    function_name: String,
+}
+
+impl Default for JsCodeGen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JsCodeGen {
+    pub fn new() -> Self {
+        Self {
+            output: String::new(),
+            tab_index: 0,
+            newline: true,
+            config: CodeGenConfig::default(),
+            function_name: String::from("run"),
+        }
+    }
+
+    /// Set the name of the generated function. Defaults to `run`.
+    pub fn set_function_name(&mut self, name: &str) {
+        self.function_name = name.to_string();
+    }
+
+    pub fn function_name(&self) -> &str {
+        &self.function_name
+    }
+
+    pub fn write(&mut self, s: &str) {
+        for c in s.chars() {
+            if self.newline {
+                for _ in 0..self.tab_index {
+                    self.output.push_str(&self.config.indent);
+                }
+                self.newline = false;
+            }
+
+            if c == '\n' {
+                self.newline = true;
+            }
+            self.output.push(c);
+        }
+    }
+
+    pub fn write_preamble(&mut self, tape_size: usize) {
+        self.write(&format!(
+            "const cells = new Uint{}Array({});\n",
+            self.config.cell_width.bits(),
+            tape_size
+        ));
+        self.write("let cell_index = 0;\n");
+    }
+
+    pub fn gen(&mut self, expr: &Expr) {
+        self.write(&format!("function {}(input) {{\n", self.function_name));
+        self.tab_index += 1;
+
+        if expr.uses_memory() {
+            let tape_size = expr
+                .max_absolute_index()
+                .map_or(10000, |index| 10000.max(index + 1));
+            self.write_preamble(tape_size);
+        }
+
+        if expr.contains_read() {
+            self.write("let input_index = 0;\n");
+        }
+
+        self.write("let output = '';\n");
+        self.gen_expr(expr);
+        self.write("return output;\n");
+
+        self.tab_index -= 1;
+        self.write("}\n");
+    }
+
+    fn gen_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Block { exprs } => {
+                for expr in exprs {
+                    self.gen_expr(expr);
+                }
+            }
+            Expr::Increment { num } => {
+                let num = *num as u64 % self.config.cell_width.modulus();
+                self.write(&format!("cells[cell_index] += {};\n", num));
+            }
+            Expr::Decrement { num } => {
+                let num = *num as u64 % self.config.cell_width.modulus();
+                self.write(&format!("cells[cell_index] -= {};\n", num));
+            }
+            Expr::ShiftRight { num } => {
+                self.write(&format!("cell_index += {};\n", num));
+            }
+            Expr::ShiftLeft { num } => {
+                self.write(&format!("cell_index -= {};\n", num));
+            }
+            Expr::Loop { expr, .. } => {
+                self.write("while (cells[cell_index] !== 0) {\n");
+                self.tab_index += 1;
+                self.gen_expr(expr);
+                self.tab_index -= 1;
+                self.write("}\n");
+            }
+            Expr::ReadChar => {
+                self.write("cells[cell_index] = input_index < input.length ? input.charCodeAt(input_index++) : 0;\n");
+            }
+            Expr::PrintChar => {
+                self.write("output += String.fromCharCode(cells[cell_index] & 255);\n");
+            }
+            Expr::Assign { index, value } => {
+                self.write(&format!("cells[{}] = {};\n", index, value));
+            }
+            Expr::AssignCurrent { value } => {
+                self.write(&format!("cells[cell_index] = {};\n", value));
+            }
+            Expr::SetCellPointer { value } => {
+                self.write(&format!("cell_index = {};\n", value));
+            }
+            Expr::PrintString { value } => {
+                self.write(&format!("output += {};\n", quote(value)));
+            }
+            Expr::ReadCharForget => {
+                self.write("input_index++;\n");
+            }
+            Expr::DivMod => {
+                self.gen_expr(&Expr::div_mod_loop());
+            }
+            Expr::Comment { .. } | Expr::Breakpoint => {}
+        }
+    }
+}
+
+/// Quote a string as a JavaScript string literal.
+fn quote(s: &str) -> String {
+    let mut quoted = String::from("'");
+    for c in s.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '\'' => quoted.push_str("\\'"),
+            '\n' => quoted.push_str("\\n"),
+            // Keep `</script>` from ending an inline script early
+            '<' => quoted.push_str("\\x3c"),
+            ' '..='~' => quoted.push(c),
+            _ => quoted.push_str(&format!("\\u{{{:x}}}", c as u32)),
+        }
+    }
+    quoted.push('\'');
+
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        test_util::parse,
+        *,
+    };
+
+    #[test]
+    fn js_matches_interpreter() {
+        let mut optimized = Optimizer::new(parse(include_str!("../../test_data/hello_world1.bf")));
+        optimized.add_pass(ZeroLoopOptimizer);
+        optimized.add_pass(SpecExecOptimizer::default());
+        optimized.optimize();
+
+        let programs = [
+            optimized.expr,
+            parse(include_str!("../../test_data/hello_world1.bf")),
+            parse(include_str!("../../test_data/count_down.bf")),
+            parse(",[.,]"),
+        ];
+
+        for expr in programs.iter() {
+            let mut vm = Interpreter::new(RecordingHandler::new());
+            vm.run_with_input(expr, b"echo").unwrap();
+
+            let mut codegen = JsCodeGen::new();
+            codegen.gen(expr);
+            assert!(codegen.output.starts_with("function run(input) {\n"));
+
+            let script = format!(
+                "{}process.stdout.write(run('echo'), 'latin1');\n",
+                codegen.output
+            );
+            let output = match std::process::Command::new("node")
+                .args(["-e", &script])
+                .output()
+            {
+                Ok(output) => output,
+                // Node isn't available
+                Err(_) => return,
+            };
+
+            assert!(output.status.success());
+            assert_eq!(output.stdout, vm.handler.output());
+        }
+    }
+}
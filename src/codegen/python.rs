@@ -0,0 +1,305 @@
+use super::CodeGenConfig;
+use crate::parser::Expr;
+
+pub struct PythonCodeGen {
+    pub output: String,
+    tab_index: usize,
+    newline: bool,
+    pub config: CodeGenConfig,
+    tape_size: usize,
+}
+
+impl Default for PythonCodeGen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PythonCodeGen {
+    pub fn new() -> Self {
+        Self {
+            output: String::new(),
+            tab_index: 0,
+            newline: true,
+            config: CodeGenConfig::default(),
+            tape_size: 10000,
+        }
+    }
+
+    /// Bake `input` into the generated program instead of reading from stdin. Sets `config.input`.
+    pub fn with_input(&mut self, input: &[u8]) {
+        self.config.input = Some(input.to_vec());
+    }
+
+    /// Set the number of cells allocated up front. Defaults to 10000.
+    ///
+    /// The tape is made larger if the program assigns to a cell past the end.
+    pub fn set_tape_size(&mut self, tape_size: usize) {
+        self.tape_size = tape_size;
+    }
+
+    pub fn write(&mut self, s: &str) {
+        for c in s.chars() {
+            if self.newline {
+                for _ in 0..self.tab_index {
+                    self.output.push_str(&self.config.indent);
+                }
+                self.newline = false;
+            }
+
+            match c {
+                '\n' => {
+                    self.newline = true;
+                    self.output.push(c);
+                }
+                _ => {
+                    self.output.push(c);
+                }
+            }
+        }
+    }
+
+    pub fn write_preamble(&mut self, tape_size: usize) {
+        self.write("cells = []\n");
+        self.write(&format!("for i in range(0, {}):\n", tape_size));
+        self.tab_index += 1;
+        self.write("cells.append(0)\n");
+        self.tab_index -= 1;
+
+        self.write("cell_index = 0\n");
+    }
+
+    fn write_input_preamble(&mut self, input: &[u8]) {
+        let bytes: Vec<String> = input.iter().map(|b| b.to_string()).collect();
+        self.write(&format!("input_data = [{}]\n", bytes.join(", ")));
+        self.write("input_index = 0\n");
+    }
+
+    pub fn gen(&mut self, expr: &Expr) {
+        if expr.uses_memory() {
+            let tape_size = expr
+                .max_absolute_index()
+                .map_or(self.tape_size, |index| self.tape_size.max(index + 1));
+            self.write_preamble(tape_size);
+        }
+
+        if let Some(input) = self.config.input.take() {
+            if expr.contains_read() {
+                self.write_input_preamble(&input);
+            }
+            self.config.input = Some(input);
+        }
+
+        self.gen_expr(expr);
+    }
+
+    fn gen_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Block { exprs } => {
+                for expr in exprs {
+                    self.gen_expr(expr);
+                }
+            }
+            Expr::Increment { num } => {
+                self.write(&format!(
+                    "cells[cell_index] = (cells[cell_index] + {}) % {}\n",
+                    num,
+                    self.config.cell_width.modulus()
+                ));
+            }
+            Expr::Decrement { num } => {
+                self.write(&format!(
+                    "cells[cell_index] = (cells[cell_index] - {}) % {}\n",
+                    num,
+                    self.config.cell_width.modulus()
+                ));
+            }
+            Expr::ShiftRight { num } => {
+                self.write(&format!("cell_index += {}\n", num));
+            }
+            Expr::ShiftLeft { num } => {
+                self.write(&format!("cell_index -= {}\n", num));
+            }
+            Expr::Loop { expr, .. } => {
+                self.write("while cells[cell_index] != 0:\n");
+                self.tab_index += 1;
+                self.gen_expr(expr);
+                self.tab_index -= 1;
+            }
+            Expr::ReadChar if self.config.input.is_some() => {
+                self.write("if input_index < len(input_data):\n");
+                self.tab_index += 1;
+                self.write("cells[cell_index] = input_data[input_index]\n");
+                self.write("input_index += 1\n");
+                self.tab_index -= 1;
+                self.write("else:\n");
+                self.tab_index += 1;
+                self.write("cells[cell_index] = 0\n");
+                self.tab_index -= 1;
+            }
+            Expr::ReadChar => {
+                self.write("cells[cell_index] = ord((input() + ' ')[0])\n");
+            }
+            Expr::PrintChar => {
+                self.write("print(chr(cells[cell_index] % 256), end='')\n");
+            }
+            Expr::Assign { index, value } => {
+                self.write(&format!("cells[{}] = {}\n", index, value));
+            }
+            Expr::AssignCurrent { value } => {
+                self.write(&format!("cells[cell_index] = {}\n", value));
+            }
+            Expr::SetCellPointer { value } => {
+                self.write(&format!("cell_index = {}\n", value));
+            }
+            Expr::PrintString { value } => {
+                self.write(&format!("print('{}', end='')\n", value));
+            }
+            Expr::ReadCharForget if self.config.input.is_some() => {
+                self.write("input_index += 1\n");
+            }
+            Expr::ReadCharForget => {
+                self.write("input()\n");
+            }
+            Expr::DivMod => {
+                self.gen_expr(&Expr::div_mod_loop());
+            }
+            Expr::Comment { .. } | Expr::Breakpoint => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+    use std::io;
+
+    struct TestHandler {
+        out: String,
+    }
+
+    impl TestHandler {
+        fn new() -> Self {
+            Self { out: String::new() }
+        }
+    }
+
+    impl Handler for TestHandler {
+        fn write_char(&mut self, c: u8) -> io::Result<()> {
+            self.out.push(char::from(c));
+            Ok(())
+        }
+    }
+
+    fn test_output_optimized_py(data: &str, expected: &str) {
+        let mut l = Lexer::new(data);
+        l.lex().unwrap();
+
+        let mut p = Parser::new(l.tokens);
+        let exprs = p.parse().unwrap();
+
+        let mut o = Optimizer::new(exprs);
+        o.add_pass(ZeroLoopOptimizer);
+        o.add_pass(SpecExecOptimizer::default());
+        o.optimize();
+
+        let exprs = o.expr;
+
+        let mut codegen = PythonCodeGen::new();
+        codegen.gen(&exprs);
+        // std::fs::write("test.py", &codegen.output).unwrap();
+
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.run(&exprs).unwrap();
+
+        dbg!(&exprs);
+
+        assert_eq!(vm.handler.out.as_str(), expected);
+    }
+
+    #[test]
+    fn python_indent() {
+        let mut l = Lexer::new("+[-[>]]");
+        l.lex().unwrap();
+        let exprs = Parser::new(l.tokens).parse().unwrap();
+
+        let mut codegen = PythonCodeGen::new();
+        codegen.config.indent = String::from("    ");
+        codegen.gen(&exprs);
+
+        assert!(codegen.output.contains("\n    cells.append(0)\n"));
+        assert!(codegen.output.contains("\n        cell_index += 1\n"));
+        assert!(!codegen.output.contains('\t'));
+    }
+
+    #[test]
+    fn python_far_assign() {
+        let exprs = Expr::Block {
+            exprs: vec![
+                Expr::Assign {
+                    index: 20000,
+                    value: 65,
+                },
+                Expr::SetCellPointer { value: 20000 },
+                Expr::PrintChar,
+            ],
+        };
+
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.run(&exprs).unwrap();
+        assert_eq!(vm.handler.out, "A");
+        assert_eq!(vm.cells().len(), 20001);
+
+        let mut codegen = PythonCodeGen::new();
+        codegen.gen(&exprs);
+        assert!(codegen.output.contains("for i in range(0, 20001):\n"));
+
+        let output = match std::process::Command::new("python3")
+            .args(["-c", &codegen.output])
+            .output()
+        {
+            Ok(output) => output,
+            // Python isn't available, the generated source was checked above
+            Err(_) => return,
+        };
+
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"A");
+    }
+
+    #[test]
+    fn python_with_input() {
+        let mut l = Lexer::new(",.,.");
+        l.lex().unwrap();
+        let exprs = Parser::new(l.tokens).parse().unwrap();
+
+        let mut codegen = PythonCodeGen::new();
+        codegen.with_input(b"AB");
+        codegen.gen(&exprs);
+
+        assert!(codegen.output.contains("input_data = [65, 66]\n"));
+        assert!(!codegen.output.contains("input()"));
+
+        let output = match std::process::Command::new("python3")
+            .args(["-c", &codegen.output])
+            .stdin(std::process::Stdio::null())
+            .output()
+        {
+            Ok(output) => output,
+            // Python isn't available, the generated source was checked above
+            Err(_) => return,
+        };
+
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"AB");
+    }
+
+    #[test]
+    fn aids_optimized_py() {
+        test_output_optimized_py(
+            include_str!("../../test_data/aids.bf"),
+            "How are you?I fucked a cheese burger",
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{
+    interpreter::{
+        Handler,
+        Interpreter,
+        RuntimeError,
+        RuntimeErrorKind,
+        StepStatus,
+    },
+    parser::Expr,
+    program::Program,
+    repl::RunError,
+    v1,
+    Lexer,
+    Parser,
+};
+use std::{
+    cell::RefCell,
+    io,
+};
+
+/// A way of running programs, so callers and benchmarks can swap backends without changing their glue.
+pub trait Engine {
+    /// What a program is turned into before it runs.
+    type Program;
+
+    /// Prepare `src` for running.
+    fn compile(&self, src: &str) -> Result<Self::Program, RunError>;
+
+    /// Run `program` on a fresh tape, reading and writing through `handler`.
+    fn run(
+        &mut self,
+        program: &Self::Program,
+        handler: &mut dyn Handler,
+    ) -> Result<(), RuntimeError>;
+}
+
+fn parse(src: &str) -> Result<Expr, RunError> {
+    let mut lexer = Lexer::new(src);
+    lexer.lex()?;
+    Ok(Parser::new(lexer.tokens).parse()?)
+}
+
+/// Runs the parsed `Expr` tree with `Interpreter::run`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ExprEngine;
+
+impl Engine for ExprEngine {
+    type Program = Expr;
+
+    fn compile(&self, src: &str) -> Result<Expr, RunError> {
+        parse(src)
+    }
+
+    fn run(&mut self, program: &Expr, handler: &mut dyn Handler) -> Result<(), RuntimeError> {
+        Interpreter::new(handler).run(program)
+    }
+}
+
+/// Runs a flattened `Program` with `Interpreter::step`.
+///
+/// Fails with `io::ErrorKind::WouldBlock` if the handler's input isn't ready, since nothing else can feed it.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProgramEngine;
+
+impl Engine for ProgramEngine {
+    type Program = Program;
+
+    fn compile(&self, src: &str) -> Result<Program, RunError> {
+        Ok(Program::compile(&parse(src)?))
+    }
+
+    fn run(&mut self, program: &Program, handler: &mut dyn Handler) -> Result<(), RuntimeError> {
+        let mut vm = Interpreter::new(handler);
+        loop {
+            match vm.step(program)? {
+                StepStatus::Running => {}
+                StepStatus::Done => return Ok(()),
+                StepStatus::Blocked => {
+                    return Err(RuntimeErrorKind::Io(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        "the handler has no input ready",
+                    ))
+                    .into())
+                }
+            }
+        }
+    }
+}
+
+/// Runs the original `v1::Interpreter`.
+///
+/// It has no error handling of its own, so moving left of cell 0 or decrementing 0 panics in debug builds.
+/// Only the handler's reads and writes are called, and the first failing one ends the run with `RuntimeErrorKind::Io`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct V1Engine;
+
+impl Engine for V1Engine {
+    type Program = Vec<v1::Instruction>;
+
+    fn compile(&self, src: &str) -> Result<Vec<v1::Instruction>, RunError> {
+        let instructions: Vec<_> = src.chars().filter_map(v1::Instruction::from_char).collect();
+
+        // v1 panics on unmatched brackets, so catch them here
+        let mut depth = Vec::new();
+        for (i, instruction) in instructions.iter().enumerate() {
+            if instruction.is_start_loop() {
+                depth.push(i);
+            } else if instruction.is_end_loop() && depth.pop().is_none() {
+                return Err(RuntimeError::from(RuntimeErrorKind::UnmatchedBracket {
+                    token: i,
+                    offset: None,
+                })
+                .into());
+            }
+        }
+        if let Some(&token) = depth.last() {
+            return Err(RuntimeError::from(RuntimeErrorKind::UnmatchedBracket {
+                token,
+                offset: None,
+            })
+            .into());
+        }
+
+        Ok(instructions)
+    }
+
+    fn run(
+        &mut self,
+        program: &Vec<v1::Instruction>,
+        handler: &mut dyn Handler,
+    ) -> Result<(), RuntimeError> {
+        let handler = RefCell::new(handler);
+        let error = RefCell::new(None);
+        let fail = |e| {
+            error.borrow_mut().get_or_insert(e);
+        };
+
+        let input_func = || {
+            if error.borrow().is_some() {
+                return 0;
+            }
+            handler.borrow_mut().read_char().unwrap_or_else(|e| {
+                fail(e);
+                0
+            })
+        };
+        let output_func = |c| {
+            if error.borrow().is_some() {
+                return;
+            }
+            if let Err(e) = handler.borrow_mut().write_char(c) {
+                fail(e);
+            }
+        };
+
+        let mut vm = v1::Interpreter::new();
+        vm.set_input_func(&input_func);
+        vm.set_output_func(&output_func);
+        vm.exec(program);
+
+        match error.into_inner() {
+            Some(e) => Err(RuntimeErrorKind::Io(e).into()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+
+    fn run<E: Engine>(mut engine: E, src: &str, input: &[u8]) -> Vec<u8> {
+        let program = engine.compile(src).unwrap();
+        let mut handler = SliceInputHandler::new(input);
+        engine.run(&program, &mut handler).unwrap();
+        handler.output().to_vec()
+    }
+
+    #[test]
+    fn engines_agree() {
+        let programs = [
+            include_str!("../test_data/hello_world1.bf"),
+            include_str!("../test_data/squares.bf"),
+            ",[.,]",
+        ];
+
+        for program in programs.iter() {
+            let expected = run(ExprEngine, program, b"echo");
+            assert!(!expected.is_empty());
+            assert_eq!(run(ProgramEngine, program, b"echo"), expected);
+            assert_eq!(run(V1Engine, program, b"echo"), expected);
+        }
+    }
+
+    #[test]
+    fn v1_unmatched() {
+        assert!(matches!(
+            V1Engine.compile("+]"),
+            Err(RunError::Runtime(e))
+                if matches!(e.kind(), RuntimeErrorKind::UnmatchedBracket { token: 1, .. })
+        ));
+        assert!(matches!(
+            V1Engine.compile("[[]"),
+            Err(RunError::Runtime(e))
+                if matches!(e.kind(), RuntimeErrorKind::UnmatchedBracket { token: 0, .. })
+        ));
+    }
+}
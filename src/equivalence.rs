@@ -0,0 +1,115 @@
+use crate::{
+    interpreter::{
+        Interpreter,
+        RecordingHandler,
+        RuntimeErrorKind,
+    },
+    parser::Expr,
+};
+use std::panic::{
+    self,
+    AssertUnwindSafe,
+};
+
+/// Operations and loop iterations each program may run per trial.
+const FUEL: u64 = 1_000_000;
+/// The longest random input given to the programs.
+const MAX_INPUT_LEN: usize = 32;
+
+/// A xorshift64 generator, good enough for picking test inputs.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Xorshift gets stuck on 0
+        Self(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+enum Outcome {
+    Finished,
+    OutOfFuel,
+    Failed,
+}
+
+fn run(expr: &Expr, input: &[u8]) -> (Vec<u8>, Outcome) {
+    let mut vm = Interpreter::new(RecordingHandler::new());
+    vm.set_fuel(Some(FUEL));
+
+    let outcome = match panic::catch_unwind(AssertUnwindSafe(|| vm.run_with_input(expr, input))) {
+        Ok(Ok(())) => Outcome::Finished,
+        Ok(Err(e)) if matches!(e.kind(), RuntimeErrorKind::FuelExhausted) => Outcome::OutOfFuel,
+        Ok(Err(_)) | Err(_) => Outcome::Failed,
+    };
+
+    (vm.handler.output().to_vec(), outcome)
+}
+
+/// Whether `a` and `b` produce the same output for `trials` random inputs generated from `seed`.
+///
+/// Both programs get the same step limit. If either runs out, only the output they both got to is compared,
+/// since an optimized program may get further than the original. Reads past the end of the input produce 0.
+pub fn programs_equivalent(a: &Expr, b: &Expr, trials: usize, seed: u64) -> bool {
+    let mut rng = Rng::new(seed);
+
+    for _ in 0..trials {
+        let len = (rng.next() % (MAX_INPUT_LEN as u64 + 1)) as usize;
+        let input: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+
+        let (a_output, a_outcome) = run(a, &input);
+        let (b_output, b_outcome) = run(b, &input);
+
+        let equivalent = match (a_outcome, b_outcome) {
+            (Outcome::OutOfFuel, _) | (_, Outcome::OutOfFuel) => {
+                let len = a_output.len().min(b_output.len());
+                a_output[..len] == b_output[..len]
+            }
+            (Outcome::Finished, Outcome::Finished) | (Outcome::Failed, Outcome::Failed) => {
+                a_output == b_output
+            }
+            _ => false,
+        };
+
+        if !equivalent {
+            return false;
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        test_util::parse,
+        *,
+    };
+
+    #[test]
+    fn optimized_is_equivalent() {
+        let expr = parse(include_str!("../test_data/hello_world1.bf"));
+        let mut o = Optimizer::new(expr.clone());
+        o.add_pass(ZeroLoopOptimizer);
+        o.add_pass(SpecExecOptimizer::default());
+        o.optimize();
+
+        assert_ne!(expr, o.expr);
+        assert!(programs_equivalent(&expr, &o.expr, 8, 1));
+    }
+
+    #[test]
+    fn input_dependent() {
+        assert!(programs_equivalent(&parse(",[.,]"), &parse(",[.,]"), 32, 2));
+        assert!(programs_equivalent(&parse(",[-]"), &parse(",[+]"), 32, 3));
+        assert!(!programs_equivalent(&parse(",."), &parse(",+."), 32, 4));
+        assert!(!programs_equivalent(&parse(",."), &parse(",,."), 32, 5));
+    }
+}
@@ -0,0 +1,145 @@
+use crate::{
+    interpreter::Interpreter,
+    optimize::{
+        OptLevel,
+        Optimizer,
+    },
+    repl::RunError,
+    Lexer,
+    Parser,
+};
+
+/// Anything that can go wrong in `eval`, from lexing to running.
+pub type Error = RunError;
+
+/// Lex, parse and run `source` in one go, reading from `input` and returning everything it printed.
+///
+/// Reads past the end of `input` produce 0.
+pub fn eval(source: &str, input: &[u8]) -> Result<Vec<u8>, Error> {
+    run(source, input, None)
+}
+
+/// Like `eval`, but optimizes the program with the passes for `level` before running it.
+pub fn eval_optimized(source: &str, input: &[u8], level: OptLevel) -> Result<Vec<u8>, Error> {
+    run(source, input, Some(level))
+}
+
+fn run(source: &str, input: &[u8], level: Option<OptLevel>) -> Result<Vec<u8>, Error> {
+    let mut lexer = Lexer::new(source);
+    lexer.lex()?;
+    let mut expr = Parser::new(lexer.tokens).parse()?;
+
+    if let Some(level) = level {
+        let mut optimizer = Optimizer::new(expr);
+        optimizer.add_passes(level);
+        optimizer.optimize();
+        expr = optimizer.expr;
+    }
+
+    Ok(Interpreter::run_collect(&expr, input)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        interpreter::RuntimeErrorKind,
+        parser::ParseError,
+    };
+
+    #[test]
+    fn eval_hello_world() {
+        let src = include_str!("../test_data/hello_world1.bf");
+        assert_eq!(eval(src, b"").unwrap(), b"Hello World!\n");
+        assert_eq!(
+            eval_optimized(src, b"", OptLevel::Aggressive).unwrap(),
+            b"Hello World!\n"
+        );
+        assert_eq!(eval(",[.,]", b"echo").unwrap(), b"echo");
+    }
+
+    #[test]
+    fn eval_too_deep() {
+        let src = format!("{}{}", "[".repeat(100_000), "]".repeat(100_000));
+        assert!(matches!(
+            eval(&src, b""),
+            Err(Error::Parse(ParseError::TooDeep { .. }))
+        ));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing() {
+        use std::sync::{
+            atomic::{
+                AtomicU64,
+                Ordering,
+            },
+            Arc,
+            Mutex,
+        };
+        use tracing::{
+            span,
+            Event,
+            Metadata,
+        };
+
+        #[derive(Clone, Default)]
+        struct Collector {
+            spans: Arc<Mutex<Vec<&'static str>>>,
+            io_events: Arc<AtomicU64>,
+            next_id: Arc<AtomicU64>,
+        }
+
+        impl tracing::Subscriber for Collector {
+            fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+                self.spans.lock().unwrap().push(span.metadata().name());
+                span::Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+            }
+
+            fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+
+            fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                if event.metadata().fields().field("byte").is_some() {
+                    self.io_events.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+
+            fn enter(&self, _span: &span::Id) {}
+
+            fn exit(&self, _span: &span::Id) {}
+        }
+
+        let collector = Collector::default();
+        tracing::subscriber::with_default(collector.clone(), || {
+            eval_optimized(",[.,]", b"hi", OptLevel::Aggressive).unwrap();
+        });
+
+        // Some passes parse and run snippets of their own while optimizing
+        let spans = collector.spans.lock().unwrap();
+        assert_eq!(spans[..3], ["lex", "parse", "optimize"]);
+        assert_eq!(spans.last(), Some(&"run"));
+        // 3 reads and 2 writes
+        assert_eq!(collector.io_events.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn eval_error() {
+        let e = eval("<", b"").unwrap_err();
+        assert!(matches!(
+            e,
+            Error::Runtime(ref e) if matches!(e.kind(), RuntimeErrorKind::PointerUnderflow { at: 0 })
+        ));
+        // The context survives leaving the interpreter
+        assert_eq!(
+            e.to_string(),
+            "the pointer moved left of cell 0 from cell 0 in ShiftLeft with the pointer at cell 0 holding 0"
+        );
+    }
+}
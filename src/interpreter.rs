@@ -1,196 +1,5005 @@
-use crate::parser::Expr;
+use crate::{
+    parser::Expr,
+    program::{
+        Op,
+        Program,
+    },
+    tape::{
+        BidirectionalTape,
+        HashMapTape,
+        SharedTape,
+        Tape,
+        TapeCell,
+        VecTape,
+    },
+    trace::Trace,
+    Token,
+    TokenData,
+};
+#[cfg(feature = "tokio")]
+use std::future::Future;
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{
+        BTreeMap,
+        HashMap,
+        HashSet,
+        VecDeque,
+    },
+    fmt,
+    io::{
+        self,
+        Read,
+        Write,
+    },
+    panic::{
+        self,
+        AssertUnwindSafe,
+    },
+    rc::Rc,
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        mpsc::{
+            self,
+            Receiver,
+            Sender,
+            TryRecvError,
+        },
+        Arc,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+#[cfg(feature = "tokio")]
+use tokio::io::{
+    AsyncRead,
+    AsyncReadExt,
+    AsyncWrite,
+    AsyncWriteExt,
+};
 
+/// How many steps run between checks of the deadline set by `Interpreter::run_with_timeout`.
+const DEADLINE_CHECK_INTERVAL: u32 = 4096;
+
+/// Hooks for an interpreter's I/O and events. Every method has a default.
+///
+/// The trait is object safe, so handlers picked at runtime can be used as a `Box<dyn Handler>`; see `BoxedInterpreter`.
 pub trait Handler {
-    fn read_char(&mut self) -> u8 {
-        0
+    fn read_char(&mut self) -> io::Result<u8> {
+        Ok(0)
+    }
+
+    /// Whether `read_char` can answer right now. If not, `Interpreter::step` reports `StepStatus::Blocked` instead of reading.
+    fn input_ready(&mut self) -> bool {
+        true
+    }
+
+    fn write_char(&mut self, _c: u8) -> io::Result<()> {
+        Ok(())
     }
 
-    fn write_char(&mut self, _c: u8) {}
+    /// Write several bytes at once, like for an `Expr::PrintString`. Calls `write_char` for each byte by default.
+    fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
+        for &c in s {
+            self.write_char(c)?;
+        }
+
+        Ok(())
+    }
 
     fn mem_read(&mut self, _index: usize) {}
+
+    /// Called at the start of every loop iteration, with the pointer and the (nonzero) current cell.
+    ///
+    /// Like the rest of the handler, this only sees the low byte of cells wider than 8 bits.
+    fn loop_iteration(&mut self, _pointer: usize, _cell: u8) {}
+
+    /// Called when a loop is reached, before its condition is checked, with the loop's id if it has one.
+    fn on_loop_enter(&mut self, _id: Option<usize>) {}
+
+    /// Called when a loop is left because its cell is 0, including loops that never ran.
+    ///
+    /// Loops that are still running when the run fails are never exited.
+    fn on_loop_exit(&mut self, _id: Option<usize>) {}
+
+    /// Called when an increment or decrement wraps the cell at `index` past its maximum or 0.
+    fn on_overflow(&mut self, _index: usize) {}
+
+    /// Called at the end of a top-level run, with the final tape and pointer.
+    fn finalize(&mut self, _cells: &[u8], _pointer: usize) {}
+
+    /// Called for every `Expr::Breakpoint`. Execution continues once this returns.
+    fn on_breakpoint(&mut self, _state: &BreakpointState) {}
+
+    /// Called every few steps, as set with `Interpreter::set_tick_interval`, with access to the tape.
+    fn on_tick(&mut self, _tape: &mut TapeView) {}
 }
 
-pub struct DefaultHandler;
-impl Handler for DefaultHandler {}
+impl<H: Handler + ?Sized> Handler for &mut H {
+    fn read_char(&mut self) -> io::Result<u8> {
+        (**self).read_char()
+    }
 
-#[derive(Debug)]
-pub enum RuntimeError {
-    GenericStr(&'static str),
+    fn input_ready(&mut self) -> bool {
+        (**self).input_ready()
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        (**self).write_char(c)
+    }
+
+    fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
+        (**self).write_str(s)
+    }
+
+    fn mem_read(&mut self, index: usize) {
+        (**self).mem_read(index);
+    }
+
+    fn loop_iteration(&mut self, pointer: usize, cell: u8) {
+        (**self).loop_iteration(pointer, cell);
+    }
+
+    fn on_loop_enter(&mut self, id: Option<usize>) {
+        (**self).on_loop_enter(id);
+    }
+
+    fn on_loop_exit(&mut self, id: Option<usize>) {
+        (**self).on_loop_exit(id);
+    }
+
+    fn on_overflow(&mut self, index: usize) {
+        (**self).on_overflow(index);
+    }
+
+    fn finalize(&mut self, cells: &[u8], pointer: usize) {
+        (**self).finalize(cells, pointer);
+    }
+
+    fn on_breakpoint(&mut self, state: &BreakpointState) {
+        (**self).on_breakpoint(state);
+    }
+
+    fn on_tick(&mut self, tape: &mut TapeView) {
+        (**self).on_tick(tape);
+    }
 }
 
-pub struct Interpreter<T> {
-    cells: Vec<u8>,
-    current_cell_index: usize,
+impl<H: Handler + ?Sized> Handler for Box<H> {
+    fn read_char(&mut self) -> io::Result<u8> {
+        (**self).read_char()
+    }
 
-    pub handler: T,
+    fn input_ready(&mut self) -> bool {
+        (**self).input_ready()
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        (**self).write_char(c)
+    }
+
+    fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
+        (**self).write_str(s)
+    }
+
+    fn mem_read(&mut self, index: usize) {
+        (**self).mem_read(index);
+    }
+
+    fn loop_iteration(&mut self, pointer: usize, cell: u8) {
+        (**self).loop_iteration(pointer, cell);
+    }
+
+    fn on_loop_enter(&mut self, id: Option<usize>) {
+        (**self).on_loop_enter(id);
+    }
+
+    fn on_loop_exit(&mut self, id: Option<usize>) {
+        (**self).on_loop_exit(id);
+    }
+
+    fn on_overflow(&mut self, index: usize) {
+        (**self).on_overflow(index);
+    }
+
+    fn finalize(&mut self, cells: &[u8], pointer: usize) {
+        (**self).finalize(cells, pointer);
+    }
+
+    fn on_breakpoint(&mut self, state: &BreakpointState) {
+        (**self).on_breakpoint(state);
+    }
+
+    fn on_tick(&mut self, tape: &mut TapeView) {
+        (**self).on_tick(tape);
+    }
 }
 
-impl<T: Handler> Interpreter<T> {
-    pub fn new(handler: T) -> Self {
-        Self {
-            cells: Vec::new(),
-            current_cell_index: 0,
+/// The tape as seen by `Handler::on_tick`, which can change cells in the middle of a run.
+///
+/// Values are full cells, not just the low byte. Writes aren't checked against the interpreter's limits.
+pub struct TapeView<'a> {
+    tape: &'a mut dyn DynTape,
+    pointer: usize,
+}
 
-            handler,
-        }
+impl TapeView<'_> {
+    pub fn get(&self, index: usize) -> u64 {
+        self.tape.get(index)
     }
 
-    pub fn cells(&self) -> &[u8] {
-        &self.cells
+    /// Write the cell at `index`, keeping only the bits of `value` that fit.
+    pub fn set(&mut self, index: usize, value: u64) {
+        self.tape.set(index, value);
     }
 
-    pub fn current_cell_index(&self) -> usize {
-        self.current_cell_index
+    /// One past the highest cell that was written to.
+    pub fn len(&self) -> usize {
+        self.tape.len()
     }
 
-    fn cell(&mut self, index: usize) -> &mut u8 {
-        if index >= self.cells.len() {
-            self.cells.resize(index + 1, 0);
-        }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-        self.cells.get_mut(index).unwrap()
+    pub fn pointer(&self) -> usize {
+        self.pointer
     }
+}
+
+/// A `Tape` without its cell type, so `TapeView` doesn't need to be generic.
+trait DynTape {
+    fn get(&self, index: usize) -> u64;
+
+    fn set(&mut self, index: usize, value: u64);
+
+    fn len(&self) -> usize;
+}
 
-    fn current_cell(&mut self) -> u8 {
-        *self.cell(self.current_cell_index)
+impl<S: Tape> DynTape for S {
+    fn get(&self, index: usize) -> u64 {
+        Tape::get(self, index).to_u64()
     }
 
-    fn current_cell_mut(&mut self) -> &mut u8 {
-        self.cell(self.current_cell_index)
+    fn set(&mut self, index: usize, value: u64) {
+        Tape::set(self, index, S::Cell::from_u64(value));
     }
 
-    pub fn run(&mut self, expr: &Expr) -> Result<(), RuntimeError> {
+    fn len(&self) -> usize {
+        Tape::len(self)
+    }
+}
+
+/// What a handler can see of the interpreter at a breakpoint.
+#[derive(Debug)]
+pub struct BreakpointState<'a> {
+    /// The tape up to the highest cell written to.
+    pub cells: &'a [u8],
+    pub pointer: usize,
+    /// The number of bytes written so far during the current run.
+    pub output_len: usize,
+}
+
+/// The low byte of every cell of `tape`, up to its `len`.
+fn tape_bytes<S: Tape>(tape: &S) -> Cow<'_, [u8]> {
+    match tape.as_slice() {
+        Some(cells) => S::Cell::to_bytes(cells),
+        None => Cow::Owned((0..tape.len()).map(|i| tape.get(i).low_byte()).collect()),
+    }
+}
+
+/// Statically compute how many cells `expr` can touch when run from cell 0.
+///
+/// Returns `None` if that depends on runtime data, which is the case for loops that move the pointer,
+/// or if the pointer would move left of cell 0.
+pub fn estimate_tape_size(expr: &Expr) -> Option<usize> {
+    fn visit(expr: &Expr, pointer: &mut usize, max: &mut usize) -> Option<()> {
         match expr {
             Expr::Block { exprs } => {
                 for expr in exprs {
-                    self.run(expr)?;
+                    visit(expr, pointer, max)?;
                 }
             }
-            Expr::Increment { num } => {
-                *self.current_cell_mut() = self.current_cell().overflowing_add(*num as u8).0;
-            }
-            Expr::Decrement { num } => {
-                *self.current_cell_mut() = self.current_cell().overflowing_sub(*num as u8).0;
+            Expr::Loop { expr, .. } => {
+                let start = *pointer;
+                visit(expr, pointer, max)?;
+                if *pointer != start {
+                    return None;
+                }
             }
             Expr::ShiftRight { num } => {
-                self.current_cell_index += num;
+                *pointer = pointer.checked_add(*num)?;
             }
             Expr::ShiftLeft { num } => {
-                self.current_cell_index -= num;
-            }
-            Expr::Loop { expr } => {
-                self.handler.mem_read(self.current_cell_index);
-                while self.current_cell() != 0 {
-                    self.run(&expr)?;
-                }
-            }
-            Expr::PrintChar => {
-                self.handler.mem_read(self.current_cell_index);
-                let cell = self.current_cell();
-                self.handler.write_char(cell);
-            }
-            Expr::ReadChar => {
-                *self.current_cell_mut() = self.handler.read_char();
-            }
-            Expr::Assign { index, value } => {
-                *self.cell(*index) = *value;
-            }
-            Expr::AssignCurrent { value } => {
-                *self.current_cell_mut() = *value;
-            }
-            Expr::PrintString { value } => {
-                for b in value.bytes() {
-                    self.handler.write_char(b);
-                }
+                *pointer = pointer.checked_sub(*num)?;
             }
             Expr::SetCellPointer { value } => {
-                self.current_cell_index = *value;
+                *pointer = *value;
             }
-            Expr::ReadCharForget => {
-                self.handler.read_char();
+            Expr::Assign { index, .. } => {
+                *max = (*max).max(*index);
+            }
+            Expr::DivMod => {
+                *max = (*max).max(pointer.checked_add(6)?);
             }
+            _ => {}
+        }
+
+        *max = (*max).max(*pointer);
+        Some(())
+    }
+
+    let mut max = 0;
+    visit(expr, &mut 0, &mut max)?;
+    max.checked_add(1)
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultHandler;
+impl Handler for DefaultHandler {}
+
+#[derive(Debug, Clone, Default)]
+pub struct LineHandler {
+    lines: Vec<String>,
+    partial: String,
+}
+
+impl LineHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Completed lines, without their trailing `\n`.
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /// Output written after the last `\n`.
+    pub fn partial(&self) -> &str {
+        &self.partial
+    }
+}
+
+impl Handler for LineHandler {
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        if c == b'\n' {
+            self.lines.push(std::mem::take(&mut self.partial));
+        } else {
+            self.partial.push(char::from(c));
         }
 
         Ok(())
     }
 }
 
-#[cfg(test)]
-mod test {
-    use crate::*;
+/// Writes output to `W`, flushing it after every `\n`.
+///
+/// Errors from the writer end the run with `RuntimeErrorKind::Io`.
+pub struct LineFlushHandler<W> {
+    writer: W,
+}
 
-    struct TestHandler {
-        out: String,
+impl<W: Write> LineFlushHandler<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
     }
 
-    impl TestHandler {
-        fn new() -> Self {
-            Self { out: String::new() }
+    pub fn writer(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> Handler for LineFlushHandler<W> {
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.writer.write_all(&[c])?;
+        if c == b'\n' {
+            self.writer.flush()?;
         }
+
+        Ok(())
     }
 
-    impl Handler for TestHandler {
-        fn write_char(&mut self, c: u8) {
-            self.out.push(char::from(c));
+    fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
+        self.writer.write_all(s)?;
+        if s.contains(&b'\n') {
+            self.writer.flush()?;
         }
+
+        Ok(())
     }
+}
 
-    fn test_output(data: &str, expected: &str) {
-        let mut l = Lexer::new(data);
-        l.lex().unwrap();
+/// Read a byte from `reader`, or 0 at the end of the input.
+fn read_byte<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut buf = [0];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(0),
+            Ok(_) => return Ok(buf[0]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
 
-        let mut p = Parser::new(l.tokens);
-        let exprs = p.parse().unwrap();
+/// Reads input from `R`. Output is dropped, and reads past the end of the input produce 0.
+pub struct ReaderHandler<R> {
+    reader: R,
+}
 
-        let mut vm = Interpreter::new(TestHandler::new());
-        vm.run(&exprs).unwrap();
+impl<R: Read> ReaderHandler<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
 
-        assert_eq!(vm.handler.out.as_str(), expected);
+    pub fn into_inner(self) -> R {
+        self.reader
     }
+}
 
-    #[test]
-    fn factorial() {
-        test_output(
-            include_str!("../test_data/factorial.bf"),
-            "0! = 1\n1! = 1\n2! = 2\n3! = 6\n4! = 24\n5! = 120\n6! = 28\n",
-        ); // Requires 16 bit cells for proper answer
+impl<R: Read> Handler for ReaderHandler<R> {
+    fn read_char(&mut self) -> io::Result<u8> {
+        read_byte(&mut self.reader)
     }
+}
 
-    #[test]
-    fn squares() {
-        test_output(include_str!("../test_data/squares.bf"), "0\n1\n4\n9\n16\n25\n36\n49\n64\n81\n100\n121\n144\n169\n196\n225\n256\n289\n324\n361\n400\n441\n484\n529\n576\n625\n676\n729\n784\n841\n900\n961\n1024\n1089\n1156\n1225\n1296\n1369\n1444\n1521\n1600\n1681\n1764\n1849\n1936\n2025\n2116\n2209\n2304\n2401\n2500\n2601\n2704\n2809\n2916\n3025\n3136\n3249\n3364\n3481\n3600\n3721\n3844\n3969\n4096\n4225\n4356\n4489\n4624\n4761\n4900\n5041\n5184\n5329\n5476\n5625\n5776\n5929\n6084\n6241\n6400\n6561\n6724\n6889\n7056\n7225\n7396\n7569\n7744\n7921\n8100\n8281\n8464\n8649\n8836\n9025\n9216\n9409\n9604\n9801\n10000\n");
+/// Writes output to `W`. Reads produce 0.
+pub struct WriterHandler<W> {
+    writer: W,
+}
+
+impl<W: Write> WriterHandler<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
     }
 
-    #[test]
-    fn hello_world3() {
-        test_output(
-            include_str!("../test_data/hello_world3.bf"),
-            "Hello, world!\n",
-        );
+    pub fn writer(&self) -> &W {
+        &self.writer
     }
 
-    #[test]
-    fn hello_world2() {
-        test_output(
-            include_str!("../test_data/hello_world2.bf"),
-            "Hello World!\n",
-        );
+    pub fn into_inner(self) -> W {
+        self.writer
     }
+}
 
-    #[test]
-    fn hello_world1() {
-        test_output(
-            include_str!("../test_data/hello_world1.bf"),
-            "Hello World!\n",
-        );
+impl<W: Write> Handler for WriterHandler<W> {
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.writer.write_all(&[c])
     }
 
-    #[test]
-    fn count_down() {
-        test_output(
-            include_str!("../test_data/count_down.bf"),
-            "9 8 7 6 5 4 3 2 1 0 ",
-        );
+    fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
+        self.writer.write_all(s)
+    }
+}
+
+/// What `SliceInputHandler` does for reads past the end of its input.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EofPolicy {
+    /// Read 0.
+    #[default]
+    Zero,
+    /// Read 255, the same as -1 in a signed byte.
+    Max,
+    /// Fail with `io::ErrorKind::UnexpectedEof`.
+    Error,
+}
+
+/// Reads input from a slice and collects the output.
+#[derive(Debug, Clone)]
+pub struct SliceInputHandler<'a> {
+    input: &'a [u8],
+    eof_policy: EofPolicy,
+    output: Vec<u8>,
+}
+
+impl<'a> SliceInputHandler<'a> {
+    pub fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            eof_policy: EofPolicy::Zero,
+            output: Vec::new(),
+        }
+    }
+
+    /// Set what reads past the end of the input do. Defaults to reading 0.
+    pub fn set_eof_policy(&mut self, eof_policy: EofPolicy) {
+        self.eof_policy = eof_policy;
+    }
+
+    /// The input that hasn't been read yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        self.input
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+}
+
+impl Handler for SliceInputHandler<'_> {
+    fn read_char(&mut self) -> io::Result<u8> {
+        match self.input.split_first() {
+            Some((&c, rest)) => {
+                self.input = rest;
+                Ok(c)
+            }
+            None => match self.eof_policy {
+                EofPolicy::Zero => Ok(0),
+                EofPolicy::Max => Ok(u8::MAX),
+                EofPolicy::Error => Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "read past the end of the input",
+                )),
+            },
+        }
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.output.push(c);
+        Ok(())
+    }
+
+    fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
+        self.output.extend_from_slice(s);
+        Ok(())
+    }
+}
+
+/// Reads from and writes to a TCP connection, so a program can be served over the network.
+///
+/// Reads past the end of the input produce 0. Finalizing shuts down the writing half, so the peer sees the end of the output.
+#[cfg(feature = "net")]
+pub struct TcpHandler {
+    reader: io::BufReader<std::net::TcpStream>,
+    writer: std::net::TcpStream,
+}
+
+#[cfg(feature = "net")]
+impl TcpHandler {
+    pub fn new(stream: std::net::TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            writer: stream.try_clone()?,
+            reader: io::BufReader::new(stream),
+        })
+    }
+
+    pub fn connect<A: std::net::ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Self::new(std::net::TcpStream::connect(addr)?)
+    }
+
+    pub fn stream(&self) -> &std::net::TcpStream {
+        &self.writer
+    }
+}
+
+#[cfg(feature = "net")]
+impl Handler for TcpHandler {
+    fn read_char(&mut self) -> io::Result<u8> {
+        read_byte(&mut self.reader)
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.writer.write_all(&[c])
+    }
+
+    fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
+        self.writer.write_all(s)
+    }
+
+    fn finalize(&mut self, _cells: &[u8], _pointer: usize) {
+        let _ = self.writer.shutdown(std::net::Shutdown::Write);
+    }
+}
+
+/// Reads from and writes to channels, so another thread like a GUI can talk to an interpreter running on a worker thread.
+///
+/// Reads block until a byte arrives, and produce 0 once every sender is dropped.
+/// Writes fail with `io::ErrorKind::BrokenPipe` once the receiver is dropped.
+pub struct ChannelHandler {
+    input: Receiver<u8>,
+    output: Sender<u8>,
+    // A byte taken off `input` by `input_ready` that wasn't read yet
+    peeked: Option<u8>,
+}
+
+impl ChannelHandler {
+    pub fn new(input: Receiver<u8>, output: Sender<u8>) -> Self {
+        Self {
+            input,
+            output,
+            peeked: None,
+        }
+    }
+
+    /// Create a handler along with the sender for its input and the receiver for its output.
+    pub fn pair() -> (Self, Sender<u8>, Receiver<u8>) {
+        let (input_tx, input_rx) = mpsc::channel();
+        let (output_tx, output_rx) = mpsc::channel();
+        (Self::new(input_rx, output_tx), input_tx, output_rx)
+    }
+}
+
+impl Handler for ChannelHandler {
+    fn read_char(&mut self) -> io::Result<u8> {
+        if let Some(c) = self.peeked.take() {
+            return Ok(c);
+        }
+
+        Ok(self.input.recv().unwrap_or(0))
+    }
+
+    fn input_ready(&mut self) -> bool {
+        if self.peeked.is_some() {
+            return true;
+        }
+
+        match self.input.try_recv() {
+            Ok(c) => {
+                self.peeked = Some(c);
+                true
+            }
+            Err(TryRecvError::Empty) => false,
+            Err(TryRecvError::Disconnected) => true,
+        }
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.output.send(c).map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "the output receiver was dropped")
+        })
+    }
+}
+
+/// Reads from stdin and writes to stdout, flushing stdout before every read so prompts show up.
+///
+/// Reads past the end of stdin produce 0.
+pub struct StdioHandler {
+    stdin: io::Stdin,
+    stdout: io::Stdout,
+}
+
+impl StdioHandler {
+    pub fn new() -> Self {
+        Self {
+            stdin: io::stdin(),
+            stdout: io::stdout(),
+        }
+    }
+}
+
+impl Default for StdioHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Handler for StdioHandler {
+    fn read_char(&mut self) -> io::Result<u8> {
+        self.stdout.flush()?;
+        read_byte(&mut self.stdin.lock())
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.stdout.write_all(&[c])
+    }
+
+    fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
+        self.stdout.write_all(s)
+    }
+}
+
+/// Wraps a handler, collecting output and passing it on with `Handler::write_str`
+/// instead of a byte at a time.
+///
+/// The output is passed on when the buffer fills up, before every read and breakpoint, and at the end of a run.
+/// Errors at a breakpoint or the end of a run keep the output buffered, and are returned by the next write or `BufferedHandler::flush`.
+pub struct BufferedHandler<H: Handler> {
+    inner: H,
+    buffer: Vec<u8>,
+    capacity: usize,
+}
+
+impl<H: Handler> BufferedHandler<H> {
+    /// Buffer up to 8 KiB of output.
+    pub fn new(inner: H) -> Self {
+        Self::with_capacity(inner, 8 * 1024)
+    }
+
+    pub fn with_capacity(inner: H, capacity: usize) -> Self {
+        Self {
+            inner,
+            buffer: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Pass the buffered output on to the wrapped handler.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.inner.write_str(&self.buffer)?;
+            self.buffer.clear();
+        }
+
+        Ok(())
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    /// Flush the buffer and return the wrapped handler.
+    pub fn into_inner(mut self) -> io::Result<H> {
+        self.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<H: Handler> Handler for BufferedHandler<H> {
+    fn read_char(&mut self) -> io::Result<u8> {
+        self.flush()?;
+        self.inner.read_char()
+    }
+
+    fn input_ready(&mut self) -> bool {
+        self.inner.input_ready()
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        if self.buffer.len() >= self.capacity {
+            self.flush()?;
+        }
+
+        self.buffer.push(c);
+        Ok(())
+    }
+
+    fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
+        if self.buffer.len() + s.len() > self.capacity {
+            self.flush()?;
+        }
+
+        if s.len() > self.capacity {
+            self.inner.write_str(s)
+        } else {
+            self.buffer.extend_from_slice(s);
+            Ok(())
+        }
+    }
+
+    fn mem_read(&mut self, index: usize) {
+        self.inner.mem_read(index);
+    }
+
+    fn loop_iteration(&mut self, pointer: usize, cell: u8) {
+        self.inner.loop_iteration(pointer, cell);
+    }
+
+    fn on_loop_enter(&mut self, id: Option<usize>) {
+        self.inner.on_loop_enter(id);
+    }
+
+    fn on_loop_exit(&mut self, id: Option<usize>) {
+        self.inner.on_loop_exit(id);
+    }
+
+    fn on_overflow(&mut self, index: usize) {
+        self.inner.on_overflow(index);
+    }
+
+    fn finalize(&mut self, cells: &[u8], pointer: usize) {
+        let _ = self.flush();
+        self.inner.finalize(cells, pointer);
+    }
+
+    fn on_breakpoint(&mut self, state: &BreakpointState) {
+        let _ = self.flush();
+        self.inner.on_breakpoint(state);
+    }
+
+    fn on_tick(&mut self, tape: &mut TapeView) {
+        self.inner.on_tick(tape);
+    }
+}
+
+/// Wraps a handler, recording every byte its `read_char` returns so the run can be replayed with `ReplayInputHandler`.
+#[derive(Debug, Clone)]
+pub struct RecordInputHandler<H> {
+    inner: H,
+    recorded: Vec<u8>,
+}
+
+impl<H: Handler> RecordInputHandler<H> {
+    pub fn new(inner: H) -> Self {
+        Self {
+            inner,
+            recorded: Vec::new(),
+        }
+    }
+
+    /// The bytes read so far, including the 0s produced past the end of the input.
+    pub fn recorded(&self) -> &[u8] {
+        &self.recorded
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn into_parts(self) -> (H, Vec<u8>) {
+        (self.inner, self.recorded)
+    }
+}
+
+impl<H: Handler> Handler for RecordInputHandler<H> {
+    fn read_char(&mut self) -> io::Result<u8> {
+        let c = self.inner.read_char()?;
+        self.recorded.push(c);
+        Ok(c)
+    }
+
+    fn input_ready(&mut self) -> bool {
+        self.inner.input_ready()
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.inner.write_char(c)
+    }
+
+    fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
+        self.inner.write_str(s)
+    }
+
+    fn mem_read(&mut self, index: usize) {
+        self.inner.mem_read(index);
+    }
+
+    fn loop_iteration(&mut self, pointer: usize, cell: u8) {
+        self.inner.loop_iteration(pointer, cell);
+    }
+
+    fn on_loop_enter(&mut self, id: Option<usize>) {
+        self.inner.on_loop_enter(id);
+    }
+
+    fn on_loop_exit(&mut self, id: Option<usize>) {
+        self.inner.on_loop_exit(id);
+    }
+
+    fn on_overflow(&mut self, index: usize) {
+        self.inner.on_overflow(index);
+    }
+
+    fn finalize(&mut self, cells: &[u8], pointer: usize) {
+        self.inner.finalize(cells, pointer);
+    }
+
+    fn on_breakpoint(&mut self, state: &BreakpointState) {
+        self.inner.on_breakpoint(state);
+    }
+
+    fn on_tick(&mut self, tape: &mut TapeView) {
+        self.inner.on_tick(tape);
+    }
+}
+
+/// Wraps a handler, answering reads from a recording made with `RecordInputHandler` instead.
+///
+/// Reading past the end of the recording means the run went differently, so it fails with `io::ErrorKind::UnexpectedEof`.
+#[derive(Debug, Clone)]
+pub struct ReplayInputHandler<H> {
+    inner: H,
+    input: VecDeque<u8>,
+}
+
+impl<H: Handler> ReplayInputHandler<H> {
+    pub fn new(inner: H, recorded: &[u8]) -> Self {
+        Self {
+            inner,
+            input: recorded.iter().copied().collect(),
+        }
+    }
+
+    /// The number of recorded bytes that haven't been read yet.
+    pub fn remaining(&self) -> usize {
+        self.input.len()
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+}
+
+impl<H: Handler> Handler for ReplayInputHandler<H> {
+    fn read_char(&mut self) -> io::Result<u8> {
+        self.input.pop_front().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read past the end of the recorded input",
+            )
+        })
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.inner.write_char(c)
+    }
+
+    fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
+        self.inner.write_str(s)
+    }
+
+    fn mem_read(&mut self, index: usize) {
+        self.inner.mem_read(index);
+    }
+
+    fn loop_iteration(&mut self, pointer: usize, cell: u8) {
+        self.inner.loop_iteration(pointer, cell);
+    }
+
+    fn on_loop_enter(&mut self, id: Option<usize>) {
+        self.inner.on_loop_enter(id);
+    }
+
+    fn on_loop_exit(&mut self, id: Option<usize>) {
+        self.inner.on_loop_exit(id);
+    }
+
+    fn on_overflow(&mut self, index: usize) {
+        self.inner.on_overflow(index);
+    }
+
+    fn finalize(&mut self, cells: &[u8], pointer: usize) {
+        self.inner.finalize(cells, pointer);
+    }
+
+    fn on_breakpoint(&mut self, state: &BreakpointState) {
+        self.inner.on_breakpoint(state);
+    }
+
+    fn on_tick(&mut self, tape: &mut TapeView) {
+        self.inner.on_tick(tape);
+    }
+}
+
+/// Sends output to both handlers, reading from the first one.
+///
+/// The other callbacks go to both, first to second.
+#[derive(Debug, Clone)]
+pub struct TeeHandler<A, B>(pub A, pub B);
+
+impl<A: Handler, B: Handler> Handler for TeeHandler<A, B> {
+    fn read_char(&mut self) -> io::Result<u8> {
+        self.0.read_char()
+    }
+
+    fn input_ready(&mut self) -> bool {
+        self.0.input_ready()
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.0.write_char(c)?;
+        self.1.write_char(c)
+    }
+
+    fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
+        self.0.write_str(s)?;
+        self.1.write_str(s)
+    }
+
+    fn mem_read(&mut self, index: usize) {
+        self.0.mem_read(index);
+        self.1.mem_read(index);
+    }
+
+    fn loop_iteration(&mut self, pointer: usize, cell: u8) {
+        self.0.loop_iteration(pointer, cell);
+        self.1.loop_iteration(pointer, cell);
+    }
+
+    fn on_loop_enter(&mut self, id: Option<usize>) {
+        self.0.on_loop_enter(id);
+        self.1.on_loop_enter(id);
+    }
+
+    fn on_loop_exit(&mut self, id: Option<usize>) {
+        self.0.on_loop_exit(id);
+        self.1.on_loop_exit(id);
+    }
+
+    fn on_overflow(&mut self, index: usize) {
+        self.0.on_overflow(index);
+        self.1.on_overflow(index);
+    }
+
+    fn finalize(&mut self, cells: &[u8], pointer: usize) {
+        self.0.finalize(cells, pointer);
+        self.1.finalize(cells, pointer);
+    }
+
+    fn on_breakpoint(&mut self, state: &BreakpointState) {
+        self.0.on_breakpoint(state);
+        self.1.on_breakpoint(state);
+    }
+
+    fn on_tick(&mut self, tape: &mut TapeView) {
+        self.0.on_tick(tape);
+        self.1.on_tick(tape);
+    }
+}
+
+/// Wraps a handler, writing a line to `log` for every byte read or written, like `read 0: h` or `write 5: \n`.
+///
+/// The number is the position of the byte in the input or output. Failing to write the log fails the read or write.
+pub struct LoggingHandler<H, W> {
+    inner: H,
+    log: W,
+    read_pos: usize,
+    write_pos: usize,
+}
+
+impl<H: Handler, W: Write> LoggingHandler<H, W> {
+    pub fn new(inner: H, log: W) -> Self {
+        Self {
+            inner,
+            log,
+            read_pos: 0,
+            write_pos: 0,
+        }
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn into_parts(self) -> (H, W) {
+        (self.inner, self.log)
+    }
+
+    fn log_write(&mut self, c: u8) -> io::Result<()> {
+        writeln!(
+            self.log,
+            "write {}: {}",
+            self.write_pos,
+            std::ascii::escape_default(c)
+        )?;
+        self.write_pos += 1;
+        Ok(())
+    }
+}
+
+impl<H: Handler, W: Write> Handler for LoggingHandler<H, W> {
+    fn read_char(&mut self) -> io::Result<u8> {
+        let result = self.inner.read_char();
+        match &result {
+            Ok(c) => writeln!(
+                self.log,
+                "read {}: {}",
+                self.read_pos,
+                std::ascii::escape_default(*c)
+            )?,
+            Err(e) => writeln!(self.log, "read {}: error: {}", self.read_pos, e)?,
+        }
+        self.read_pos += 1;
+        result
+    }
+
+    fn input_ready(&mut self) -> bool {
+        self.inner.input_ready()
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.log_write(c)?;
+        self.inner.write_char(c)
+    }
+
+    fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
+        for &c in s {
+            self.log_write(c)?;
+        }
+        self.inner.write_str(s)
+    }
+
+    fn mem_read(&mut self, index: usize) {
+        self.inner.mem_read(index);
+    }
+
+    fn loop_iteration(&mut self, pointer: usize, cell: u8) {
+        self.inner.loop_iteration(pointer, cell);
+    }
+
+    fn on_loop_enter(&mut self, id: Option<usize>) {
+        self.inner.on_loop_enter(id);
+    }
+
+    fn on_loop_exit(&mut self, id: Option<usize>) {
+        self.inner.on_loop_exit(id);
+    }
+
+    fn on_overflow(&mut self, index: usize) {
+        self.inner.on_overflow(index);
+    }
+
+    fn finalize(&mut self, cells: &[u8], pointer: usize) {
+        self.inner.finalize(cells, pointer);
+    }
+
+    fn on_breakpoint(&mut self, state: &BreakpointState) {
+        self.inner.on_breakpoint(state);
+    }
+
+    fn on_tick(&mut self, tape: &mut TapeView) {
+        self.inner.on_tick(tape);
+    }
+}
+
+/// The input and output of `Interpreter::run_async`, which can wait without blocking a thread.
+#[cfg(feature = "tokio")]
+pub trait AsyncHandler {
+    fn read_char(&mut self) -> impl Future<Output = io::Result<u8>> {
+        async { Ok(0) }
+    }
+
+    fn write_char(&mut self, _c: u8) -> impl Future<Output = io::Result<()>> {
+        async { Ok(()) }
+    }
+}
+
+/// Reads from a tokio `AsyncRead` and writes to an `AsyncWrite`, flushing before every read.
+///
+/// Reads past the end of the input produce 0.
+#[cfg(feature = "tokio")]
+pub struct AsyncIoHandler<R, W> {
+    reader: R,
+    writer: W,
+}
+
+#[cfg(feature = "tokio")]
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> AsyncIoHandler<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self { reader, writer }
+    }
+
+    pub fn writer(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> AsyncHandler for AsyncIoHandler<R, W> {
+    async fn read_char(&mut self) -> io::Result<u8> {
+        self.writer.flush().await?;
+        let mut buf = [0];
+        loop {
+            match self.reader.read(&mut buf).await {
+                Ok(0) => return Ok(0),
+                Ok(_) => return Ok(buf[0]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.writer.write_all(&[c]).await
+    }
+}
+
+/// Decodes output as UTF-8 as it's written.
+///
+/// Invalid bytes become U+FFFD, or fail the write with `io::ErrorKind::InvalidData` if made with `Utf8OutputHandler::strict`.
+#[derive(Debug, Default)]
+pub struct Utf8OutputHandler {
+    output: String,
+    // The start of a character that isn't complete yet
+    incomplete: Vec<u8>,
+    strict: bool,
+}
+
+impl Utf8OutputHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail on invalid UTF-8 instead of replacing it.
+    pub fn strict() -> Self {
+        Self {
+            strict: true,
+            ..Self::default()
+        }
+    }
+
+    /// The characters decoded so far.
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    pub fn into_string(self) -> String {
+        self.output
+    }
+
+    /// The bytes of a character that was cut off. In strict mode this is where a run ending in the middle of one leaves it.
+    pub fn incomplete(&self) -> &[u8] {
+        &self.incomplete
+    }
+}
+
+impl Handler for Utf8OutputHandler {
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.incomplete.push(c);
+        while !self.incomplete.is_empty() {
+            match std::str::from_utf8(&self.incomplete) {
+                Ok(s) => {
+                    self.output.push_str(s);
+                    self.incomplete.clear();
+                }
+                Err(e) => {
+                    let valid = e.valid_up_to();
+                    let invalid_len = match e.error_len() {
+                        Some(len) => len,
+                        // Wait for the rest of the character
+                        None => break,
+                    };
+
+                    let bytes: Vec<u8> = self.incomplete.drain(..valid + invalid_len).collect();
+                    self.output
+                        .push_str(std::str::from_utf8(&bytes[..valid]).unwrap());
+                    if self.strict {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "output is not valid UTF-8",
+                        ));
+                    }
+                    self.output.push(char::REPLACEMENT_CHARACTER);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finalize(&mut self, _cells: &[u8], _pointer: usize) {
+        if !self.strict && !self.incomplete.is_empty() {
+            self.incomplete.clear();
+            self.output.push(char::REPLACEMENT_CHARACTER);
+        }
+    }
+}
+
+/// Records the output and the final tape of a run.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingHandler {
+    output: Vec<u8>,
+    cells: Vec<u8>,
+    pointer: usize,
+}
+
+impl RecordingHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// The tape at the end of the last run.
+    pub fn cells(&self) -> &[u8] {
+        &self.cells
+    }
+
+    /// The pointer at the end of the last run.
+    pub fn pointer(&self) -> usize {
+        self.pointer
+    }
+}
+
+impl Handler for RecordingHandler {
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.output.push(c);
+        Ok(())
+    }
+
+    fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
+        self.output.extend_from_slice(s);
+        Ok(())
+    }
+
+    fn finalize(&mut self, cells: &[u8], pointer: usize) {
+        self.cells = cells.to_vec();
+        self.pointer = pointer;
+    }
+}
+
+/// What went wrong in a failed run, from `RuntimeError::kind`.
+#[derive(Debug)]
+pub enum RuntimeErrorKind {
+    /// The bracket at index `token` passed to `Interpreter::run_tokens` has no match.
+    /// `offset` is its byte offset in the source, if the lexer recorded it.
+    UnmatchedBracket { token: usize, offset: Option<usize> },
+    /// `Interpreter::run_catch_unwind` caught a panic, from the handler or the interpreter itself, with this message.
+    InternalPanic(String),
+    /// A loop iteration moved the pointer while `Interpreter::set_strict_balanced` was on.
+    /// `id` is the loop's id, if the parser gave it one.
+    UnbalancedLoop { id: Option<usize> },
+    /// The limit set with `Interpreter::set_fuel` ran out.
+    FuelExhausted,
+    /// A write would make memory use go over the limit set with `Interpreter::set_memory_limit`.
+    MemoryLimitExceeded,
+    /// An increment or decrement overflowed the cell at `index` under `OverflowPolicy::Error`.
+    Overflow { index: usize },
+    /// The pointer moved past the limit set with `Interpreter::set_max_tape_len`, or the end of a fixed size tape.
+    TapeOverflow,
+    /// The pointer moved left of cell 0 from cell `at`, on a tape that doesn't allow negative indices.
+    PointerUnderflow { at: usize },
+    /// The program printed more than `ExecutionLimits::max_output` bytes.
+    OutputLimitExceeded,
+    /// The loop with `id` went over the limit set with `Interpreter::set_max_loop_iterations`.
+    LoopLimitExceeded { id: Option<usize> },
+    /// The deadline passed to `Interpreter::run_with_timeout` went by.
+    Timeout,
+    /// The token passed to `Interpreter::set_cancel_token` was cancelled.
+    Cancelled,
+    /// The handler failed to read or write.
+    Io(io::Error),
+    /// A state passed to `Interpreter::load_state` doesn't fit the interpreter or the program being stepped through.
+    InvalidState(&'static str),
+}
+
+impl fmt::Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnmatchedBracket {
+                token,
+                offset: Some(offset),
+            } => write!(f, "unmatched bracket at token {} (byte {})", token, offset),
+            Self::UnmatchedBracket {
+                token,
+                offset: None,
+            } => {
+                write!(f, "unmatched bracket at token {}", token)
+            }
+            Self::InternalPanic(message) => write!(f, "internal panic: {}", message),
+            Self::UnbalancedLoop { id: Some(id) } => {
+                write!(f, "loop {} didn't return to the cell it started on", id)
+            }
+            Self::UnbalancedLoop { id: None } => {
+                write!(f, "a loop didn't return to the cell it started on")
+            }
+            Self::FuelExhausted => write!(f, "ran out of fuel"),
+            Self::MemoryLimitExceeded => write!(f, "exceeded the memory limit"),
+            Self::Overflow { index } => write!(f, "cell {} overflowed", index),
+            Self::TapeOverflow => write!(f, "the pointer moved past the end of the tape"),
+            Self::PointerUnderflow { at } => {
+                write!(f, "the pointer moved left of cell 0 from cell {}", at)
+            }
+            Self::OutputLimitExceeded => write!(f, "exceeded the output limit"),
+            Self::LoopLimitExceeded { id: Some(id) } => {
+                write!(f, "loop {} exceeded the iteration limit", id)
+            }
+            Self::LoopLimitExceeded { id: None } => {
+                write!(f, "a loop exceeded the iteration limit")
+            }
+            Self::Timeout => write!(f, "timed out"),
+            Self::Cancelled => write!(f, "cancelled"),
+            Self::Io(e) => write!(f, "I/O error: {}", e),
+            Self::InvalidState(reason) => write!(f, "invalid interpreter state: {}", reason),
+        }
+    }
+}
+
+/// A failed run, with where the interpreter was when it failed.
+#[derive(Debug)]
+pub struct RuntimeError {
+    kind: RuntimeErrorKind,
+    context: Option<Box<ErrorContext>>,
+}
+
+impl RuntimeError {
+    pub fn kind(&self) -> &RuntimeErrorKind {
+        &self.kind
+    }
+
+    pub fn into_kind(self) -> RuntimeErrorKind {
+        self.kind
+    }
+
+    /// Where the run failed. Errors from outside of a program, like a state `Interpreter::load_state` rejected, have none.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        self.context.as_deref()
+    }
+}
+
+impl From<RuntimeErrorKind> for RuntimeError {
+    fn from(kind: RuntimeErrorKind) -> Self {
+        Self {
+            kind,
+            context: None,
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(context) = &self.context {
+            match (context.expr, context.position) {
+                (Some(expr), _) => write!(f, " in {}", expr)?,
+                (None, Some(position)) => write!(f, " at {}", position)?,
+                (None, None) => {}
+            }
+            write!(
+                f,
+                " with the pointer at cell {} holding {}",
+                context.pointer, context.cell
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            RuntimeErrorKind::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Where a run failed, from `RuntimeError::context`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// The name of the expression that failed, like `Expr::name`, when running an `Expr`.
+    pub expr: Option<&'static str>,
+    /// The index of the op that failed when running a `Program`, or of the token in `Interpreter::run_tokens`.
+    pub position: Option<usize>,
+    pub pointer: usize,
+    /// The cell under the pointer when the run failed.
+    pub cell: u64,
+}
+
+/// The state of a program run with `Interpreter::step`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepStatus {
+    Running,
+    /// The program finished.
+    Done,
+    /// The next op reads, but the input added with `Interpreter::push_input` ran out,
+    /// or the handler's `input_ready` returned false.
+    Blocked,
+}
+
+/// Something that happened while running a program with `Interpreter::events`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The program printed a byte.
+    Output(u8),
+    /// The next op reads, and there's no input left. Add some with `Events::push_input` to continue.
+    NeedsInput,
+    /// The loop with `id` started running, because the cell was not 0 when it was reached.
+    LoopEntered { id: Option<usize> },
+    /// The program finished. This is the last event.
+    Halted,
+}
+
+/// What happens when an increment or decrement goes past the range of a cell.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OverflowPolicy {
+    #[default]
+    Wrapping,
+    /// Stay at the maximum or 0.
+    Saturating,
+    /// Fail with `RuntimeErrorKind::Overflow`, leaving the cell unchanged.
+    Error,
+}
+
+/// A flag that stops a run from another thread or a Ctrl-C handler, see `Interpreter::set_cancel_token`.
+///
+/// Clones share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make runs checking this token fail with `RuntimeErrorKind::Cancelled`, until `CancelToken::reset` is called.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Limits for running untrusted programs, set with `Interpreter::set_limits`. `None` means no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExecutionLimits {
+    /// The number of cells the pointer can reach, see `Interpreter::set_max_tape_len`.
+    pub max_cells: Option<usize>,
+    /// The number of operations and loop iterations that can run, see `Interpreter::set_fuel`.
+    pub max_steps: Option<u64>,
+    /// The number of bytes a run can print before failing with `RuntimeErrorKind::OutputLimitExceeded`.
+    pub max_output: Option<usize>,
+    /// The number of iterations a loop can run each time it's reached, see `Interpreter::set_max_loop_iterations`.
+    pub max_loop_iterations: Option<u64>,
+}
+
+/// What happened during a run, from `Interpreter::run_with_stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecutionStats {
+    /// How many times each kind of expression ran, keyed by `Expr::name`. Blocks aren't counted.
+    pub exprs: BTreeMap<&'static str, u64>,
+    pub loop_iterations: u64,
+    /// The highest cell the pointer reached. Cells left of 0 aren't counted.
+    pub max_pointer: usize,
+    /// The number of different cells the pointer was at or that were written to.
+    pub cells_touched: usize,
+    /// The number of reads, including ones past the end of the input.
+    pub bytes_read: u64,
+    pub bytes_written: usize,
+}
+
+/// How many times each loop body ran, collected with `Interpreter::set_profiling`.
+///
+/// Loops are identified by their id. Loops without one, like those made by some optimizer passes, are counted together under `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoopProfile {
+    iterations: HashMap<Option<usize>, u64>,
+}
+
+impl LoopProfile {
+    /// How many times the body of the loop with `id` ran.
+    pub fn iterations(&self, id: Option<usize>) -> u64 {
+        self.iterations.get(&id).copied().unwrap_or(0)
+    }
+
+    /// The total number of loop iterations.
+    pub fn total(&self) -> u64 {
+        self.iterations.values().sum()
+    }
+
+    /// Every loop that ran along with its iterations, hottest first.
+    pub fn ranked(&self) -> Vec<(Option<usize>, u64)> {
+        let mut ranked: Vec<_> = self.iterations.iter().map(|(&id, &n)| (id, n)).collect();
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked
+    }
+
+    fn record(&mut self, id: Option<usize>) {
+        *self.iterations.entry(id).or_insert(0) += 1;
+    }
+}
+
+/// A block or loop being run by `Interpreter::run_expr`.
+enum Frame<'a> {
+    Block {
+        /// The expressions of the block left to run.
+        exprs: std::slice::Iter<'a, Expr>,
+        /// The index in `Expr::nodes` of the next expression, only kept up to date while collecting coverage.
+        next_node: usize,
+    },
+    Loop {
+        body: &'a Expr,
+        /// The index of `body` in `Expr::nodes`.
+        body_node: usize,
+        id: Option<usize>,
+        /// The pointer at the start of the current iteration.
+        start_index: usize,
+        /// The iterations run so far, counting the current one.
+        iterations: u64,
+    },
+}
+
+/// Which nodes of an expression ran, from `Interpreter::coverage`.
+///
+/// Nodes are identified by their index in `Expr::nodes`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coverage {
+    covered: Vec<bool>,
+}
+
+impl Coverage {
+    pub fn is_covered(&self, id: usize) -> bool {
+        self.covered.get(id).copied().unwrap_or(false)
+    }
+
+    /// The ids of the nodes that never ran.
+    pub fn uncovered(&self) -> Vec<usize> {
+        (0..self.covered.len())
+            .filter(|&id| !self.covered[id])
+            .collect()
+    }
+
+    pub fn covered_count(&self) -> usize {
+        self.covered.iter().filter(|&&covered| covered).count()
+    }
+
+    /// The number of nodes in the expression.
+    pub fn len(&self) -> usize {
+        self.covered.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.covered.is_empty()
+    }
+}
+
+/// Stats being collected for `Interpreter::run_with_stats`.
+#[derive(Clone, Default)]
+struct StatsCollector {
+    stats: ExecutionStats,
+    touched: HashSet<usize>,
+}
+
+#[derive(Clone)]
+pub struct Interpreter<T, S = VecTape> {
+    tape: S,
+    current_cell_index: usize,
+    strict_balanced: bool,
+    input: Option<VecDeque<u8>>,
+    output_len: usize,
+    fuel: Option<u64>,
+    memory_limit: Option<usize>,
+    overflow_policy: OverflowPolicy,
+    max_tape_len: Option<usize>,
+    max_output: Option<usize>,
+    max_loop_iterations: Option<u64>,
+    deadline: Option<Instant>,
+    // Steps left until the deadline is checked again
+    deadline_countdown: u32,
+    stats: Option<Box<StatsCollector>>,
+    profile: Option<LoopProfile>,
+    trace: Option<Trace>,
+    cancel_token: Option<CancelToken>,
+    error_context: Option<ErrorContext>,
+    // The indices in `Expr::nodes` of the nodes that ran
+    covered: Option<HashSet<usize>>,
+    tick_interval: Option<u64>,
+    // Steps left until the next tick
+    tick_countdown: u64,
+    cursor: ProgramCursor,
+
+    pub handler: T,
+}
+
+/// A checkpoint of an `Interpreter`, from `Interpreter::save_state`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InterpreterState<C = u8> {
+    /// The tape up to the highest cell written to.
+    pub cells: Vec<C>,
+    pub pointer: usize,
+    /// The position of `Interpreter::step` and `Interpreter::run_until_output` in their program.
+    pub program_counter: usize,
+    /// The pointer at the start of each loop that `program_counter` is inside of.
+    pub loop_starts: Vec<usize>,
+    /// The iterations run so far by each loop in `loop_starts`. States saved without them count from 0.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub loop_iterations: Vec<u64>,
+    /// Output that `Interpreter::run_until_output` didn't return yet.
+    pub pending_output: Vec<u8>,
+    /// Input added with `Interpreter::push_input` that wasn't read yet.
+    pub input: Option<Vec<u8>>,
+}
+
+/// Where `Interpreter::run_until_output` stopped.
+#[derive(Clone, Default)]
+struct ProgramCursor {
+    pc: usize,
+    // Bytes of a `PrintString` that weren't returned yet
+    pending: VecDeque<u8>,
+    // The pointer at the start of the current iteration of each running loop
+    loop_starts: Vec<usize>,
+    // The iterations run so far by each running loop
+    loop_iterations: Vec<u64>,
+}
+
+impl<T: Handler> Interpreter<T> {
+    pub fn new(handler: T) -> Self {
+        Self::with_tape(handler, VecTape::new())
+    }
+
+    /// Start with `memory` already on the tape and the pointer at `start_index`.
+    pub fn with_memory(handler: T, memory: Vec<u8>, start_index: usize) -> Self {
+        let mut vm = Self::with_tape(handler, VecTape::from(memory));
+        vm.current_cell_index = start_index;
+        vm
+    }
+}
+
+/// An interpreter whose handler is chosen at runtime.
+pub type BoxedInterpreter<S = VecTape> = Interpreter<Box<dyn Handler>, S>;
+
+impl BoxedInterpreter {
+    /// Create an interpreter that boxes `handler`, so it has the same type whatever the handler is.
+    pub fn boxed<H: Handler + 'static>(handler: H) -> Self {
+        Self::new(Box::new(handler))
+    }
+}
+
+impl Interpreter<RecordingHandler> {
+    /// Run `expr` on a new interpreter, reading from `input` and returning everything it printed.
+    ///
+    /// Reads past the end of `input` produce 0.
+    pub fn run_collect(expr: &Expr, input: &[u8]) -> Result<Vec<u8>, RuntimeError> {
+        let mut vm = Self::new(RecordingHandler::new());
+        vm.run_with_input(expr, input)?;
+        Ok(vm.handler.output)
+    }
+}
+
+impl<T: Handler> Interpreter<T, HashMapTape> {
+    /// Create an interpreter with a sparse tape, for programs that touch cells far apart.
+    pub fn sparse(handler: T) -> Self {
+        Self::with_tape(handler, HashMapTape::new())
+    }
+}
+
+impl<T: Handler> Interpreter<T, BidirectionalTape> {
+    /// Create an interpreter whose pointer can move left of cell 0.
+    pub fn bidirectional(handler: T) -> Self {
+        Self::with_tape(handler, BidirectionalTape::new())
+    }
+}
+
+impl<T: Handler> Interpreter<T, SharedTape> {
+    /// Create an interpreter whose cells live in `tape`, which other interpreters may also use.
+    pub fn with_shared_tape(handler: T, tape: Rc<RefCell<Vec<u8>>>) -> Self {
+        Self::with_tape(handler, SharedTape::new(tape))
+    }
+}
+
+impl<T, C: TapeCell> Interpreter<T, VecTape<C>> {
+    pub fn cells(&self) -> &[C] {
+        self.tape.cells()
+    }
+}
+
+impl<T: Handler, S: Tape> Interpreter<T, S> {
+    pub fn with_tape(handler: T, tape: S) -> Self {
+        Self {
+            tape,
+            current_cell_index: 0,
+            strict_balanced: false,
+            input: None,
+            output_len: 0,
+            fuel: None,
+            memory_limit: None,
+            overflow_policy: OverflowPolicy::Wrapping,
+            max_tape_len: None,
+            max_output: None,
+            max_loop_iterations: None,
+            deadline: None,
+            deadline_countdown: 0,
+            stats: None,
+            profile: None,
+            trace: None,
+            cancel_token: None,
+            error_context: None,
+            covered: None,
+            tick_interval: None,
+            tick_countdown: 0,
+            cursor: ProgramCursor::default(),
+
+            handler,
+        }
+    }
+
+    pub fn tape(&self) -> &S {
+        &self.tape
+    }
+
+    pub fn tape_mut(&mut self) -> &mut S {
+        &mut self.tape
+    }
+
+    /// The pointer. Cells left of 0 wrap around from `usize::MAX`, see `Tape::allows_negative`.
+    pub fn current_cell_index(&self) -> usize {
+        self.current_cell_index
+    }
+
+    /// Where the last failed run stopped, the same as the `RuntimeError::context` it returned.
+    pub fn error_context(&self) -> Option<&ErrorContext> {
+        self.error_context.as_ref()
+    }
+
+    /// The number of bytes written during the current or last run.
+    pub fn output_len(&self) -> usize {
+        self.output_len
+    }
+
+    /// Allocate the tape up front if `estimate_tape_size` can determine how much `expr` needs.
+    ///
+    /// Nothing is allocated if that would go over the memory limit.
+    pub fn reserve_for(&mut self, expr: &Expr) {
+        if let Some(size) = estimate_tape_size(expr) {
+            if !matches!(self.memory_limit, Some(limit) if size > limit) {
+                self.tape.reserve(size);
+            }
+        }
+    }
+
+    /// Limit the bytes used by the tape and buffered input.
+    ///
+    /// Writes that would go over it fail with `RuntimeErrorKind::MemoryLimitExceeded`. `None`, the default, lifts the limit.
+    pub fn set_memory_limit(&mut self, bytes: Option<usize>) {
+        self.memory_limit = bytes;
+    }
+
+    /// Set the value of cells that haven't been written to yet. Defaults to 0.
+    pub fn set_fill_value(&mut self, value: S::Cell) {
+        self.tape.set_fill_value(value);
+    }
+
+    /// Limit the number of cells the pointer can reach.
+    ///
+    /// Moving the pointer or writing past it fails with `RuntimeErrorKind::TapeOverflow`.
+    /// Tapes that allow negative indices are limited to the same distance left of cell 0. `None`, the default, lifts the limit.
+    pub fn set_max_tape_len(&mut self, len: Option<usize>) {
+        self.max_tape_len = len;
+    }
+
+    /// Limit how many bytes a run can print, so a program like `+[.]` fails with `RuntimeErrorKind::OutputLimitExceeded`.
+    ///
+    /// Output up to the limit still reaches the handler. `step` counts output until `reset` is called.
+    pub fn set_max_output(&mut self, max: Option<usize>) {
+        self.max_output = max;
+    }
+
+    /// Limit how many iterations a loop can run each time it's reached, so an accidental infinite loop fails early
+    /// with `RuntimeErrorKind::LoopLimitExceeded`. The count starts over when the loop is reached again.
+    pub fn set_max_loop_iterations(&mut self, max: Option<u64>) {
+        self.max_loop_iterations = max;
+    }
+
+    /// Set what increments and decrements do when they overflow a cell. Defaults to wrapping.
+    pub fn set_overflow_policy(&mut self, overflow_policy: OverflowPolicy) {
+        self.overflow_policy = overflow_policy;
+    }
+
+    /// Error with `RuntimeErrorKind::UnbalancedLoop` if a loop iteration moves the pointer.
+    ///
+    /// This usually indicates a bug in generated code, but some valid programs rely on it.
+    pub fn set_strict_balanced(&mut self, strict_balanced: bool) {
+        self.strict_balanced = strict_balanced;
+    }
+
+    /// Limit the number of operations and loop iterations that can run.
+    ///
+    /// Once it runs out, runs fail with `RuntimeErrorKind::FuelExhausted`. `None` means no limit.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// Check `cancel_token` before every step, failing with `RuntimeErrorKind::Cancelled` once it's cancelled.
+    ///
+    /// A read that blocks isn't interrupted.
+    pub fn set_cancel_token(&mut self, cancel_token: Option<CancelToken>) {
+        self.cancel_token = cancel_token;
+    }
+
+    /// Call `Handler::on_tick` once every `interval` steps, or never if `None`.
+    pub fn set_tick_interval(&mut self, interval: Option<u64>) {
+        self.tick_interval = interval.map(|interval| interval.max(1));
+        self.tick_countdown = self.tick_interval.unwrap_or(0);
+    }
+
+    /// Replace the tape, fuel, output and loop limits with `limits`.
+    pub fn set_limits(&mut self, limits: ExecutionLimits) {
+        self.max_tape_len = limits.max_cells;
+        self.fuel = limits.max_steps;
+        self.max_output = limits.max_output;
+        self.max_loop_iterations = limits.max_loop_iterations;
+    }
+
+    /// Count how many times each loop body runs, in `run` and `step`. Turning it on clears the counts.
+    pub fn set_profiling(&mut self, profiling: bool) {
+        self.profile = if profiling {
+            Some(LoopProfile::default())
+        } else {
+            None
+        };
+    }
+
+    /// The loop counts collected since profiling was turned on.
+    pub fn loop_profile(&self) -> Option<&LoopProfile> {
+        self.profile.as_ref()
+    }
+
+    /// Remember which expression nodes `run` executes, for `Interpreter::coverage`. Turning it on forgets earlier runs.
+    ///
+    /// Nodes are recorded by their index in `Expr::nodes`, so runs of different programs are merged.
+    pub fn set_coverage(&mut self, coverage: bool) {
+        self.covered = if coverage { Some(HashSet::new()) } else { None };
+    }
+
+    /// Which nodes of `expr` ran since coverage was turned on, or `None` if it's off.
+    pub fn coverage(&self, expr: &Expr) -> Option<Coverage> {
+        let covered = self.covered.as_ref()?;
+        Some(Coverage {
+            covered: (0..expr.node_count())
+                .map(|id| covered.contains(&id))
+                .collect(),
+        })
+    }
+
+    /// Record every expression `run` executes. Turning it on starts a new trace.
+    pub fn set_tracing(&mut self, tracing: bool) {
+        self.trace = if tracing { Some(Trace::new()) } else { None };
+    }
+
+    /// The expressions recorded since tracing was turned on.
+    pub fn trace(&self) -> Option<&Trace> {
+        self.trace.as_ref()
+    }
+
+    /// Take the recorded trace, turning tracing off.
+    pub fn take_trace(&mut self) -> Option<Trace> {
+        self.trace.take()
+    }
+
+    /// The fuel left, or `None` if there is no limit.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    fn consume_fuel(&mut self) -> Result<(), RuntimeErrorKind> {
+        match &mut self.fuel {
+            Some(0) => return Err(RuntimeErrorKind::FuelExhausted),
+            Some(fuel) => *fuel -= 1,
+            None => {}
+        }
+
+        if matches!(&self.cancel_token, Some(token) if token.is_cancelled()) {
+            return Err(RuntimeErrorKind::Cancelled);
+        }
+
+        if let Some(deadline) = self.deadline {
+            // Reading the clock is slow compared to a step, so only do it every so often
+            if self.deadline_countdown == 0 {
+                if Instant::now() >= deadline {
+                    return Err(RuntimeErrorKind::Timeout);
+                }
+                self.deadline_countdown = DEADLINE_CHECK_INTERVAL;
+            }
+            self.deadline_countdown -= 1;
+        }
+
+        if let Some(interval) = self.tick_interval {
+            self.tick_countdown -= 1;
+            if self.tick_countdown == 0 {
+                self.tick_countdown = interval;
+                self.handler.on_tick(&mut TapeView {
+                    tape: &mut self.tape,
+                    pointer: self.current_cell_index,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_char(&mut self) -> Result<u8, RuntimeErrorKind> {
+        if let Some(collector) = &mut self.stats {
+            collector.stats.bytes_read += 1;
+        }
+
+        let c = match &mut self.input {
+            Some(input) => input.pop_front().unwrap_or(0),
+            None => self.handler.read_char().map_err(RuntimeErrorKind::Io)?,
+        };
+        #[cfg(feature = "tracing")]
+        tracing::trace!(byte = c, "read");
+
+        Ok(c)
+    }
+
+    fn cell(&self, index: usize) -> u64 {
+        self.tape.get(index).to_u64()
+    }
+
+    fn current_cell(&self) -> u64 {
+        self.cell(self.current_cell_index)
+    }
+
+    fn check_index(&self, index: usize) -> Result<(), RuntimeErrorKind> {
+        let distance = if self.tape.allows_negative() {
+            (index as isize).unsigned_abs()
+        } else {
+            index
+        };
+
+        let max_len = match (self.max_tape_len, self.tape.max_len()) {
+            (Some(limit), Some(len)) => Some(limit.min(len)),
+            (limit, len) => limit.or(len),
+        };
+        match max_len {
+            Some(len) if distance >= len => Err(RuntimeErrorKind::TapeOverflow),
+            _ => Ok(()),
+        }
+    }
+
+    fn set_pointer(&mut self, index: usize) -> Result<(), RuntimeErrorKind> {
+        self.check_index(index)?;
+        self.current_cell_index = index;
+        Ok(())
+    }
+
+    fn shift_right(&mut self, num: usize) -> Result<(), RuntimeErrorKind> {
+        let index = if self.tape.allows_negative() {
+            self.current_cell_index.wrapping_add(num)
+        } else {
+            self.current_cell_index
+                .checked_add(num)
+                .ok_or(RuntimeErrorKind::TapeOverflow)?
+        };
+        self.set_pointer(index)
+    }
+
+    fn shift_left(&mut self, num: usize) -> Result<(), RuntimeErrorKind> {
+        let index = if self.tape.allows_negative() {
+            self.current_cell_index.wrapping_sub(num)
+        } else {
+            self.current_cell_index
+                .checked_sub(num)
+                .ok_or(RuntimeErrorKind::PointerUnderflow {
+                    at: self.current_cell_index,
+                })?
+        };
+        self.set_pointer(index)
+    }
+
+    /// Write the cell at `index`, keeping only the bits of `value` that fit.
+    fn set_cell(&mut self, index: usize, value: u64) -> Result<(), RuntimeErrorKind> {
+        self.check_index(index)?;
+
+        if let Some(limit) = self.memory_limit {
+            let input_len = self.input.as_ref().map_or(0, |input| input.len());
+            if self.tape.memory_usage_after_set(index) + input_len > limit {
+                return Err(RuntimeErrorKind::MemoryLimitExceeded);
+            }
+        }
+
+        self.tape.set(index, S::Cell::from_u64(value));
+        if let Some(collector) = &mut self.stats {
+            collector.touched.insert(index);
+        }
+
+        Ok(())
+    }
+
+    /// Count `expr` and the pointer it runs at, if stats are being collected.
+    fn record_stats(&mut self, expr: &Expr) {
+        if let Some(collector) = &mut self.stats {
+            *collector.stats.exprs.entry(expr.name()).or_insert(0) += 1;
+            let pointer = self.current_cell_index;
+            if !(self.tape.allows_negative() && (pointer as isize) < 0) {
+                collector.stats.max_pointer = collector.stats.max_pointer.max(pointer);
+            }
+            collector.touched.insert(pointer);
+        }
+    }
+
+    fn set_current_cell(&mut self, value: u64) -> Result<(), RuntimeErrorKind> {
+        self.set_cell(self.current_cell_index, value)
+    }
+
+    fn increment(&mut self, num: usize) -> Result<(), RuntimeErrorKind> {
+        let cell = self.current_cell();
+        if u128::from(cell) + num as u128 <= u128::from(S::Cell::MAX) {
+            return self.set_current_cell(cell + num as u64);
+        }
+
+        self.overflow(cell.wrapping_add(num as u64), S::Cell::MAX)
+    }
+
+    fn decrement(&mut self, num: usize) -> Result<(), RuntimeErrorKind> {
+        let cell = self.current_cell();
+        if num as u64 <= cell {
+            return self.set_current_cell(cell - num as u64);
+        }
+
+        self.overflow(cell.wrapping_sub(num as u64), 0)
+    }
+
+    /// Handle an overflow of the current cell according to the overflow policy.
+    fn overflow(&mut self, wrapped: u64, saturated: u64) -> Result<(), RuntimeErrorKind> {
+        let index = self.current_cell_index;
+        self.handler.on_overflow(index);
+
+        match self.overflow_policy {
+            OverflowPolicy::Wrapping => self.set_current_cell(wrapped),
+            OverflowPolicy::Saturating => self.set_current_cell(saturated),
+            OverflowPolicy::Error => Err(RuntimeErrorKind::Overflow { index }),
+        }
+    }
+
+    fn count_output(&mut self) -> Result<(), RuntimeErrorKind> {
+        if matches!(self.max_output, Some(max) if self.output_len >= max) {
+            return Err(RuntimeErrorKind::OutputLimitExceeded);
+        }
+
+        self.output_len += 1;
+        Ok(())
+    }
+
+    fn write_char(&mut self, c: u8) -> Result<(), RuntimeErrorKind> {
+        self.count_output()?;
+        #[cfg(feature = "tracing")]
+        tracing::trace!(byte = c, "write");
+        self.handler.write_char(c).map_err(RuntimeErrorKind::Io)
+    }
+
+    /// Write `s` with `Handler::write_str`, cutting it off at the output limit.
+    fn write_str(&mut self, s: &[u8]) -> Result<(), RuntimeErrorKind> {
+        let allowed = match self.max_output {
+            Some(max) => s.len().min(max.saturating_sub(self.output_len)),
+            None => s.len(),
+        };
+
+        if allowed > 0 {
+            self.output_len += allowed;
+            #[cfg(feature = "tracing")]
+            tracing::trace!(len = allowed, "write");
+            self.handler
+                .write_str(&s[..allowed])
+                .map_err(RuntimeErrorKind::Io)?;
+        }
+
+        if allowed < s.len() {
+            return Err(RuntimeErrorKind::OutputLimitExceeded);
+        }
+
+        Ok(())
+    }
+
+    fn print_char(&mut self) -> Result<(), RuntimeErrorKind> {
+        self.handler.mem_read(self.current_cell_index);
+        let cell = self.current_cell();
+        self.write_char(cell as u8)
+    }
+
+    /// Attach where `kind` happened to it, remembering it for `error_context` too.
+    fn record_error(
+        &mut self,
+        kind: RuntimeErrorKind,
+        expr: Option<&'static str>,
+        position: Option<usize>,
+    ) -> RuntimeError {
+        let pointer = self.current_cell_index;
+        let context = ErrorContext {
+            expr,
+            position,
+            pointer,
+            cell: self.tape.get(pointer).to_u64(),
+        };
+        self.error_context = Some(context.clone());
+
+        RuntimeError {
+            kind,
+            context: Some(Box::new(context)),
+        }
+    }
+
+    /// Check that the loop with `id` can start its iteration number `iterations`.
+    fn check_loop_limit(&self, id: Option<usize>, iterations: u64) -> Result<(), RuntimeErrorKind> {
+        if matches!(self.max_loop_iterations, Some(max) if iterations > max) {
+            return Err(RuntimeErrorKind::LoopLimitExceeded { id });
+        }
+
+        Ok(())
+    }
+
+    fn loop_iteration(&mut self, id: Option<usize>) {
+        if let Some(profile) = &mut self.profile {
+            profile.record(id);
+        }
+
+        let cell = self.current_cell();
+        self.handler
+            .loop_iteration(self.current_cell_index, cell as u8);
+    }
+
+    /// Run `Expr::div_mod_loop`, computing the result directly when the cells are laid out as usual.
+    ///
+    /// Handler hooks aren't called for the shortcut.
+    fn div_mod(&mut self) -> Result<(), RuntimeErrorKind> {
+        let p = self.current_cell_index;
+        let n = self.cell(p);
+        let d = self.cell(p + 2);
+        // The shortcut can't report overflow or moving past the end of the tape
+        let usual = self.overflow_policy == OverflowPolicy::Wrapping
+            && matches!(p.checked_add(6), Some(end) if self.check_index(end).is_ok())
+            && n != 0
+            // With a divisor of 1 the loop ends an iteration 3 cells left of where it started
+            && d > 1
+            && self.cell(p + 3) == 0
+            && self.cell(p + 5) == 0
+            && self.cell(p + 6) == 0;
+
+        if !usual {
+            // The nodes of the loop aren't part of the program being covered
+            let covered = self.covered.take();
+            let result = self.run_expr(&Expr::div_mod_loop());
+            self.covered = covered;
+            return result.map_err(RuntimeError::into_kind);
+        }
+
+        let quotient = self.cell(p + 4).wrapping_add(n / d);
+        let remainder = n % d;
+        let moved = self.cell(p + 1).wrapping_add(n);
+        self.set_cell(p + 4, quotient)?;
+        self.set_cell(p + 3, remainder)?;
+        self.set_cell(p + 2, d - remainder)?;
+        self.set_cell(p + 1, moved)?;
+        self.set_cell(p, 0)?;
+
+        Ok(())
+    }
+
+    /// Clear the tape, pointer, buffered input, program position, loop profile, trace, coverage and error context,
+    /// so the interpreter can run another program without rebuilding the handler.
+    ///
+    /// Settings like the limits, fuel left and cancel token are kept.
+    pub fn reset(&mut self) {
+        self.tape.clear();
+        self.current_cell_index = 0;
+        self.input = None;
+        self.output_len = 0;
+        self.cursor = ProgramCursor::default();
+        self.error_context = None;
+        if self.profile.is_some() {
+            self.profile = Some(LoopProfile::default());
+        }
+        if self.trace.is_some() {
+            self.trace = Some(Trace::new());
+        }
+        if let Some(covered) = &mut self.covered {
+            covered.clear();
+        }
+    }
+
+    /// Run `expr` on a fresh tape, like `run` after `reset`.
+    pub fn run_program(&mut self, expr: &Expr) -> Result<(), RuntimeError> {
+        self.reset();
+        self.run(expr)
+    }
+
+    pub fn run(&mut self, expr: &Expr) -> Result<(), RuntimeError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("run").entered();
+
+        self.output_len = 0;
+        self.error_context = None;
+        let result = self.run_expr(expr);
+        self.finalize();
+
+        result
+    }
+
+    fn finalize(&mut self) {
+        let cells = tape_bytes(&self.tape);
+        self.handler.finalize(&cells, self.current_cell_index);
+    }
+
+    fn breakpoint(&mut self) {
+        let cells = tape_bytes(&self.tape);
+        self.handler.on_breakpoint(&BreakpointState {
+            cells: &cells,
+            pointer: self.current_cell_index,
+            output_len: self.output_len,
+        });
+    }
+
+    /// Run `expr` with an explicit stack of the blocks and loops being run, so deep nesting can't overflow the Rust stack.
+    fn run_expr(&mut self, expr: &Expr) -> Result<(), RuntimeError> {
+        let mut frames = Vec::new();
+        self.enter_expr(expr, 0, &mut frames)
+            .map_err(|e| self.record_error(e, Some(expr.name()), None))?;
+
+        while let Some(frame) = frames.last_mut() {
+            match frame {
+                Frame::Block { exprs, next_node } => match exprs.next() {
+                    Some(expr) => {
+                        let node = *next_node;
+                        if self.covered.is_some() {
+                            *next_node += expr.node_count();
+                        }
+                        self.enter_expr(expr, node, &mut frames)
+                            .map_err(|e| self.record_error(e, Some(expr.name()), None))?
+                    }
+                    None => {
+                        frames.pop();
+                    }
+                },
+                // The top frame is a loop once an iteration of its body is done
+                &mut Frame::Loop {
+                    body,
+                    body_node,
+                    id,
+                    start_index,
+                    iterations,
+                } => {
+                    if self.strict_balanced && self.current_cell_index != start_index {
+                        let e = RuntimeErrorKind::UnbalancedLoop { id };
+                        return Err(self.record_error(e, Some("Loop"), None));
+                    }
+
+                    frames.pop();
+                    if self.current_cell() == 0 {
+                        self.handler.on_loop_exit(id);
+                    } else {
+                        self.start_iteration(body, body_node, id, iterations + 1, &mut frames)
+                            .map_err(|e| self.record_error(e, Some("Loop"), None))?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Start running `expr`, the node at index `node` of `Expr::nodes`, pushing a frame if it has children.
+    /// Anything else runs right away.
+    fn enter_expr<'a>(
+        &mut self,
+        expr: &'a Expr,
+        node: usize,
+        frames: &mut Vec<Frame<'a>>,
+    ) -> Result<(), RuntimeErrorKind> {
+        if !matches!(
+            expr,
+            Expr::Block { .. } | Expr::Comment { .. } | Expr::Breakpoint
+        ) {
+            self.consume_fuel()?;
+        }
+        if let Some(covered) = &mut self.covered {
+            covered.insert(node);
+        }
+        if !matches!(expr, Expr::Block { .. }) {
+            self.record_stats(expr);
+            if let Some(trace) = &mut self.trace {
+                let pointer = self.current_cell_index;
+                trace.record(expr.name(), pointer, self.tape.get(pointer).to_u64());
+            }
+        }
+
+        match expr {
+            Expr::Block { exprs } => {
+                frames.push(Frame::Block {
+                    exprs: exprs.iter(),
+                    next_node: node + 1,
+                });
+            }
+            Expr::Increment { num } => {
+                self.increment(*num)?;
+            }
+            Expr::Decrement { num } => {
+                self.decrement(*num)?;
+            }
+            Expr::ShiftRight { num } => {
+                self.shift_right(*num)?;
+            }
+            Expr::ShiftLeft { num } => {
+                self.shift_left(*num)?;
+            }
+            Expr::Loop { expr, id } => {
+                #[cfg(feature = "tracing")]
+                tracing::trace!(id = ?id, pointer = self.current_cell_index, "loop entered");
+                self.handler.on_loop_enter(*id);
+                self.handler.mem_read(self.current_cell_index);
+                if self.current_cell() != 0 {
+                    self.start_iteration(expr, node + 1, *id, 1, frames)?;
+                } else {
+                    self.handler.on_loop_exit(*id);
+                }
+            }
+            Expr::PrintChar => {
+                self.print_char()?;
+            }
+            Expr::ReadChar => {
+                let c = self.read_char()?;
+                self.set_current_cell(u64::from(c))?;
+            }
+            Expr::Assign { index, value } => {
+                self.set_cell(*index, u64::from(*value))?;
+            }
+            Expr::AssignCurrent { value } => {
+                self.set_current_cell(u64::from(*value))?;
+            }
+            Expr::PrintString { value } => {
+                self.write_str(value.as_bytes())?;
+            }
+            Expr::SetCellPointer { value } => {
+                self.set_pointer(*value)?;
+            }
+            Expr::ReadCharForget => {
+                self.read_char()?;
+            }
+            Expr::DivMod => {
+                self.div_mod()?;
+            }
+            Expr::Comment { .. } => {}
+            Expr::Breakpoint => {
+                self.breakpoint();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Start an iteration of the loop with `body`, leaving a frame to check the loop once it's done.
+    fn start_iteration<'a>(
+        &mut self,
+        body: &'a Expr,
+        body_node: usize,
+        id: Option<usize>,
+        iterations: u64,
+        frames: &mut Vec<Frame<'a>>,
+    ) -> Result<(), RuntimeErrorKind> {
+        self.check_loop_limit(id, iterations)?;
+        self.consume_fuel()?;
+        if let Some(collector) = &mut self.stats {
+            collector.stats.loop_iterations += 1;
+        }
+        let start_index = self.current_cell_index;
+        self.loop_iteration(id);
+
+        frames.push(Frame::Loop {
+            body,
+            body_node,
+            id,
+            start_index,
+            iterations,
+        });
+        self.enter_expr(body, body_node, frames)
+    }
+
+    /// Run lexer output directly, without building an AST.
+    ///
+    /// Loops are run with a bracket jump table, so no loop ids are available.
+    pub fn run_tokens(&mut self, tokens: &[Token]) -> Result<(), RuntimeError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("run", tokens = tokens.len()).entered();
+
+        let mut jumps = vec![0; tokens.len()];
+        let mut stack = Vec::new();
+        for (i, token) in tokens.iter().enumerate() {
+            match token.data {
+                TokenData::StartLoop => stack.push(i),
+                TokenData::EndLoop => {
+                    let start = stack.pop().ok_or(RuntimeErrorKind::UnmatchedBracket {
+                        token: i,
+                        offset: token.offset,
+                    })?;
+                    jumps[start] = i;
+                    jumps[i] = start;
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(&token) = stack.last() {
+            return Err(RuntimeErrorKind::UnmatchedBracket {
+                token,
+                offset: tokens[token].offset,
+            }
+            .into());
+        }
+
+        self.output_len = 0;
+        self.error_context = None;
+
+        // The pointer at the start of the current iteration of each running loop, and the iterations it ran
+        let mut loop_starts = Vec::new();
+        let mut i = 0;
+        let mut result = Ok(());
+        while i < tokens.len() {
+            if let Err(e) = self.run_token(tokens, &jumps, &mut loop_starts, &mut i) {
+                result = Err(self.record_error(e, None, Some(i)));
+                break;
+            }
+
+            i += 1;
+        }
+        self.finalize();
+
+        result
+    }
+
+    /// Run the token at `i` for `run_tokens`, moving `i` to the matching bracket for jumps.
+    fn run_token(
+        &mut self,
+        tokens: &[Token],
+        jumps: &[usize],
+        loop_starts: &mut Vec<(usize, u64)>,
+        i: &mut usize,
+    ) -> Result<(), RuntimeErrorKind> {
+        self.consume_fuel()?;
+        match tokens[*i].data {
+            TokenData::Increment(num) => {
+                self.increment(num)?;
+            }
+            TokenData::Decrement(num) => {
+                self.decrement(num)?;
+            }
+            TokenData::ShiftRight(num) => {
+                self.shift_right(num)?;
+            }
+            TokenData::ShiftLeft(num) => {
+                self.shift_left(num)?;
+            }
+            TokenData::StartLoop => {
+                #[cfg(feature = "tracing")]
+                tracing::trace!(pointer = self.current_cell_index, "loop entered");
+                self.handler.on_loop_enter(None);
+                self.handler.mem_read(self.current_cell_index);
+                if self.current_cell() == 0 {
+                    self.handler.on_loop_exit(None);
+                    *i = jumps[*i];
+                } else {
+                    self.check_loop_limit(None, 1)?;
+                    loop_starts.push((self.current_cell_index, 1));
+                    self.loop_iteration(None);
+                }
+            }
+            TokenData::EndLoop => {
+                let (start_index, iterations) = loop_starts.pop().unwrap();
+                if self.strict_balanced && self.current_cell_index != start_index {
+                    return Err(RuntimeErrorKind::UnbalancedLoop { id: None });
+                }
+
+                if self.current_cell() != 0 {
+                    self.check_loop_limit(None, iterations + 1)?;
+                    *i = jumps[*i];
+                    loop_starts.push((self.current_cell_index, iterations + 1));
+                    self.loop_iteration(None);
+                } else {
+                    self.handler.on_loop_exit(None);
+                }
+            }
+            TokenData::Print => {
+                self.print_char()?;
+            }
+            TokenData::Read => {
+                let c = self.read_char()?;
+                self.set_current_cell(u64::from(c))?;
+            }
+            TokenData::Breakpoint => {
+                self.breakpoint();
+            }
+            TokenData::Other(_) => {}
+        }
+
+        Ok(())
+    }
+
+    /// Run `program` until it prints a byte, and return that byte instead of passing it to the handler.
+    ///
+    /// The position in `program` is kept, so the next call continues after the returned byte.
+    /// Returns `None` once the program finishes, and the call after that starts it over.
+    pub fn run_until_output(&mut self, program: &Program) -> Result<Option<u8>, RuntimeError> {
+        loop {
+            if let Some(c) = self.cursor.pending.pop_front() {
+                self.count_output()?;
+                return Ok(Some(c));
+            }
+
+            if self.cursor.pc >= program.ops.len() {
+                self.cursor = ProgramCursor::default();
+                return Ok(None);
+            }
+
+            self.run_op(program)?;
+        }
+    }
+
+    /// Run the next op of `program`, sharing the position with `run_until_output`.
+    ///
+    /// Output goes to the handler. Returns `StepStatus::Done` after the last op, once the handler is finalized,
+    /// and the call after that starts the program over.
+    pub fn step(&mut self, program: &Program) -> Result<StepStatus, RuntimeError> {
+        if let Some(Op::ReadChar) | Some(Op::ReadCharForget) = program.ops.get(self.cursor.pc) {
+            let blocked = match &self.input {
+                Some(input) => input.is_empty(),
+                None => !self.handler.input_ready(),
+            };
+            if blocked {
+                return Ok(StepStatus::Blocked);
+            }
+        }
+
+        if self.cursor.pc < program.ops.len() {
+            self.run_op(program)?;
+        }
+
+        if !self.cursor.pending.is_empty() {
+            let pending = Vec::from(std::mem::take(&mut self.cursor.pending));
+            self.write_str(&pending)?;
+        }
+
+        if self.cursor.pc >= program.ops.len() {
+            self.cursor = ProgramCursor::default();
+            self.finalize();
+            return Ok(StepStatus::Done);
+        }
+
+        Ok(StepStatus::Running)
+    }
+
+    /// Run `program` to the end, reading and writing through `handler` instead of the interpreter's handler.
+    ///
+    /// Continues from the position shared with `step`. Input pushed with `push_input` is still read first.
+    #[cfg(feature = "tokio")]
+    pub async fn run_async<A: AsyncHandler>(
+        &mut self,
+        program: &Program,
+        handler: &mut A,
+    ) -> Result<(), RuntimeError> {
+        while let Some(op) = program.ops.get(self.cursor.pc) {
+            match op {
+                Op::ReadChar | Op::ReadCharForget if self.input.is_none() => {
+                    self.consume_fuel()?;
+                    self.cursor.pc += 1;
+                    let c = handler.read_char().await.map_err(RuntimeErrorKind::Io)?;
+                    if let Op::ReadChar = op {
+                        self.set_current_cell(u64::from(c))?;
+                    }
+                }
+                _ => self.run_op(program)?,
+            }
+
+            while let Some(c) = self.cursor.pending.pop_front() {
+                self.count_output()?;
+                handler.write_char(c).await.map_err(RuntimeErrorKind::Io)?;
+            }
+        }
+
+        self.cursor = ProgramCursor::default();
+        self.finalize();
+
+        Ok(())
+    }
+
+    /// Run `program` as an iterator of events, instead of passing output to the handler.
+    ///
+    /// Reads only come from input added with `Events::push_input`. The position is shared with `step`.
+    /// Iteration stops after `Event::Halted` or the first error.
+    pub fn events<'a>(&'a mut self, program: &'a Program) -> Events<'a, T, S> {
+        Events {
+            vm: self,
+            program,
+            done: false,
+        }
+    }
+
+    /// The index of the op in the program passed to `step` or `run_until_output` that runs next.
+    pub fn program_counter(&self) -> usize {
+        self.cursor.pc
+    }
+
+    /// Buffer `input` for reads, in front of the handler.
+    ///
+    /// `step` reports `StepStatus::Blocked` instead of reading once it runs out, while other reads produce 0.
+    pub fn push_input(&mut self, input: &[u8]) {
+        self.input
+            .get_or_insert_with(VecDeque::new)
+            .extend(input.iter().copied());
+    }
+
+    /// Capture the tape, pointer and position in the program being stepped through.
+    ///
+    /// Cells left of 0 and settings like the fuel aren't included.
+    pub fn save_state(&self) -> InterpreterState<S::Cell> {
+        InterpreterState {
+            cells: (0..self.tape.len()).map(|i| self.tape.get(i)).collect(),
+            pointer: self.current_cell_index,
+            program_counter: self.cursor.pc,
+            loop_starts: self.cursor.loop_starts.clone(),
+            loop_iterations: self.cursor.loop_iterations.clone(),
+            pending_output: self.cursor.pending.iter().copied().collect(),
+            input: self
+                .input
+                .as_ref()
+                .map(|input| input.iter().copied().collect()),
+        }
+    }
+
+    /// Continue from a state returned by `Interpreter::save_state`.
+    ///
+    /// Cells past the saved ones, and any left of 0, read as the tape's fill value. Fails without changing anything if the cells or pointer
+    /// don't fit in the tape limits. The memory limit isn't checked, since only cells that differ from the fill value
+    /// are stored, like in the run the state came from.
+    pub fn load_state(&mut self, state: &InterpreterState<S::Cell>) -> Result<(), RuntimeError> {
+        if let Some(last) = state.cells.len().checked_sub(1) {
+            self.check_index(last)?;
+        }
+        self.check_index(state.pointer)?;
+        let loop_iterations = match state.loop_iterations.len() {
+            0 => vec![0; state.loop_starts.len()],
+            len if len == state.loop_starts.len() => state.loop_iterations.clone(),
+            _ => {
+                return Err(RuntimeErrorKind::InvalidState(
+                    "loop_iterations doesn't match loop_starts",
+                )
+                .into())
+            }
+        };
+
+        self.tape.clear();
+        for (i, &cell) in state.cells.iter().enumerate() {
+            if self.tape.get(i) != cell {
+                self.tape.set(i, cell);
+            }
+        }
+
+        self.input = state
+            .input
+            .as_ref()
+            .map(|input| input.iter().copied().collect());
+        self.current_cell_index = state.pointer;
+        self.cursor = ProgramCursor {
+            pc: state.program_counter,
+            pending: state.pending_output.iter().copied().collect(),
+            loop_starts: state.loop_starts.clone(),
+            loop_iterations,
+        };
+
+        Ok(())
+    }
+
+    /// Copy the tape, pointer, program position and settings into a new interpreter that uses `handler`,
+    /// so several branches can continue from the same point. Cloning does the same but keeps a clone of the handler.
+    ///
+    /// The fork shares its cells with this interpreter if the tape is a `SharedTape`, and its cancel token if it has one.
+    pub fn fork<U: Handler>(&self, handler: U) -> Interpreter<U, S>
+    where
+        S: Clone,
+    {
+        Interpreter {
+            tape: self.tape.clone(),
+            current_cell_index: self.current_cell_index,
+            strict_balanced: self.strict_balanced,
+            input: self.input.clone(),
+            output_len: self.output_len,
+            fuel: self.fuel,
+            memory_limit: self.memory_limit,
+            overflow_policy: self.overflow_policy,
+            max_tape_len: self.max_tape_len,
+            max_output: self.max_output,
+            max_loop_iterations: self.max_loop_iterations,
+            deadline: self.deadline,
+            deadline_countdown: self.deadline_countdown,
+            stats: self.stats.clone(),
+            profile: self.profile.clone(),
+            trace: self.trace.clone(),
+            cancel_token: self.cancel_token.clone(),
+            error_context: self.error_context.clone(),
+            covered: self.covered.clone(),
+            tick_interval: self.tick_interval,
+            tick_countdown: self.tick_countdown,
+            cursor: self.cursor.clone(),
+
+            handler,
+        }
+    }
+
+    /// Step through `program` until it finishes, passing a `save_state` checkpoint to `sink` after every `interval` steps.
+    /// An `interval` of 0 is treated as 1.
+    ///
+    /// After a crash, `load_state` the last checkpoint and call this again to continue from it.
+    /// Errors from `sink` end the run with `RuntimeErrorKind::Io`. Fails with `io::ErrorKind::WouldBlock`
+    /// if the handler's input isn't ready, like when stepping through a `pipe` that nothing is feeding.
+    pub fn run_with_checkpoints<F>(
+        &mut self,
+        program: &Program,
+        interval: u64,
+        mut sink: F,
+    ) -> Result<(), RuntimeError>
+    where
+        F: FnMut(&InterpreterState<S::Cell>) -> io::Result<()>,
+    {
+        let interval = interval.max(1);
+        let mut countdown = interval;
+        loop {
+            match self.step(program)? {
+                StepStatus::Running => {}
+                StepStatus::Done => return Ok(()),
+                StepStatus::Blocked => {
+                    return Err(RuntimeErrorKind::Io(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        "the handler has no input ready",
+                    ))
+                    .into())
+                }
+            }
+
+            countdown -= 1;
+            if countdown == 0 {
+                sink(&self.save_state()).map_err(RuntimeErrorKind::Io)?;
+                countdown = interval;
+            }
+        }
+    }
+
+    /// Run the op at the cursor, queueing its output.
+    fn run_op(&mut self, program: &Program) -> Result<(), RuntimeError> {
+        let pc = self.cursor.pc;
+        self.exec_op(program)
+            .map_err(|e| self.record_error(e, None, Some(pc)))
+    }
+
+    fn exec_op(&mut self, program: &Program) -> Result<(), RuntimeErrorKind> {
+        let op = &program.ops[self.cursor.pc];
+        self.consume_fuel()?;
+        self.cursor.pc += 1;
+
+        match op {
+            Op::Increment(num) => {
+                self.increment(*num)?;
+            }
+            Op::Decrement(num) => {
+                self.decrement(*num)?;
+            }
+            Op::ShiftRight(num) => {
+                self.shift_right(*num)?;
+            }
+            Op::ShiftLeft(num) => {
+                self.shift_left(*num)?;
+            }
+            Op::LoopStart { end, id } => {
+                #[cfg(feature = "tracing")]
+                tracing::trace!(id = ?id, pointer = self.current_cell_index, "loop entered");
+                self.handler.on_loop_enter(*id);
+                self.handler.mem_read(self.current_cell_index);
+                if self.current_cell() == 0 {
+                    self.handler.on_loop_exit(*id);
+                    self.cursor.pc = end + 1;
+                } else {
+                    self.check_loop_limit(*id, 1)?;
+                    self.cursor.loop_starts.push(self.current_cell_index);
+                    self.cursor.loop_iterations.push(1);
+                    self.loop_iteration(*id);
+                }
+            }
+            Op::LoopEnd { start, id } => {
+                // Only a loaded state can end a loop that never started
+                let start_index =
+                    self.cursor
+                        .loop_starts
+                        .pop()
+                        .ok_or(RuntimeErrorKind::InvalidState(
+                            "program_counter is inside more loops than loop_starts",
+                        ))?;
+                let iterations = self.cursor.loop_iterations.pop().unwrap_or(0);
+                if self.strict_balanced && self.current_cell_index != start_index {
+                    return Err(RuntimeErrorKind::UnbalancedLoop { id: *id });
+                }
+
+                if self.current_cell() != 0 {
+                    self.check_loop_limit(*id, iterations + 1)?;
+                    self.cursor.pc = start + 1;
+                    self.cursor.loop_starts.push(self.current_cell_index);
+                    self.cursor.loop_iterations.push(iterations + 1);
+                    self.loop_iteration(*id);
+                } else {
+                    self.handler.on_loop_exit(*id);
+                }
+            }
+            Op::PrintChar => {
+                self.handler.mem_read(self.current_cell_index);
+                let cell = self.current_cell();
+                self.cursor.pending.push_back(cell as u8);
+            }
+            Op::ReadChar => {
+                let c = self.read_char()?;
+                self.set_current_cell(u64::from(c))?;
+            }
+            Op::Assign { index, value } => {
+                self.set_cell(*index, u64::from(*value))?;
+            }
+            Op::AssignCurrent(value) => {
+                self.set_current_cell(u64::from(*value))?;
+            }
+            Op::PrintString(value) => {
+                self.cursor.pending.extend(value.bytes());
+            }
+            Op::SetCellPointer(value) => {
+                self.set_pointer(*value)?;
+            }
+            Op::ReadCharForget => {
+                self.read_char()?;
+            }
+            Op::DivMod => {
+                self.div_mod()?;
+            }
+            Op::Breakpoint => {
+                self.breakpoint();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run `expr`, reading from `input` instead of the handler.
+    ///
+    /// Reads past the end of `input` produce 0.
+    pub fn run_with_input(&mut self, expr: &Expr, input: &[u8]) -> Result<(), RuntimeError> {
+        self.input = Some(input.iter().copied().collect());
+        let result = self.run(expr);
+        self.input = None;
+
+        result
+    }
+
+    /// Run `expr` with `max_steps` of fuel instead of the limit set with `Interpreter::set_fuel`.
+    ///
+    /// Fails with `RuntimeErrorKind::FuelExhausted` if `expr` runs longer.
+    pub fn run_with_fuel(&mut self, expr: &Expr, max_steps: u64) -> Result<(), RuntimeError> {
+        let fuel = self.fuel.replace(max_steps);
+        let result = self.run(expr);
+        self.fuel = fuel;
+
+        result
+    }
+
+    /// Run `expr` and count what it did, for comparing programs or optimizer passes.
+    ///
+    /// Collecting the stats slows the run down.
+    pub fn run_with_stats(&mut self, expr: &Expr) -> Result<ExecutionStats, RuntimeError> {
+        self.stats = Some(Box::default());
+        let result = self.run(expr);
+        let collector = self.stats.take().unwrap();
+        result?;
+
+        let mut stats = collector.stats;
+        stats.cells_touched = collector.touched.len();
+        stats.bytes_written = self.output_len;
+
+        Ok(stats)
+    }
+
+    /// Run `expr`, failing with `RuntimeErrorKind::Timeout` once it has run for longer than `timeout`.
+    ///
+    /// The clock is only checked every few thousand steps, and a read that blocks isn't interrupted.
+    pub fn run_with_timeout(&mut self, expr: &Expr, timeout: Duration) -> Result<(), RuntimeError> {
+        let deadline = std::mem::replace(&mut self.deadline, Instant::now().checked_add(timeout));
+        self.deadline_countdown = 0;
+        let result = self.run(expr);
+        self.deadline = deadline;
+
+        result
+    }
+
+    /// Like `run`, but converts a panic during execution into `RuntimeErrorKind::InternalPanic`.
+    ///
+    /// The interpreter state is left as it was when the panic occurred.
+    pub fn run_catch_unwind(&mut self, expr: &Expr) -> Result<(), RuntimeError> {
+        match panic::catch_unwind(AssertUnwindSafe(|| self.run(expr))) {
+            Ok(result) => result,
+            Err(payload) => {
+                let message = if let Some(message) = payload.downcast_ref::<&str>() {
+                    message.to_string()
+                } else if let Some(message) = payload.downcast_ref::<String>() {
+                    message.clone()
+                } else {
+                    String::from("unknown panic")
+                };
+
+                let kind = RuntimeErrorKind::InternalPanic(message);
+                Err(self.record_error(kind, None, None))
+            }
+        }
+    }
+}
+
+/// The events of a program, from `Interpreter::events`.
+pub struct Events<'a, T, S> {
+    vm: &'a mut Interpreter<T, S>,
+    program: &'a Program,
+    done: bool,
+}
+
+impl<'a, T: Handler, S: Tape> Events<'a, T, S> {
+    /// Buffer `input` for the program to read.
+    pub fn push_input(&mut self, input: &[u8]) {
+        self.vm.push_input(input);
+    }
+
+    pub fn interpreter(&self) -> &Interpreter<T, S> {
+        self.vm
+    }
+
+    fn next_event(&mut self) -> Result<Event, RuntimeError> {
+        loop {
+            if let Some(c) = self.vm.cursor.pending.pop_front() {
+                self.vm.count_output()?;
+                return Ok(Event::Output(c));
+            }
+
+            let pc = self.vm.cursor.pc;
+            match self.program.ops.get(pc) {
+                None => {
+                    self.vm.cursor = ProgramCursor::default();
+                    return Ok(Event::Halted);
+                }
+                Some(Op::ReadChar) | Some(Op::ReadCharForget) if !matches!(&self.vm.input, Some(input) if !input.is_empty()) =>
+                {
+                    return Ok(Event::NeedsInput);
+                }
+                Some(Op::LoopStart { id, .. }) => {
+                    self.vm.run_op(self.program)?;
+                    if self.vm.cursor.pc == pc + 1 {
+                        return Ok(Event::LoopEntered { id: *id });
+                    }
+                }
+                Some(_) => self.vm.run_op(self.program)?,
+            }
+        }
+    }
+}
+
+impl<'a, T: Handler, S: Tape> Iterator for Events<'a, T, S> {
+    type Item = Result<Event, RuntimeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let event = self.next_event();
+        self.done = matches!(event, Ok(Event::Halted) | Err(_));
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        estimate_tape_size,
+        DefaultHandler,
+        LineHandler,
+        RuntimeError,
+        RuntimeErrorKind,
+    };
+    use crate::{
+        codegen::CellWidth,
+        tape::Tape,
+        test_util::parse,
+        *,
+    };
+    use std::{
+        io,
+        time::Duration,
+    };
+
+    #[derive(Clone)]
+    struct TestHandler {
+        out: String,
+    }
+
+    impl TestHandler {
+        fn new() -> Self {
+            Self { out: String::new() }
+        }
+    }
+
+    impl Handler for TestHandler {
+        fn write_char(&mut self, c: u8) -> io::Result<()> {
+            self.out.push(char::from(c));
+            Ok(())
+        }
+    }
+
+    fn test_output(data: &str, expected: &str) {
+        let mut l = Lexer::new(data);
+        l.lex().unwrap();
+
+        let mut p = Parser::new(l.tokens);
+        let exprs = p.parse().unwrap();
+
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.run(&exprs).unwrap();
+
+        assert_eq!(vm.handler.out.as_str(), expected);
+    }
+
+    #[test]
+    fn factorial() {
+        test_output(
+            include_str!("../test_data/factorial.bf"),
+            "0! = 1\n1! = 1\n2! = 2\n3! = 6\n4! = 24\n5! = 120\n6! = 28\n",
+        ); // Requires 16 bit cells for proper answer
+    }
+
+    #[test]
+    fn squares() {
+        test_output(include_str!("../test_data/squares.bf"), "0\n1\n4\n9\n16\n25\n36\n49\n64\n81\n100\n121\n144\n169\n196\n225\n256\n289\n324\n361\n400\n441\n484\n529\n576\n625\n676\n729\n784\n841\n900\n961\n1024\n1089\n1156\n1225\n1296\n1369\n1444\n1521\n1600\n1681\n1764\n1849\n1936\n2025\n2116\n2209\n2304\n2401\n2500\n2601\n2704\n2809\n2916\n3025\n3136\n3249\n3364\n3481\n3600\n3721\n3844\n3969\n4096\n4225\n4356\n4489\n4624\n4761\n4900\n5041\n5184\n5329\n5476\n5625\n5776\n5929\n6084\n6241\n6400\n6561\n6724\n6889\n7056\n7225\n7396\n7569\n7744\n7921\n8100\n8281\n8464\n8649\n8836\n9025\n9216\n9409\n9604\n9801\n10000\n");
+    }
+
+    #[test]
+    fn hello_world3() {
+        test_output(
+            include_str!("../test_data/hello_world3.bf"),
+            "Hello, world!\n",
+        );
+    }
+
+    #[test]
+    fn hello_world2() {
+        test_output(
+            include_str!("../test_data/hello_world2.bf"),
+            "Hello World!\n",
+        );
+    }
+
+    #[test]
+    fn hello_world1() {
+        test_output(
+            include_str!("../test_data/hello_world1.bf"),
+            "Hello World!\n",
+        );
+    }
+
+    #[test]
+    fn count_down() {
+        test_output(
+            include_str!("../test_data/count_down.bf"),
+            "9 8 7 6 5 4 3 2 1 0 ",
+        );
+    }
+
+    fn run_lines(data: &str) -> LineHandler {
+        let exprs = parse(data);
+
+        let mut vm = Interpreter::new(LineHandler::new());
+        vm.run(&exprs).unwrap();
+
+        vm.handler
+    }
+
+    #[test]
+    fn run_until_output() {
+        let program = Program::compile(&parse(include_str!("../test_data/count_down.bf")));
+        let mut vm = Interpreter::new(TestHandler::new());
+
+        let mut output = String::new();
+        while let Some(c) = vm.run_until_output(&program).unwrap() {
+            output.push(char::from(c));
+        }
+
+        assert_eq!(output, "9 8 7 6 5 4 3 2 1 0 ");
+        assert!(vm.handler.out.is_empty());
+
+        let program = Program::compile(&Expr::PrintString { value: "ab".into() });
+        assert_eq!(vm.run_until_output(&program).unwrap(), Some(b'a'));
+        assert_eq!(vm.run_until_output(&program).unwrap(), Some(b'b'));
+        assert_eq!(vm.run_until_output(&program).unwrap(), None);
+        assert_eq!(vm.run_until_output(&program).unwrap(), Some(b'a'));
+    }
+
+    #[test]
+    fn cell_width() {
+        fn run<C: TapeCell>(data: &str) -> Vec<u8> {
+            let mut vm = Interpreter::with_tape(RecordingHandler::new(), VecTape::<C>::default());
+            vm.run(&parse(data)).unwrap();
+            vm.handler.output().to_vec()
+        }
+
+        let factorial = include_str!("../test_data/factorial.bf");
+        assert_eq!(run::<u8>(factorial), reference::run(factorial, b""));
+        assert_eq!(
+            run::<u16>(factorial),
+            reference::run_with_cell_width(factorial, b"", CellWidth::U16)
+        );
+
+        let hello_world = include_str!("../test_data/hello_world1.bf");
+        let expected = reference::run(hello_world, b"");
+        assert_eq!(run::<u16>(hello_world), expected);
+        assert_eq!(run::<u32>(hello_world), expected);
+        assert_eq!(run::<u64>(hello_world), expected);
+
+        let mut vm = Interpreter::with_tape(OverflowHandler::default(), VecTape::<u16>::default());
+        vm.run(&parse("->+++[>++++++++<-]>[<<+>>-]")).unwrap();
+        assert_eq!(vm.cells(), [23, 0, 0]);
+        assert_eq!(vm.handler.overflows, [0, 0]);
+
+        let mut vm = Interpreter::with_tape(DefaultHandler, VecTape::<u64>::default());
+        vm.run(&parse("-")).unwrap();
+        assert_eq!(vm.cells(), [u64::MAX]);
+    }
+
+    #[test]
+    fn overflow_policy() {
+        let run = |policy, data| {
+            let mut vm = Interpreter::new(OverflowHandler::default());
+            vm.set_overflow_policy(policy);
+            let result = vm.run(&parse(data));
+            (result, vm.cells().to_vec(), vm.handler.overflows)
+        };
+
+        let (result, cells, overflows) = run(OverflowPolicy::Wrapping, "->++");
+        assert!(result.is_ok());
+        assert_eq!(cells, [255, 2]);
+        assert_eq!(overflows, [0]);
+
+        let (result, cells, overflows) = run(
+            OverflowPolicy::Saturating,
+            "--->++++++++[<++++++++++++++++++++++++++++++++++++>-]",
+        );
+        assert!(result.is_ok());
+        assert_eq!(cells, [255, 0]);
+        assert_eq!(overflows, [0, 0]);
+
+        let (result, cells, overflows) = run(OverflowPolicy::Error, "+>+++<--");
+        assert!(matches!(
+            result.as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::Overflow { index: 0 })
+        ));
+        assert_eq!(cells, [1, 3]);
+        assert_eq!(overflows, [0]);
+    }
+
+    #[test]
+    fn max_tape_len() {
+        let mut vm = Interpreter::new(DefaultHandler);
+        vm.set_max_tape_len(Some(3));
+        vm.run(&parse(">>+<<+")).unwrap();
+        assert_eq!(vm.cells(), [1, 0, 1]);
+
+        assert!(matches!(
+            vm.run(&parse("+[>+]")).as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::TapeOverflow)
+        ));
+        assert_eq!(vm.current_cell_index(), 2);
+        assert_eq!(vm.cells(), [2, 1, 2]);
+
+        let mut vm = Interpreter::new(DefaultHandler);
+        vm.set_max_tape_len(Some(3));
+        assert!(matches!(
+            vm.run(&Expr::Assign { index: 3, value: 1 })
+                .as_ref()
+                .map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::TapeOverflow)
+        ));
+        let mut l = Lexer::new(">>>");
+        l.lex().unwrap();
+        assert!(matches!(
+            vm.run_tokens(&l.tokens)
+                .as_ref()
+                .map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::TapeOverflow)
+        ));
+        assert!(vm.cells().is_empty());
+
+        vm.set_max_tape_len(None);
+        vm.run(&parse(">>>+")).unwrap();
+        assert_eq!(vm.cells(), [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn bidirectional() {
+        let mut vm = Interpreter::bidirectional(TestHandler::new());
+        vm.run(&parse("<<+++[>++++++++++++<-]>+.<<+>>>++<"))
+            .unwrap();
+        assert_eq!(vm.handler.out, "%");
+        assert_eq!(vm.current_cell_index() as isize, -1);
+        assert_eq!(vm.tape().cells(), [2]);
+        assert_eq!(vm.tape().negative_cells(), [37, 0, 1]);
+
+        let mut vm = Interpreter::bidirectional(TestHandler::new());
+        vm.set_max_tape_len(Some(2));
+        vm.run(&parse("<+>>+")).unwrap();
+        assert!(matches!(
+            vm.run(&parse("<<<")).as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::TapeOverflow)
+        ));
+        assert_eq!(vm.current_cell_index(), 1);
+
+        let expected = reference::run(include_str!("../test_data/squares.bf"), b"");
+        let mut vm = Interpreter::bidirectional(TestHandler::new());
+        vm.run(&parse(include_str!("../test_data/squares.bf")))
+            .unwrap();
+        assert_eq!(vm.handler.out.as_bytes(), expected);
+    }
+
+    #[test]
+    fn run_with_fuel() {
+        let mut vm = Interpreter::new(TestHandler::new());
+        assert!(matches!(
+            vm.run_with_fuel(&parse("+[]"), 1000)
+                .as_ref()
+                .map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::FuelExhausted)
+        ));
+        assert_eq!(vm.fuel(), None);
+
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.set_fuel(Some(5));
+        vm.run_with_fuel(&parse(include_str!("../test_data/count_down.bf")), 10_000)
+            .unwrap();
+        assert_eq!(vm.handler.out, "9 8 7 6 5 4 3 2 1 0 ");
+        assert_eq!(vm.fuel(), Some(5));
+    }
+
+    #[test]
+    fn step() {
+        let program = Program::compile(&parse(",[.,]"));
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.push_input(b"a");
+
+        let mut statuses = Vec::new();
+        loop {
+            let status = vm.step(&program).unwrap();
+            statuses.push(status);
+            if status == StepStatus::Blocked {
+                break;
+            }
+        }
+        assert_eq!(
+            statuses,
+            [
+                StepStatus::Running,
+                StepStatus::Running,
+                StepStatus::Running,
+                StepStatus::Blocked
+            ]
+        );
+        assert_eq!(vm.handler.out, "a");
+        assert_eq!(vm.program_counter(), 3);
+
+        vm.push_input(b"b\0");
+        while vm.step(&program).unwrap() == StepStatus::Running {}
+        assert_eq!(vm.handler.out, "ab");
+        assert_eq!(vm.program_counter(), 0);
+    }
+
+    #[derive(Default)]
+    struct BreakpointHandler {
+        breakpoints: Vec<(Vec<u8>, usize, usize)>,
+    }
+
+    impl Handler for BreakpointHandler {
+        fn on_breakpoint(&mut self, state: &BreakpointState) {
+            self.breakpoints
+                .push((state.cells.to_vec(), state.pointer, state.output_len));
+        }
+    }
+
+    #[test]
+    fn breakpoint() {
+        let mut l = Lexer::new("++#>+.# done");
+        l.set_breakpoints(true);
+        l.lex().unwrap();
+        let expected = [(vec![2], 0, 0), (vec![2, 1], 1, 1)];
+
+        let mut vm = Interpreter::new(BreakpointHandler::default());
+        vm.run(&Parser::new(l.tokens.clone()).parse().unwrap())
+            .unwrap();
+        assert_eq!(vm.handler.breakpoints, expected);
+
+        let mut vm = Interpreter::new(BreakpointHandler::default());
+        vm.run_tokens(&l.tokens).unwrap();
+        assert_eq!(vm.handler.breakpoints, expected);
+
+        let program = Program::compile(&Parser::new(l.tokens).parse().unwrap());
+        let mut vm = Interpreter::new(BreakpointHandler::default());
+        while vm.step(&program).unwrap() == StepStatus::Running {}
+        assert_eq!(vm.handler.breakpoints, expected);
+        assert_eq!(Program::from_bytes(&program.to_bytes()).unwrap(), program);
+    }
+
+    #[test]
+    fn save_state() {
+        let program = Program::compile(&parse(include_str!("../test_data/count_down.bf")));
+        let mut vm = Interpreter::new(TestHandler::new());
+        for _ in 0..6 {
+            vm.run_until_output(&program).unwrap();
+        }
+
+        let state = vm.save_state();
+        #[cfg(feature = "serde")]
+        let state: InterpreterState =
+            serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+
+        let mut output = Vec::new();
+        while let Some(c) = vm.run_until_output(&program).unwrap() {
+            output.push(c);
+        }
+        assert_eq!(output, b"6 5 4 3 2 1 0 ");
+
+        let mut resumed = Interpreter::new(TestHandler::new());
+        resumed.run(&parse(">>>>+")).unwrap();
+        resumed.load_state(&state).unwrap();
+        assert_eq!(resumed.save_state(), state);
+
+        let mut resumed_output = Vec::new();
+        while let Some(c) = resumed.run_until_output(&program).unwrap() {
+            resumed_output.push(c);
+        }
+        assert_eq!(resumed_output, output);
+    }
+
+    #[test]
+    fn run_with_checkpoints() {
+        let program = Program::compile(&parse(include_str!("../test_data/count_down.bf")));
+        let mut vm = Interpreter::new(RecordingHandler::new());
+        let mut checkpoints = Vec::new();
+        vm.run_with_checkpoints(&program, 50, |state| {
+            checkpoints.push(state.clone());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(vm.handler.output(), b"9 8 7 6 5 4 3 2 1 0 ");
+        assert!(checkpoints.len() > 2);
+
+        let state = &checkpoints[checkpoints.len() / 2];
+        let mut resumed = Interpreter::new(RecordingHandler::new());
+        resumed.load_state(state).unwrap();
+        resumed
+            .run_with_checkpoints(&program, 50, |_| Ok(()))
+            .unwrap();
+        assert!(vm.handler.output().ends_with(resumed.handler.output()));
+        assert!(!resumed.handler.output().is_empty());
+
+        let mut vm = Interpreter::new(RecordingHandler::new());
+        let result = vm.run_with_checkpoints(&program, 50, |_| Err(io::Error::other("disk full")));
+        assert!(matches!(
+            result.as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::Io(_))
+        ));
+        assert!(vm.handler.output().len() < 20);
+
+        let mut counts = Vec::new();
+        for interval in [0, 1] {
+            let mut vm = Interpreter::new(RecordingHandler::new());
+            let mut count = 0;
+            vm.run_with_checkpoints(&Program::compile(&parse("+>+>+")), interval, |_| {
+                count += 1;
+                Ok(())
+            })
+            .unwrap();
+            counts.push(count);
+        }
+        assert_eq!(counts[0], counts[1]);
+        assert!(counts[0] > 0);
+    }
+
+    #[test]
+    fn load_state_checks() {
+        let program = Program::compile(&parse("++[>+++<-]>."));
+        let mut vm = Interpreter::new(RecordingHandler::new());
+        for _ in 0..8 {
+            vm.step(&program).unwrap();
+        }
+        let state = vm.save_state();
+        assert_eq!(state.loop_iterations, [2]);
+
+        // Nothing changes if the state doesn't fit
+        let mut small = Interpreter::new(RecordingHandler::new());
+        small.set_max_tape_len(Some(1));
+        small.run(&parse("+++")).unwrap();
+        let before = small.save_state();
+        assert!(matches!(
+            small
+                .load_state(&state)
+                .as_ref()
+                .map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::TapeOverflow)
+        ));
+        assert_eq!(small.save_state(), before);
+
+        let mut bad = state.clone();
+        bad.loop_iterations = vec![1, 2];
+        assert!(matches!(
+            vm.load_state(&bad).as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::InvalidState(_))
+        ));
+
+        // A crafted state can claim to be inside a loop without saying where it started
+        let mut bad = state.clone();
+        bad.loop_starts.clear();
+        bad.loop_iterations.clear();
+        vm.load_state(&bad).unwrap();
+        let result = (0..10).try_for_each(|_| vm.step(&program).map(drop));
+        assert!(matches!(
+            result.as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::InvalidState(_))
+        ));
+
+        // Only cells that differ from the fill value are stored
+        let mut sparse = Interpreter::sparse(RecordingHandler::new());
+        sparse
+            .run(&parse(&format!("{}+", ">".repeat(1_000_000))))
+            .unwrap();
+        sparse.set_memory_limit(Some(1024));
+        sparse.load_state(&state).unwrap();
+        assert!(sparse.tape().memory_usage() < 1024);
+        while sparse.step(&program).unwrap() != StepStatus::Done {}
+        assert_eq!(sparse.handler.output(), [6]);
+    }
+
+    #[test]
+    fn line_handler() {
+        let handler = run_lines(include_str!("../test_data/count_down.bf"));
+        assert!(handler.lines().is_empty());
+        assert_eq!(handler.partial(), "9 8 7 6 5 4 3 2 1 0 ");
+
+        let handler = run_lines(include_str!("../test_data/squares.bf"));
+        assert_eq!(handler.lines().len(), 101);
+        assert_eq!(handler.lines()[12], "144");
+        assert_eq!(handler.partial(), "");
+    }
+
+    struct PanicHandler;
+
+    impl Handler for PanicHandler {
+        fn write_char(&mut self, _c: u8) -> io::Result<()> {
+            panic!("write failed");
+        }
+    }
+
+    #[test]
+    fn run_catch_unwind() {
+        let exprs = parse("+.");
+
+        let mut vm = Interpreter::new(PanicHandler);
+        match vm
+            .run_catch_unwind(&exprs)
+            .as_ref()
+            .map_err(RuntimeError::kind)
+        {
+            Err(RuntimeErrorKind::InternalPanic(message)) => assert_eq!(message, "write failed"),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(vm.cells(), &[1]);
+        assert_eq!(
+            RuntimeErrorKind::InternalPanic(String::from("write failed")).to_string(),
+            "internal panic: write failed"
+        );
+    }
+
+    #[test]
+    fn concat() {
+        let exprs = Expr::concat(&[parse("+"), parse("."), Expr::PrintChar]);
+        assert_eq!(
+            exprs,
+            Expr::Block {
+                exprs: vec![Expr::Increment { num: 1 }, Expr::PrintChar, Expr::PrintChar]
+            }
+        );
+
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.run(&exprs).unwrap();
+        assert_eq!(vm.handler.out.as_str(), "\u{1}\u{1}");
+    }
+
+    #[test]
+    fn strict_balanced() {
+        let exprs = parse("+[>+]");
+
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.set_strict_balanced(true);
+        match vm.run(&exprs).as_ref().map_err(RuntimeError::kind) {
+            Err(RuntimeErrorKind::UnbalancedLoop { id: Some(0) }) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        let exprs = parse("++[>+<-]");
+
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.set_strict_balanced(true);
+        vm.run(&exprs).unwrap();
+        assert_eq!(vm.cells(), &[0, 2]);
+    }
+
+    #[test]
+    fn clone() {
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.run(&parse("+++.>++")).unwrap();
+
+        let mut fork = vm.clone();
+        fork.run(&parse("+.<[-]")).unwrap();
+
+        assert_eq!(vm.cells(), &[3, 2]);
+        assert_eq!(vm.current_cell_index(), 1);
+        assert_eq!(vm.handler.out.as_str(), "\u{3}");
+
+        assert_eq!(fork.cells(), &[0, 3]);
+        assert_eq!(fork.current_cell_index(), 0);
+        assert_eq!(fork.handler.out.as_str(), "\u{3}\u{3}");
+    }
+
+    #[test]
+    fn run_with_input() {
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.run_with_input(&parse(",[.,]"), b"echo").unwrap();
+        assert_eq!(vm.handler.out.as_str(), "echo");
+
+        vm.run_with_input(&parse(",.,.,."), b"hi").unwrap();
+        assert_eq!(vm.handler.out.as_str(), "echohi\u{0}");
+    }
+
+    #[derive(Default)]
+    struct LoopHandler {
+        iterations: Vec<(usize, u8)>,
+    }
+
+    impl Handler for LoopHandler {
+        fn loop_iteration(&mut self, pointer: usize, cell: u8) {
+            self.iterations.push((pointer, cell));
+        }
+    }
+
+    #[test]
+    fn loop_iteration() {
+        let mut vm = Interpreter::new(LoopHandler::default());
+        vm.run(&parse("+++[-]")).unwrap();
+        assert_eq!(vm.handler.iterations, [(0, 3), (0, 2), (0, 1)]);
+
+        let mut vm = Interpreter::new(LoopHandler::default());
+        vm.run(&parse("[-]>++[>+[-]<-]")).unwrap();
+        assert_eq!(vm.handler.iterations, [(1, 2), (2, 1), (1, 1), (2, 1)]);
+    }
+
+    #[derive(Default)]
+    struct LoopBoundaryHandler {
+        events: Vec<(bool, Option<usize>)>,
+    }
+
+    impl Handler for LoopBoundaryHandler {
+        fn on_loop_enter(&mut self, id: Option<usize>) {
+            self.events.push((true, id));
+        }
+
+        fn on_loop_exit(&mut self, id: Option<usize>) {
+            self.events.push((false, id));
+        }
+    }
+
+    #[test]
+    fn loop_enter_exit() {
+        let src = "[-]++[>+[-]<-]";
+        let expected = [
+            (true, Some(0)),
+            (false, Some(0)),
+            (true, Some(1)),
+            (true, Some(2)),
+            (false, Some(2)),
+            (true, Some(2)),
+            (false, Some(2)),
+            (false, Some(1)),
+        ];
+
+        let mut vm = Interpreter::new(LoopBoundaryHandler::default());
+        vm.run(&parse(src)).unwrap();
+        assert_eq!(vm.handler.events, expected);
+
+        let mut vm = Interpreter::new(LoopBoundaryHandler::default());
+        let program = Program::compile(&parse(src));
+        while vm.step(&program).unwrap() != StepStatus::Done {}
+        assert_eq!(vm.handler.events, expected);
+
+        let mut l = Lexer::new(src);
+        l.lex().unwrap();
+        let mut vm = Interpreter::new(LoopBoundaryHandler::default());
+        vm.run_tokens(&l.tokens).unwrap();
+        let without_ids: Vec<_> = expected.iter().map(|&(enter, _)| (enter, None)).collect();
+        assert_eq!(vm.handler.events, without_ids);
+    }
+
+    #[test]
+    fn tape_size() {
+        assert_eq!(estimate_tape_size(&parse(">>>+")), Some(4));
+        assert_eq!(estimate_tape_size(&parse("")), Some(1));
+        assert_eq!(estimate_tape_size(&parse(">>>><<+[>>+<<-]")), Some(5));
+        assert_eq!(estimate_tape_size(&parse("+[>+]")), None);
+        assert_eq!(estimate_tape_size(&parse("<")), None);
+
+        let exprs = parse(include_str!("../test_data/hello_world1.bf"));
+        assert_eq!(estimate_tape_size(&exprs), Some(5));
+
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.reserve_for(&exprs);
+        assert!(vm.tape().capacity() >= 5);
+        vm.run(&exprs).unwrap();
+        assert_eq!(vm.cells().len(), 5);
+    }
+
+    #[test]
+    fn run_tokens() {
+        let data = include_str!("../test_data/hello_world1.bf");
+        let mut l = Lexer::new(data);
+        l.lex().unwrap();
+
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.run_tokens(&l.tokens).unwrap();
+
+        let mut expected = Interpreter::new(TestHandler::new());
+        expected.run(&parse(data)).unwrap();
+
+        assert_eq!(vm.handler.out, expected.handler.out);
+        assert_eq!(vm.cells(), expected.cells());
+
+        // The handler is finalized like after `run`
+        let mut vm = Interpreter::new(RecordingHandler::new());
+        vm.run_tokens(&l.tokens).unwrap();
+        assert_eq!(vm.handler.cells(), expected.cells());
+        assert_eq!(vm.handler.pointer(), expected.current_cell_index());
+
+        let mut l = Lexer::new("+[>+]");
+        l.lex().unwrap();
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.set_strict_balanced(true);
+        assert!(matches!(
+            vm.run_tokens(&l.tokens)
+                .as_ref()
+                .map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::UnbalancedLoop { id: None })
+        ));
+
+        let mut l = Lexer::new("+]");
+        l.lex().unwrap();
+        assert!(Interpreter::new(TestHandler::new())
+            .run_tokens(&l.tokens)
+            .is_err());
+    }
+
+    #[derive(Default)]
+    struct OverflowHandler {
+        overflows: Vec<usize>,
+    }
+
+    impl Handler for OverflowHandler {
+        fn on_overflow(&mut self, index: usize) {
+            self.overflows.push(index);
+        }
+    }
+
+    #[test]
+    fn on_overflow() {
+        let mut vm = Interpreter::new(OverflowHandler::default());
+        vm.run(&parse(include_str!("../test_data/factorial.bf")))
+            .unwrap();
+        assert!(!vm.handler.overflows.is_empty());
+
+        let mut vm = Interpreter::new(OverflowHandler::default());
+        vm.run(&parse("-+>+++[-]>")).unwrap();
+        assert_eq!(vm.handler.overflows, [0, 0]);
+        assert_eq!(vm.cells(), &[0, 0]);
+
+        let mut vm = Interpreter::new(OverflowHandler::default());
+        vm.run(&Expr::Block {
+            exprs: vec![
+                Expr::Increment { num: 255 },
+                Expr::ShiftRight { num: 1 },
+                Expr::Increment { num: 257 },
+                Expr::ShiftRight { num: 1 },
+                Expr::Increment { num: 3 },
+                Expr::Decrement { num: 259 },
+            ],
+        })
+        .unwrap();
+        assert_eq!(vm.handler.overflows, [1, 2]);
+        assert_eq!(vm.cells(), &[255, 1, 0]);
+    }
+
+    #[test]
+    fn output_len() {
+        let mut vm = Interpreter::new(DefaultHandler);
+        vm.run(&parse(include_str!("../test_data/hello_world1.bf")))
+            .unwrap();
+        assert_eq!(vm.output_len(), "Hello World!\n".len());
+
+        vm.run(&Expr::PrintString {
+            value: "abc".into(),
+        })
+        .unwrap();
+        assert_eq!(vm.output_len(), 3);
+    }
+
+    #[test]
+    fn custom_tape() {
+        let exprs = Expr::Block {
+            exprs: vec![
+                Expr::Increment { num: 1 },
+                Expr::ShiftRight { num: 1_000_000 },
+                Expr::Increment { num: 2 },
+                Expr::PrintChar,
+                Expr::Loop {
+                    expr: Box::new(Expr::Block {
+                        exprs: vec![
+                            Expr::ShiftLeft { num: 1_000_000 },
+                            Expr::Increment { num: 1 },
+                            Expr::ShiftRight { num: 1_000_000 },
+                            Expr::Decrement { num: 1 },
+                        ],
+                    }),
+                    id: None,
+                },
+            ],
+        };
+
+        let mut vm = Interpreter::with_tape(TestHandler::new(), HashMapTape::new());
+        vm.run(&exprs).unwrap();
+
+        assert_eq!(vm.handler.out.as_str(), "\u{2}");
+        assert_eq!(vm.tape().stored(), 2);
+        assert_eq!(vm.tape().get(0), 3);
+        assert_eq!(vm.tape().len(), 1_000_001);
+    }
+
+    #[test]
+    fn fixed_tape() {
+        let data = parse(include_str!("../test_data/hello_world1.bf"));
+        let mut vm = Interpreter::with_tape(TestHandler::new(), FixedTape::new(10));
+        vm.run(&data).unwrap();
+        assert_eq!(vm.handler.out, "Hello World!\n");
+        assert_eq!(vm.tape().memory_usage(), 10);
+
+        let mut vm = Interpreter::with_tape(DefaultHandler, FixedTape::new(3));
+        vm.run(&parse(">>+")).unwrap();
+        assert!(matches!(
+            vm.run(&parse(">")).as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::TapeOverflow)
+        ));
+        assert_eq!(vm.current_cell_index(), 2);
+
+        vm.reset();
+        vm.tape_mut().set_fill_value(7);
+        vm.run(&parse(">+")).unwrap();
+        assert_eq!(vm.tape().as_slice(), Some(&[7, 8][..]));
+        assert_eq!(vm.tape().get(2), 7);
+    }
+
+    #[cfg(feature = "memmap2")]
+    #[test]
+    fn mmap_tape() {
+        // Only the pages that are written to take up memory
+        let mut vm =
+            Interpreter::with_tape(TestHandler::new(), MmapTape::anonymous(1 << 30).unwrap());
+        vm.run(&parse(include_str!("../test_data/hello_world1.bf")))
+            .unwrap();
+        assert_eq!(vm.handler.out, "Hello World!\n");
+        vm.tape_mut().set((1 << 30) - 1, 1);
+        assert_eq!(vm.tape().len(), 1 << 30);
+        vm.reset();
+        assert!(vm.tape().is_empty());
+
+        let path = std::env::temp_dir().join(format!("bf-mmap-{}", std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(4).unwrap();
+        let tape = unsafe { MmapTape::from_file(&file, 4).unwrap() };
+        let mut vm = Interpreter::with_tape(DefaultHandler, tape);
+        vm.run(&parse("+++>>++")).unwrap();
+        assert!(matches!(
+            vm.run(&parse(">>")).as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::TapeOverflow)
+        ));
+        vm.tape().flush().unwrap();
+        drop(vm);
+
+        assert_eq!(std::fs::read(&path).unwrap(), [3, 0, 2, 0]);
+        let tape = unsafe { MmapTape::from_file(&file, 4).unwrap() };
+        assert_eq!(tape.len(), 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sparse_matches_dense() {
+        let src = format!("{}+.", ">".repeat(1_000_000));
+        let exprs = parse(&src);
+
+        let mut sparse = Interpreter::sparse(TestHandler::new());
+        sparse.run(&exprs).unwrap();
+        assert_eq!(sparse.tape().stored(), 1);
+
+        let mut dense = Interpreter::new(TestHandler::new());
+        dense.run(&exprs).unwrap();
+
+        assert_eq!(sparse.handler.out, "\u{1}");
+        assert_eq!(sparse.handler.out, dense.handler.out);
+    }
+
+    #[derive(Default)]
+    struct FlushRecorder {
+        data: Vec<u8>,
+        // The length of `data` at each flush
+        flushes: Vec<usize>,
+    }
+
+    impl std::io::Write for FlushRecorder {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.flushes.push(self.data.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn line_flush_handler() {
+        let exprs = parse(include_str!("../test_data/squares.bf"));
+        let mut vm = Interpreter::new(LineFlushHandler::new(FlushRecorder::default()));
+        vm.run(&exprs).unwrap();
+
+        let recorder = vm.handler.into_inner();
+        let newlines: Vec<usize> = recorder
+            .data
+            .iter()
+            .enumerate()
+            .filter(|(_, &c)| c == b'\n')
+            .map(|(i, _)| i + 1)
+            .collect();
+
+        assert!(!newlines.is_empty());
+        assert_eq!(recorder.flushes, newlines);
+    }
+
+    struct ClosedPipe;
+
+    impl io::Write for ClosedPipe {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct FailingReader;
+
+    impl Handler for FailingReader {
+        fn read_char(&mut self) -> io::Result<u8> {
+            Err(io::ErrorKind::UnexpectedEof.into())
+        }
+    }
+
+    #[test]
+    fn io_error() {
+        let mut vm = Interpreter::new(LineFlushHandler::new(ClosedPipe));
+        match vm.run(&parse("+.+.")).as_ref().map_err(RuntimeError::kind) {
+            Err(RuntimeErrorKind::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::BrokenPipe),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(vm.cells(), [1]);
+
+        let mut vm = Interpreter::new(FailingReader);
+        match vm.run(&parse("+,+")).as_ref().map_err(RuntimeError::kind) {
+            Err(RuntimeErrorKind::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        vm.run_with_input(&parse(",."), b"a").unwrap();
+    }
+
+    #[test]
+    fn reader_writer_handlers() {
+        let mut vm = Interpreter::new(ReaderHandler::new(&b"ab"[..]));
+        vm.run(&parse(",>,>,")).unwrap();
+        assert_eq!(vm.cells(), b"ab\0");
+
+        let data = include_str!("../test_data/hello_world1.bf");
+        let mut vm = Interpreter::new(WriterHandler::new(Vec::new()));
+        vm.run(&parse(data)).unwrap();
+        assert_eq!(vm.handler.into_inner(), reference::run(data, b""));
+
+        // Reads from stdin aren't tested, but writes go to the test's captured stdout
+        let mut vm = Interpreter::new(StdioHandler::new());
+        vm.run(&parse(data)).unwrap();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn run_async() {
+        let program = Program::compile(&parse(",[.,]"));
+        let mut handler = AsyncIoHandler::new(&b"echo"[..], Vec::new());
+        let mut vm = Interpreter::new(DefaultHandler);
+        vm.run_async(&program, &mut handler).await.unwrap();
+        assert_eq!(handler.writer(), b"echo");
+        assert_eq!(vm.output_len(), 4);
+
+        let data = include_str!("../test_data/hello_world1.bf");
+        let program = Program::compile(&parse(data));
+        let mut handler = AsyncIoHandler::new(&b""[..], Vec::new());
+        vm.run_async(&program, &mut handler).await.unwrap();
+        assert_eq!(handler.into_inner().1, reference::run(data, b""));
+    }
+
+    #[test]
+    fn limits() {
+        let data = include_str!("../test_data/hello_world1.bf");
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.set_limits(ExecutionLimits {
+            max_output: Some(5),
+            ..ExecutionLimits::default()
+        });
+        assert!(matches!(
+            vm.run(&parse(data)).as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::OutputLimitExceeded)
+        ));
+        assert_eq!(vm.handler.out, "Hello");
+
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.set_limits(ExecutionLimits {
+            max_cells: Some(2),
+            max_steps: Some(1000),
+            max_output: Some(5),
+            max_loop_iterations: None,
+        });
+        assert!(matches!(
+            vm.run(&parse(">>+")).as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::TapeOverflow)
+        ));
+        assert!(matches!(
+            vm.run(&parse("+[]")).as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::FuelExhausted)
+        ));
+
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.set_limits(ExecutionLimits {
+            max_output: Some(13),
+            ..ExecutionLimits::default()
+        });
+        vm.run(&parse(data)).unwrap();
+    }
+
+    #[test]
+    fn output_bomb() {
+        let bomb = parse("+[.]");
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.set_max_output(Some(1000));
+        assert!(matches!(
+            vm.run(&bomb).as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::OutputLimitExceeded)
+        ));
+        assert_eq!(vm.handler.out.len(), 1000);
+        assert_eq!(vm.output_len(), 1000);
+
+        let mut l = Lexer::new("+[.]");
+        l.lex().unwrap();
+        vm.handler.out.clear();
+        assert!(matches!(
+            vm.run_tokens(&l.tokens)
+                .as_ref()
+                .map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::OutputLimitExceeded)
+        ));
+        assert_eq!(vm.handler.out.len(), 1000);
+
+        let program = Program::compile(&bomb);
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.set_max_output(Some(10));
+        let result = loop {
+            match vm.step(&program) {
+                Ok(StepStatus::Running) => {}
+                result => break result,
+            }
+        };
+        assert!(matches!(
+            result.as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::OutputLimitExceeded)
+        ));
+        assert_eq!(vm.handler.out.len(), 10);
+
+        let mut vm = Interpreter::new(DefaultHandler);
+        vm.set_max_output(Some(10));
+        let events: Vec<_> = vm.events(&program).collect();
+        // Entering the loop, 10 bytes of output and the error
+        assert_eq!(events.len(), 12);
+        assert!(matches!(
+            events
+                .last()
+                .map(|event| event.as_ref().map_err(RuntimeError::kind)),
+            Some(Err(RuntimeErrorKind::OutputLimitExceeded))
+        ));
+
+        vm.set_max_output(None);
+        vm.reset();
+        assert!(vm.events(&program).take(10_000).all(|event| event.is_ok()));
+    }
+
+    #[test]
+    fn loop_limit() {
+        let mut vm = Interpreter::new(DefaultHandler);
+        vm.set_max_loop_iterations(Some(3));
+        vm.run(&parse("+++[-]")).unwrap();
+        // The inner loop runs 6 times in total, but only 3 each time it's reached
+        vm.run(&parse("++[>+++[-]<-]")).unwrap();
+        assert!(matches!(
+            vm.run(&parse("+[>++++[-]<]"))
+                .as_ref()
+                .map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::LoopLimitExceeded { id: Some(1) })
+        ));
+
+        let mut vm = Interpreter::new(DefaultHandler);
+        vm.set_limits(ExecutionLimits {
+            max_loop_iterations: Some(100),
+            ..ExecutionLimits::default()
+        });
+        let program = Program::compile(&parse("+[]"));
+        let result = loop {
+            match vm.step(&program) {
+                Ok(StepStatus::Running) => {}
+                result => break result,
+            }
+        };
+        assert!(matches!(
+            result.as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::LoopLimitExceeded { id: Some(0) })
+        ));
+
+        let mut l = Lexer::new("+[]");
+        l.lex().unwrap();
+        let mut vm = Interpreter::new(DefaultHandler);
+        vm.set_max_loop_iterations(Some(100));
+        assert!(matches!(
+            vm.run_tokens(&l.tokens)
+                .as_ref()
+                .map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::LoopLimitExceeded { id: None })
+        ));
+    }
+
+    #[test]
+    fn run_with_timeout() {
+        let mut vm = Interpreter::new(DefaultHandler);
+        assert!(matches!(
+            vm.run_with_timeout(&parse("+[]"), Duration::from_millis(20))
+                .as_ref()
+                .map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::Timeout)
+        ));
+
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.run_with_timeout(
+            &parse(include_str!("../test_data/hello_world1.bf")),
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        assert_eq!(vm.handler.out, "Hello World!\n");
+
+        // The deadline only applies to that run
+        assert!(matches!(
+            vm.run_with_fuel(&parse("+[]"), 100_000)
+                .as_ref()
+                .map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::FuelExhausted)
+        ));
+    }
+
+    #[test]
+    fn run_with_stats() {
+        let mut vm = Interpreter::new(TestHandler::new());
+        let stats = vm.run_with_stats(&parse(",>+++[->++<]>.")).unwrap();
+        assert_eq!(stats.exprs["ReadChar"], 1);
+        assert_eq!(stats.exprs["Loop"], 1);
+        assert_eq!(stats.exprs["Decrement"], 3);
+        assert_eq!(stats.exprs["ShiftRight"], 5);
+        assert_eq!(stats.loop_iterations, 3);
+        assert_eq!(stats.max_pointer, 2);
+        assert_eq!(stats.cells_touched, 3);
+        assert_eq!(stats.bytes_read, 1);
+        assert_eq!(stats.bytes_written, 1);
+        assert_eq!(vm.handler.out, "\u{6}");
+
+        // Optimizing should make the same program do less work
+        let data = include_str!("../test_data/hello_world1.bf");
+        let mut o = Optimizer::new(parse(data));
+        o.add_pass(SpecExecOptimizer::default());
+        o.optimize();
+        let before = Interpreter::new(DefaultHandler)
+            .run_with_stats(&parse(data))
+            .unwrap();
+        let after = Interpreter::new(DefaultHandler)
+            .run_with_stats(&o.expr)
+            .unwrap();
+        assert_eq!(before.bytes_written, after.bytes_written);
+        assert!(after.exprs.values().sum::<u64>() < before.exprs.values().sum::<u64>());
+    }
+
+    #[test]
+    fn profiling() {
+        let expr = parse("++[>+++[>++++[-]<-]<-]");
+        let mut vm = Interpreter::new(DefaultHandler);
+        vm.run(&expr).unwrap();
+        assert!(vm.loop_profile().is_none());
+
+        vm.set_profiling(true);
+        vm.run(&expr).unwrap();
+        let profile = vm.loop_profile().unwrap().clone();
+        assert_eq!(
+            profile.ranked(),
+            [(Some(2), 24), (Some(1), 6), (Some(0), 2)]
+        );
+        assert_eq!(profile.iterations(Some(1)), 6);
+        assert_eq!(profile.iterations(Some(7)), 0);
+        assert_eq!(profile.total(), 32);
+
+        // Stepping through the compiled program counts the same loops
+        let program = Program::compile(&expr);
+        let mut vm = Interpreter::new(DefaultHandler);
+        vm.set_profiling(true);
+        while vm.step(&program).unwrap() != StepStatus::Done {}
+        assert_eq!(vm.loop_profile(), Some(&profile));
+    }
+
+    #[test]
+    fn cancel_token() {
+        let token = CancelToken::new();
+        let mut vm = Interpreter::new(DefaultHandler);
+        vm.set_cancel_token(Some(token.clone()));
+
+        let handle = std::thread::spawn(move || {
+            vm.run(&parse("+[]"))
+                .map(|_| ())
+                .map_err(|e| format!("{:?}", e.kind()))
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        token.cancel();
+        assert_eq!(handle.join().unwrap(), Err(String::from("Cancelled")));
+
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.set_cancel_token(Some(token.clone()));
+        assert!(matches!(
+            vm.run(&parse("+.")).as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::Cancelled)
+        ));
+        token.reset();
+        vm.run(&parse("+.")).unwrap();
+        assert_eq!(vm.handler.out, "\u{1}");
+    }
+
+    #[test]
+    fn record_replay_input() {
+        let data = ",[.,]";
+        let mut vm = Interpreter::new(RecordInputHandler::new(ReaderHandler::new(&b"hi"[..])));
+        vm.run(&parse(data)).unwrap();
+        let (_, recorded) = vm.handler.into_parts();
+        assert_eq!(recorded, b"hi\0");
+
+        let mut vm = Interpreter::new(ReplayInputHandler::new(TestHandler::new(), &recorded));
+        vm.run(&parse(data)).unwrap();
+        assert_eq!(vm.handler.remaining(), 0);
+        assert_eq!(vm.handler.inner().out, "hi");
+
+        let mut vm = Interpreter::new(ReplayInputHandler::new(DefaultHandler, b"hi"));
+        match vm.run(&parse(data)).as_ref().map_err(RuntimeError::kind) {
+            Err(RuntimeErrorKind::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn tee_and_logging_handlers() {
+        let handler = TeeHandler(SliceInputHandler::new(b"hi"), TestHandler::new());
+        let mut vm = Interpreter::new(handler);
+        vm.run(&parse(",[.,]")).unwrap();
+        assert_eq!(vm.handler.0.output(), b"hi");
+        assert_eq!(vm.handler.1.out, "hi");
+
+        let handler = LoggingHandler::new(SliceInputHandler::new(b"a"), Vec::new());
+        let mut vm = Interpreter::new(handler);
+        vm.run(&parse(",.,++++++++++.")).unwrap();
+        let (inner, log) = vm.handler.into_parts();
+        assert_eq!(inner.output(), b"a\n");
+        assert_eq!(
+            String::from_utf8(log).unwrap(),
+            "read 0: a\nwrite 0: a\nread 1: \\x00\nwrite 1: \\n\n"
+        );
+    }
+
+    #[test]
+    fn channel_handler() {
+        let (handler, input, output) = ChannelHandler::pair();
+        let worker = std::thread::spawn(move || {
+            let mut vm = Interpreter::new(handler);
+            vm.run(&parse(",[+.,]"))
+        });
+
+        input.send(b'H').unwrap();
+        assert_eq!(output.recv().unwrap(), b'I');
+        input.send(b'A').unwrap();
+        input.send(b'L').unwrap();
+        drop(input);
+        worker.join().unwrap().unwrap();
+        assert_eq!(output.iter().collect::<Vec<_>>(), b"BM");
+
+        let (handler, input, output) = ChannelHandler::pair();
+        let program = Program::compile(&parse(",."));
+        let mut vm = Interpreter::new(handler);
+        assert_eq!(vm.step(&program).unwrap(), StepStatus::Blocked);
+        input.send(b'x').unwrap();
+        while vm.step(&program).unwrap() != StepStatus::Done {}
+        assert_eq!(output.try_recv(), Ok(b'x'));
+
+        drop(output);
+        assert!(matches!(
+            vm.run(&parse(".")).as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe
+        ));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn tcp_handler() {
+        use std::{
+            io::{
+                Read,
+                Write,
+            },
+            net::{
+                Shutdown,
+                TcpListener,
+                TcpStream,
+            },
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut vm = Interpreter::new(TcpHandler::new(stream).unwrap());
+            vm.run(&parse(",[+.,]"))
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"HAL").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).unwrap();
+        assert_eq!(output, b"IBM");
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn deep_nesting() {
+        // +[>+[>+[ ... [-] ... ]]], built directly since the parser recurses
+        let depth = 200_000;
+        let mut expr = Expr::Loop {
+            expr: Box::new(Expr::Decrement { num: 1 }),
+            id: None,
+        };
+        for _ in 0..depth {
+            expr = Expr::Loop {
+                expr: Box::new(Expr::Block {
+                    exprs: vec![
+                        Expr::ShiftRight { num: 1 },
+                        Expr::Increment { num: 1 },
+                        expr,
+                    ],
+                }),
+                id: None,
+            };
+        }
+        let expr = Expr::Block {
+            exprs: vec![Expr::Increment { num: 1 }, expr],
+        };
+
+        let mut vm = Interpreter::new(DefaultHandler);
+        let stats = vm.run_with_stats(&expr).unwrap();
+        assert_eq!(stats.exprs["Loop"], depth + 1);
+        assert_eq!(vm.current_cell_index(), depth as usize);
+        assert_eq!(vm.cells()[depth as usize], 0);
+
+        // Dropping is recursive too
+        std::mem::forget(expr);
+    }
+
+    #[test]
+    fn reset() {
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.set_profiling(true);
+        vm.run(&parse("++[>+++<-]>.")).unwrap();
+        assert_eq!(vm.cells(), [0, 6]);
+
+        vm.run_program(&parse("+.")).unwrap();
+        assert_eq!(vm.cells(), [1]);
+        assert_eq!(vm.current_cell_index(), 0);
+        assert_eq!(vm.loop_profile().unwrap().total(), 0);
+        assert_eq!(vm.handler.out, "\u{6}\u{1}");
+
+        let mut vm = Interpreter::bidirectional(DefaultHandler);
+        vm.run(&parse("<+>>+")).unwrap();
+        vm.reset();
+        assert!(vm.tape().cells().is_empty());
+        assert!(vm.tape().negative_cells().is_empty());
+    }
+
+    #[test]
+    fn run_collect() {
+        let data = include_str!("../test_data/hello_world1.bf");
+        assert_eq!(
+            Interpreter::run_collect(&parse(data), b"").unwrap(),
+            b"Hello World!\n"
+        );
+        assert_eq!(
+            Interpreter::run_collect(&parse(",[.,]"), b"echo").unwrap(),
+            b"echo"
+        );
+    }
+
+    #[test]
+    fn fork() {
+        let program = Program::compile(&parse(",[>+++<-]>."));
+        let mut vm = Interpreter::new(SliceInputHandler::new(b"\x02"));
+        while vm.cells().get(1) != Some(&3) {
+            vm.step(&program).unwrap();
+        }
+
+        let mut fork = vm.fork(RecordingHandler::new());
+        let mut clone = vm.clone();
+        while vm.step(&program).unwrap() != StepStatus::Done {}
+        while clone.step(&program).unwrap() != StepStatus::Done {}
+        while fork.step(&program).unwrap() != StepStatus::Done {}
+
+        assert_eq!(vm.handler.output(), [6]);
+        assert_eq!(clone.handler.output(), [6]);
+        assert_eq!(fork.handler.output(), [6]);
+        assert_eq!(fork.cells(), vm.cells());
+    }
+
+    #[test]
+    fn boxed_handler() {
+        let handlers: Vec<Box<dyn Handler>> = vec![
+            Box::new(DefaultHandler),
+            Box::new(SliceInputHandler::new(b"hi")),
+        ];
+        for handler in handlers {
+            let mut vm: BoxedInterpreter = Interpreter::new(handler);
+            vm.run(&parse(",.,.")).unwrap();
+        }
+
+        let mut vm = BoxedInterpreter::boxed(SliceInputHandler::new(b"hi"));
+        vm.run(&parse(",[.,]")).unwrap();
+        assert_eq!(vm.cells(), [0]);
+    }
+
+    #[test]
+    fn events() {
+        let program = Program::compile(&parse(",[.,]+[-]"));
+        let mut vm = Interpreter::new(DefaultHandler);
+        let mut events = vm.events(&program);
+        assert_eq!(events.next().unwrap().unwrap(), Event::NeedsInput);
+        assert_eq!(events.next().unwrap().unwrap(), Event::NeedsInput);
+
+        events.push_input(b"hi\0");
+        let rest: Vec<_> = events.map(Result::unwrap).collect();
+        assert_eq!(
+            rest,
+            [
+                Event::LoopEntered { id: Some(0) },
+                Event::Output(b'h'),
+                Event::Output(b'i'),
+                Event::LoopEntered { id: Some(1) },
+                Event::Halted,
+            ]
+        );
+
+        let mut vm = Interpreter::new(DefaultHandler);
+        vm.set_limits(ExecutionLimits {
+            max_output: Some(1),
+            ..ExecutionLimits::default()
+        });
+        let program = Program::compile(&parse("+.."));
+        let events: Vec<_> = vm.events(&program).collect();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            events[1].as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::OutputLimitExceeded)
+        ));
+    }
+
+    /// Records each call to `write_char` and `write_str` separately.
+    #[derive(Default)]
+    struct WriteCallHandler {
+        calls: Vec<Vec<u8>>,
+    }
+
+    impl Handler for WriteCallHandler {
+        fn write_char(&mut self, c: u8) -> io::Result<()> {
+            self.calls.push(vec![c]);
+            Ok(())
+        }
+
+        fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
+            self.calls.push(s.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_str() {
+        let expr = Expr::Block {
+            exprs: vec![
+                Expr::PrintString {
+                    value: "abc".into(),
+                },
+                Expr::Increment { num: 65 },
+                Expr::PrintChar,
+            ],
+        };
+        let mut vm = Interpreter::new(WriteCallHandler::default());
+        vm.run(&expr).unwrap();
+        assert_eq!(vm.handler.calls, [&b"abc"[..], b"A"]);
+
+        let mut vm = Interpreter::new(WriteCallHandler::default());
+        vm.set_limits(ExecutionLimits {
+            max_output: Some(2),
+            ..ExecutionLimits::default()
+        });
+        assert!(matches!(
+            vm.run(&expr).as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::OutputLimitExceeded)
+        ));
+        assert_eq!(vm.handler.calls, [b"ab"]);
+
+        // Stepping writes each op's output at once too
+        let program = Program::compile(&expr);
+        let mut vm = Interpreter::new(WriteCallHandler::default());
+        while vm.step(&program).unwrap() != StepStatus::Done {}
+        assert_eq!(vm.handler.calls, [&b"abc"[..], b"A"]);
+    }
+
+    #[test]
+    fn buffered_handler() {
+        let data = include_str!("../test_data/hello_world1.bf");
+        let mut vm = Interpreter::new(BufferedHandler::new(WriteCallHandler::default()));
+        vm.run(&parse(data)).unwrap();
+        assert_eq!(vm.handler.inner().calls, [b"Hello World!\n"]);
+
+        // Output is passed on before reads and when the buffer is full
+        let mut vm = Interpreter::new(BufferedHandler::with_capacity(
+            WriteCallHandler::default(),
+            2,
+        ));
+        vm.run(&parse("+++...,...")).unwrap();
+        let handler = vm.handler.into_inner().unwrap();
+        assert_eq!(
+            handler.calls,
+            [&b"\x03\x03"[..], b"\x03", b"\x00\x00", b"\x00"]
+        );
+    }
+
+    #[test]
+    fn utf8_output_handler() {
+        let expr = Expr::PrintString {
+            value: "héllo ✓".into(),
+        };
+        let mut vm = Interpreter::new(Utf8OutputHandler::new());
+        vm.run(&expr).unwrap();
+        assert_eq!(vm.handler.output(), "héllo ✓");
+
+        // 0xff is never valid, and 0xc3 is cut off by the end of the run
+        let cut_off = format!("{}.", "-".repeat(61));
+        let mut vm = Interpreter::new(Utf8OutputHandler::new());
+        vm.run(&parse(&format!("-.+.{}", cut_off))).unwrap();
+        assert_eq!(vm.handler.into_string(), "\u{fffd}\0\u{fffd}");
+
+        let mut vm = Interpreter::new(Utf8OutputHandler::strict());
+        vm.run(&parse(&cut_off)).unwrap();
+        assert_eq!(vm.handler.output(), "");
+        assert_eq!(vm.handler.incomplete(), [0xc3]);
+        match vm.run(&parse("[-]-.")).as_ref().map_err(RuntimeError::kind) {
+            Err(RuntimeErrorKind::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn slice_input_handler() {
+        // Echo until a read produces 255
+        let expr = parse(",+[-.,+]");
+        let mut vm = Interpreter::new(SliceInputHandler::new(b"abc"));
+        vm.handler.set_eof_policy(EofPolicy::Max);
+        vm.run(&expr).unwrap();
+        assert_eq!(vm.handler.output(), b"abc");
+        assert!(vm.handler.remaining().is_empty());
+
+        let mut vm = Interpreter::new(SliceInputHandler::new(b"ab\0c"));
+        vm.run(&parse(",[.,]")).unwrap();
+        assert_eq!(vm.handler.output(), b"ab");
+        assert_eq!(vm.handler.remaining(), b"c");
+
+        let mut vm = Interpreter::new(SliceInputHandler::new(b"ab"));
+        vm.handler.set_eof_policy(EofPolicy::Error);
+        match vm.run(&expr).as_ref().map_err(RuntimeError::kind) {
+            Err(RuntimeErrorKind::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(vm.handler.output(), b"ab");
+    }
+
+    #[test]
+    fn coverage() {
+        // Both loops are reached, but the bodies of the first and the inner one never run
+        let expr = parse("[>+<-]+[-[>]]");
+        let mut vm = Interpreter::new(DefaultHandler);
+        assert!(vm.coverage(&expr).is_none());
+
+        vm.set_coverage(true);
+        vm.run(&expr).unwrap();
+        let coverage = vm.coverage(&expr).unwrap();
+        let nodes = expr.nodes();
+        assert_eq!(coverage.len(), nodes.len());
+        let uncovered: Vec<_> = coverage
+            .uncovered()
+            .into_iter()
+            .map(|id| nodes[id].name())
+            .collect();
+        assert_eq!(
+            uncovered,
+            [
+                "Block",
+                "ShiftRight",
+                "Increment",
+                "ShiftLeft",
+                "Decrement",
+                "Block",
+                "ShiftRight"
+            ]
+        );
+        assert!(coverage.is_covered(0));
+        assert_eq!(coverage.covered_count(), nodes.len() - 7);
+
+        // Nodes are identified by position, so a copy or an equal tree has the same coverage
+        assert_eq!(vm.coverage(&expr.clone()), Some(coverage.clone()));
+        assert_eq!(vm.coverage(&parse("[>+<-]+[-[>]]")), Some(coverage));
+
+        // The nodes of the native divmod's fallback loop aren't counted
+        let expr = Expr::concat(&[parse(">>>>+>>+<<"), Expr::DivMod, parse("[>]")]);
+        vm.set_coverage(true);
+        vm.run(&expr).unwrap();
+        let nodes = expr.nodes();
+        let uncovered: Vec<_> = vm
+            .coverage(&expr)
+            .unwrap()
+            .uncovered()
+            .into_iter()
+            .map(|id| nodes[id].name())
+            .collect();
+        assert_eq!(uncovered, ["Block", "ShiftRight"]);
+    }
+
+    #[test]
+    fn pointer_underflow() {
+        let mut vm = Interpreter::new(DefaultHandler);
+        assert!(matches!(
+            vm.run(&parse(">>+<<<"))
+                .as_ref()
+                .map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::PointerUnderflow { at: 2 })
+        ));
+        assert_eq!(vm.current_cell_index(), 2);
+
+        let tokens = {
+            let mut l = Lexer::new(">[<<]");
+            l.lex().unwrap();
+            l.tokens
+        };
+        let mut vm = Interpreter::new(DefaultHandler);
+        vm.tape_mut().set(1, 1);
+        assert!(matches!(
+            vm.run_tokens(&tokens).as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::PointerUnderflow { at: 1 })
+        ));
+
+        let program = Program::compile(&parse("<"));
+        let mut vm = Interpreter::new(DefaultHandler);
+        assert!(matches!(
+            vm.step(&program).as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::PointerUnderflow { at: 0 })
+        ));
+        assert!(Interpreter::run_collect(&parse("<"), b"").is_err());
+    }
+
+    #[test]
+    fn error_context() {
+        let mut vm = Interpreter::new(DefaultHandler);
+        vm.run(&parse("+")).unwrap();
+        assert!(vm.error_context().is_none());
+
+        assert!(vm.run(&parse(">+++<<")).is_err());
+        assert_eq!(
+            vm.error_context(),
+            Some(&ErrorContext {
+                expr: Some("ShiftLeft"),
+                position: None,
+                pointer: 1,
+                cell: 3,
+            })
+        );
+        vm.reset();
+        assert!(vm.error_context().is_none());
+
+        let program = Program::compile(&parse("+++<"));
+        let result = loop {
+            match vm.step(&program) {
+                Ok(StepStatus::Running) => {}
+                result => break result,
+            }
+        };
+        assert!(result.is_err());
+        assert_eq!(
+            vm.error_context(),
+            Some(&ErrorContext {
+                expr: None,
+                position: Some(1),
+                pointer: 0,
+                cell: 3,
+            })
+        );
+
+        let tokens = |src| {
+            let mut l = Lexer::with_offsets(src);
+            l.lex().unwrap();
+            l.tokens
+        };
+        let mut vm = Interpreter::new(DefaultHandler);
+        assert!(vm.run_tokens(&tokens("+<")).is_err());
+        assert_eq!(vm.error_context().unwrap().position, Some(1));
+        assert!(matches!(
+            vm.run_tokens(&tokens("+]"))
+                .as_ref()
+                .map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::UnmatchedBracket {
+                token: 1,
+                offset: Some(1),
+            })
+        ));
+        assert!(matches!(
+            vm.run_tokens(&tokens("[[]"))
+                .as_ref()
+                .map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::UnmatchedBracket {
+                token: 0,
+                offset: Some(0),
+            })
+        ));
+    }
+
+    #[test]
+    fn error_display() {
+        use std::error::Error;
+
+        assert_eq!(
+            RuntimeErrorKind::PointerUnderflow { at: 2 }.to_string(),
+            "the pointer moved left of cell 0 from cell 2"
+        );
+        assert_eq!(
+            RuntimeErrorKind::UnmatchedBracket {
+                token: 1,
+                offset: Some(2),
+            }
+            .to_string(),
+            "unmatched bracket at token 1 (byte 2)"
+        );
+        assert!(RuntimeError::from(RuntimeErrorKind::FuelExhausted)
+            .source()
+            .is_none());
+
+        let e = RuntimeError::from(RuntimeErrorKind::Io(io::Error::other("broken")));
+        assert_eq!(e.to_string(), "I/O error: broken");
+        assert!(e.source().is_some());
+
+        // Errors from a run say where it failed
+        let e = Interpreter::new(DefaultHandler)
+            .run(&parse("+++<"))
+            .unwrap_err();
+        assert!(matches!(
+            e.kind(),
+            RuntimeErrorKind::PointerUnderflow { at: 0 }
+        ));
+        assert_eq!(
+            e.context(),
+            Some(&ErrorContext {
+                expr: Some("ShiftLeft"),
+                position: None,
+                pointer: 0,
+                cell: 3,
+            })
+        );
+        assert_eq!(
+            e.to_string(),
+            "the pointer moved left of cell 0 from cell 0 in ShiftLeft with the pointer at cell 0 holding 3"
+        );
+    }
+
+    #[test]
+    fn with_memory() {
+        // Add the cell at the pointer to the one after it
+        let add = parse("[->+<]>");
+        let mut vm = Interpreter::with_memory(DefaultHandler, vec![9, 3, 4], 1);
+        vm.run(&add).unwrap();
+        assert_eq!(vm.cells(), [9, 0, 7]);
+        assert_eq!(vm.current_cell_index(), 2);
+
+        let mut vm = Interpreter::with_memory(TestHandler::new(), b"hi".to_vec(), 0);
+        vm.run(&parse(".>.>.")).unwrap();
+        assert_eq!(vm.handler.out, "hi\0");
+    }
+
+    /// Sets cell 1 to the number of ticks so far.
+    #[derive(Default)]
+    struct TickHandler {
+        ticks: u64,
+        pointers: Vec<usize>,
+    }
+
+    impl Handler for TickHandler {
+        fn on_tick(&mut self, tape: &mut TapeView) {
+            self.ticks += 1;
+            self.pointers.push(tape.pointer());
+            tape.set(1, self.ticks);
+        }
+    }
+
+    #[test]
+    fn on_tick() {
+        // Spin until cell 1 is set from outside
+        let expr = parse("+[>[<->[-]]<]");
+        let mut vm = Interpreter::new(TickHandler::default());
+        vm.set_tick_interval(Some(10));
+        vm.run_with_fuel(&expr, 10_000).unwrap();
+        assert!(vm.handler.ticks >= 1);
+        assert_eq!(vm.handler.pointers.len() as u64, vm.handler.ticks);
+
+        // Before the 3rd, 6th and 9th step
+        let mut vm = Interpreter::new(TickHandler::default());
+        vm.set_tick_interval(Some(3));
+        vm.run(&parse("+>+>+>+>+")).unwrap();
+        assert_eq!(vm.handler.pointers, [1, 2, 4]);
+        assert_eq!(vm.cells(), [1, 3, 1, 1, 1]);
+
+        let mut vm = Interpreter::new(TickHandler::default());
+        vm.run(&parse("+>+>+>+>+")).unwrap();
+        assert_eq!(vm.handler.ticks, 0);
+        // Wrapping handlers pass ticks on
+        let mut vm = Interpreter::new(BufferedHandler::new(TickHandler::default()));
+        vm.set_tick_interval(Some(3));
+        vm.run(&parse("+>+>+>+>+")).unwrap();
+        assert_eq!(vm.cells(), [1, 3, 1, 1, 1]);
+
+        let handler = RecordInputHandler::new(TickHandler::default());
+        let mut vm = Interpreter::new(ReplayInputHandler::new(handler, b""));
+        vm.set_tick_interval(Some(3));
+        vm.run(&parse("+>+>+>+>+")).unwrap();
+        assert_eq!(vm.handler.into_inner().into_parts().0.pointers, [1, 2, 4]);
+    }
+
+    #[test]
+    fn fuel() {
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.set_fuel(Some(100));
+        assert!(matches!(
+            vm.run(&parse("+[]")).as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::FuelExhausted)
+        ));
+        assert_eq!(vm.fuel(), Some(0));
+
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.set_fuel(Some(100));
+        vm.run(&parse("++[-].")).unwrap();
+        assert_eq!(vm.fuel(), Some(100 - 7));
+
+        let mut l = Lexer::new("+[]");
+        l.lex().unwrap();
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.set_fuel(Some(10));
+        assert!(matches!(
+            vm.run_tokens(&l.tokens)
+                .as_ref()
+                .map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::FuelExhausted)
+        ));
+    }
+
+    #[test]
+    fn shared_tape() {
+        let tape = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut writer = Interpreter::with_shared_tape(TestHandler::new(), tape.clone());
+        let mut reader = Interpreter::with_shared_tape(TestHandler::new(), tape.clone());
+
+        writer.run(&parse(">++++++++[<++++++++>-]<+")).unwrap();
+        reader.run(&parse(".")).unwrap();
+
+        assert_eq!(reader.handler.out, "A");
+        assert_eq!(*tape.borrow(), [65, 0]);
+    }
+
+    #[test]
+    fn fill_value() {
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.set_fill_value(255);
+        vm.run(&parse(">.>>+.")).unwrap();
+
+        assert_eq!(vm.handler.out, "\u{ff}\u{0}");
+        assert_eq!(vm.cells(), [255, 255, 255, 0]);
+
+        let mut vm = Interpreter::sparse(TestHandler::new());
+        vm.set_fill_value(7);
+        vm.run(&parse(">>>>>>.")).unwrap();
+        assert_eq!(vm.handler.out, "\u{7}");
+    }
+
+    #[test]
+    fn recording_handler() {
+        let mut vm = Interpreter::new(RecordingHandler::new());
+        vm.run(&parse("+++.")).unwrap();
+        assert_eq!(vm.handler.output(), [3]);
+        assert_eq!(vm.handler.cells(), [3]);
+        assert_eq!(vm.handler.pointer(), 0);
+
+        let mut vm = Interpreter::sparse(RecordingHandler::new());
+        vm.run(&parse(">>+>+[-]<.")).unwrap();
+        assert_eq!(vm.handler.output(), [1]);
+        assert_eq!(vm.handler.cells(), [0, 0, 1, 0]);
+        assert_eq!(vm.handler.pointer(), 2);
+    }
+
+    #[test]
+    fn memory_limit() {
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.set_memory_limit(Some(1000));
+        assert!(matches!(
+            vm.run(&parse("+[>+]")).as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::MemoryLimitExceeded)
+        ));
+        assert_eq!(vm.cells().len(), 1000);
+        assert!(vm.tape().capacity() < 2000);
+
+        let mut vm = Interpreter::new(TestHandler::new());
+        vm.set_memory_limit(Some(1000));
+        vm.run(&parse(">>>>>>>>>+.")).unwrap();
+        vm.set_memory_limit(Some(8));
+        assert!(vm.run(&parse(">>>>>>>>>>+")).is_err());
+        vm.set_memory_limit(None);
+        vm.run(&parse(">>>>>>>>>>+")).unwrap();
+
+        let mut vm = Interpreter::sparse(TestHandler::new());
+        vm.set_memory_limit(Some(100));
+        vm.run(&parse(&format!("{}+", ">".repeat(1_000_000))))
+            .unwrap();
+        assert!(matches!(
+            vm.run(&parse("+[>+]")).as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::MemoryLimitExceeded)
+        ));
+        assert!(vm.tape().memory_usage() <= 100);
     }
 
     #[test]
@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum TokenData {
     ShiftLeft(usize),
     ShiftRight(usize),
@@ -8,13 +8,17 @@ pub enum TokenData {
     EndLoop,
     Read,
     Print,
+    /// A `#`, if enabled with `Lexer::set_breakpoints`.
+    Breakpoint,
 
     Other(String),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Token {
     pub data: TokenData,
+    /// Byte offset of the token in the source, if the lexer was created with `Lexer::with_offsets`.
+    pub offset: Option<usize>,
 }
 
 fn is_bf_char(c: char) -> bool {
@@ -24,11 +28,21 @@ fn is_bf_char(c: char) -> bool {
 #[derive(Debug)]
 pub struct LexerError;
 
+impl std::fmt::Display for LexerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed to lex the program")
+    }
+}
+
+impl std::error::Error for LexerError {}
+
 pub struct Lexer<'a> {
     pub tokens: Vec<Token>,
 
     iter: std::iter::Peekable<std::str::CharIndices<'a>>,
     data: &'a str,
+    record_offsets: bool,
+    breakpoints: bool,
 }
 
 impl<'a> Lexer<'a> {
@@ -37,11 +51,32 @@ impl<'a> Lexer<'a> {
             tokens: Vec::new(),
             iter: data.char_indices().peekable(),
             data,
+            record_offsets: false,
+            breakpoints: false,
         }
     }
 
-    fn push_token(&mut self, data: TokenData) {
-        self.tokens.push(Token { data });
+    /// Create a lexer that records the starting byte offset of each token.
+    pub fn with_offsets(data: &'a str) -> Self {
+        Self {
+            record_offsets: true,
+            ..Self::new(data)
+        }
+    }
+
+    /// Lex `#` as `TokenData::Breakpoint` instead of as part of a comment.
+    pub fn set_breakpoints(&mut self, breakpoints: bool) {
+        self.breakpoints = breakpoints;
+    }
+
+    fn push_token(&mut self, data: TokenData, offset: usize) {
+        let offset = if self.record_offsets {
+            Some(offset)
+        } else {
+            None
+        };
+
+        self.tokens.push(Token { data, offset });
     }
 
     fn count_char(&mut self, c: char) -> usize {
@@ -55,53 +90,60 @@ impl<'a> Lexer<'a> {
     }
 
     pub fn lex(&mut self) -> Result<(), LexerError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("lex", len = self.data.len()).entered();
+
         loop {
             let next_char = self.iter.peek().copied();
             match next_char {
-                Some((_, '+')) => {
+                Some((start, '+')) => {
                     let n = self.count_char('+');
-                    self.push_token(TokenData::Increment(n));
+                    self.push_token(TokenData::Increment(n), start);
                 }
-                Some((_, '-')) => {
+                Some((start, '-')) => {
                     let n = self.count_char('-');
-                    self.push_token(TokenData::Decrement(n));
+                    self.push_token(TokenData::Decrement(n), start);
                 }
-                Some((_, '>')) => {
+                Some((start, '>')) => {
                     let n = self.count_char('>');
-                    self.push_token(TokenData::ShiftRight(n));
+                    self.push_token(TokenData::ShiftRight(n), start);
                 }
-                Some((_, '<')) => {
+                Some((start, '<')) => {
                     let n = self.count_char('<');
-                    self.push_token(TokenData::ShiftLeft(n));
+                    self.push_token(TokenData::ShiftLeft(n), start);
+                }
+                Some((start, '.')) => {
+                    self.iter.next();
+                    self.push_token(TokenData::Print, start);
                 }
-                Some((_, '.')) => {
+                Some((start, ',')) => {
                     self.iter.next();
-                    self.push_token(TokenData::Print);
+                    self.push_token(TokenData::Read, start);
                 }
-                Some((_, ',')) => {
+                Some((start, ']')) => {
                     self.iter.next();
-                    self.push_token(TokenData::Read);
+                    self.push_token(TokenData::EndLoop, start);
                 }
-                Some((_, ']')) => {
+                Some((start, '[')) => {
                     self.iter.next();
-                    self.push_token(TokenData::EndLoop);
+                    self.push_token(TokenData::StartLoop, start);
                 }
-                Some((_, '[')) => {
+                Some((start, '#')) if self.breakpoints => {
                     self.iter.next();
-                    self.push_token(TokenData::StartLoop);
+                    self.push_token(TokenData::Breakpoint, start);
                 }
                 Some((start, _)) => {
-                    let mut end = 0;
+                    let mut end = self.data.len();
                     while let Some((i, c)) = self.iter.peek() {
-                        end = *i;
-                        if is_bf_char(*c) {
+                        if is_bf_char(*c) || (self.breakpoints && *c == '#') {
+                            end = *i;
                             break;
                         }
                         self.iter.next();
                     }
 
                     let s = self.data[start..end].to_string();
-                    self.push_token(TokenData::Other(s));
+                    self.push_token(TokenData::Other(s), start);
                 }
                 None => {
                     break;
@@ -112,3 +154,54 @@ impl<'a> Lexer<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn breakpoints() {
+        let mut l = Lexer::new("+# x#.");
+        l.lex().unwrap();
+        assert_eq!(l.tokens[1].data, TokenData::Other("# x#".into()));
+
+        let mut l = Lexer::new("+# x#.");
+        l.set_breakpoints(true);
+        l.lex().unwrap();
+        let data: Vec<_> = l.tokens.into_iter().map(|token| token.data).collect();
+        assert_eq!(
+            data,
+            [
+                TokenData::Increment(1),
+                TokenData::Breakpoint,
+                TokenData::Other(" x".into()),
+                TokenData::Breakpoint,
+                TokenData::Print,
+            ]
+        );
+    }
+
+    #[test]
+    fn offsets() {
+        let mut l = Lexer::with_offsets("+ +>>[-]");
+        l.lex().unwrap();
+
+        let offsets: Vec<_> = l.tokens.iter().map(|token| token.offset).collect();
+        assert_eq!(
+            offsets,
+            [
+                Some(0),
+                Some(1),
+                Some(2),
+                Some(3),
+                Some(5),
+                Some(6),
+                Some(7)
+            ]
+        );
+
+        let mut l = Lexer::new("+ +");
+        l.lex().unwrap();
+        assert!(l.tokens.iter().all(|token| token.offset.is_none()));
+    }
+}
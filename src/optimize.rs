@@ -1,3 +1,12 @@
+//! Passes that rewrite an `Expr` tree into a faster one with the same behaviour.
+//!
+//! Some passes assume the expr they are given is a whole program: that it runs from the start, on a zeroed tape.
+//! Running them on a loop body or a snippet that is later spliced into another program can change what it does.
+//! These are:
+//! - `EmptyLoopOptimizer`
+//! - `ConstantLoopOptimizer`
+//! - `DeadCodeAfterInfiniteLoop`
+
 use crate::{
     interpreter::{
         Handler,
@@ -5,10 +14,49 @@ use crate::{
     },
     parser::Expr,
 };
+use std::{
+    collections::{
+        BTreeSet,
+        HashMap,
+        HashSet,
+    },
+    io,
+};
 // use std::collections::HashSet;
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum Diagnostic {
+    /// An empty loop that never terminates if it is entered.
+    EmptyLoop { id: Option<usize> },
+    /// Code after a loop that is entered and never terminates was removed.
+    UnreachableCode { id: Option<usize>, removed: usize },
+}
+
 pub trait OptimizePass {
     fn optimize(&mut self, expr: &mut Expr);
+
+    /// The name shown in debug output. Defaults to the type name without its path.
+    fn name(&self) -> &str {
+        let name = std::any::type_name::<Self>();
+        name.rsplit("::").next().unwrap_or(name)
+    }
+
+    /// Diagnostics recorded since the last call.
+    fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        Vec::new()
+    }
+}
+
+struct ClosurePass<F>(F);
+
+impl<F: FnMut(&mut Expr)> OptimizePass for ClosurePass<F> {
+    fn optimize(&mut self, expr: &mut Expr) {
+        (self.0)(expr)
+    }
+
+    fn name(&self) -> &str {
+        "closure"
+    }
 }
 
 pub struct ZeroLoopOptimizer;
@@ -21,7 +69,7 @@ impl OptimizePass for ZeroLoopOptimizer {
                     self.optimize(expr);
                 }
             }
-            Expr::Loop { expr } => match &**expr {
+            Expr::Loop { expr, .. } => match &**expr {
                 Expr::Block { exprs } if exprs.as_slice() == [Expr::Decrement { num: 1 }] => {
                     *top_expr = Expr::AssignCurrent { value: 0 };
                 }
@@ -32,51 +80,512 @@ impl OptimizePass for ZeroLoopOptimizer {
     }
 }
 
+/// Replaces the common divmod loop with `Expr::DivMod`.
+///
+/// This only matches the exact loop from `Expr::div_mod_loop`, so it is only added at `OptLevel::Aggressive`.
+pub struct DivModOptimizer;
+
+impl DivModOptimizer {
+    fn replace(expr: &mut Expr, pattern: &Expr) {
+        match expr {
+            Expr::Block { exprs } => {
+                for expr in exprs.iter_mut() {
+                    Self::replace(expr, pattern);
+                }
+            }
+            Expr::Loop { expr: body, .. } => Self::replace(body, pattern),
+            _ => {}
+        }
+
+        if expr.is_loop() && expr.without_loop_ids() == *pattern {
+            *expr = Expr::DivMod;
+        }
+    }
+}
+
+impl OptimizePass for DivModOptimizer {
+    fn optimize(&mut self, expr: &mut Expr) {
+        Self::replace(expr, &Expr::div_mod_loop());
+    }
+}
+
+/// Removes empty loops that are reached with a zero cell, and reports the rest as diagnostics.
+#[derive(Default)]
+pub struct EmptyLoopOptimizer {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl EmptyLoopOptimizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `zero` is whether the current cell is known to be 0 at the start of the block.
+    fn optimize_block(&mut self, exprs: &mut Vec<Expr>, mut zero: bool) {
+        let mut i = 0;
+        while i < exprs.len() {
+            match &mut exprs[i] {
+                Expr::Loop { expr, id } => {
+                    if is_empty_block(expr) {
+                        if zero {
+                            exprs.remove(i);
+                            continue;
+                        }
+
+                        let diagnostic = Diagnostic::EmptyLoop { id: *id };
+                        if !self.diagnostics.contains(&diagnostic) {
+                            self.diagnostics.push(diagnostic);
+                        }
+                    } else if let Expr::Block { exprs } = &mut **expr {
+                        self.optimize_block(exprs, false);
+                    }
+
+                    zero = true;
+                }
+                Expr::Block { exprs } => {
+                    self.optimize_block(exprs, zero);
+                    zero = false;
+                }
+                Expr::AssignCurrent { value } => {
+                    zero = *value == 0;
+                }
+                Expr::PrintChar
+                | Expr::PrintString { .. }
+                | Expr::ReadCharForget
+                | Expr::Comment { .. }
+                | Expr::Breakpoint => {}
+                _ => {
+                    zero = false;
+                }
+            }
+
+            i += 1;
+        }
+    }
+}
+
+fn is_empty_block(expr: &Expr) -> bool {
+    match expr {
+        Expr::Block { exprs } => exprs.is_empty(),
+        _ => false,
+    }
+}
+
+impl OptimizePass for EmptyLoopOptimizer {
+    fn optimize(&mut self, expr: &mut Expr) {
+        if let Expr::Block { exprs } = expr {
+            self.optimize_block(exprs, true);
+        }
+    }
+
+    fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+}
+
+/// What is known about the tape at a point in the top-level block of a program.
+///
+/// Cells that are neither in `values` nor `unknown` still hold their initial value of 0.
+#[derive(Clone, Default)]
+struct KnownTape {
+    pointer: usize,
+    values: HashMap<usize, u8>,
+    unknown: HashSet<usize>,
+}
+
+impl KnownTape {
+    fn get(&self, index: usize) -> Option<u8> {
+        if self.unknown.contains(&index) {
+            None
+        } else {
+            Some(self.values.get(&index).copied().unwrap_or(0))
+        }
+    }
+
+    fn set(&mut self, index: usize, value: Option<u8>) {
+        match value {
+            Some(value) => {
+                self.unknown.remove(&index);
+                self.values.insert(index, value);
+            }
+            None => {
+                self.values.remove(&index);
+                self.unknown.insert(index);
+            }
+        }
+    }
+
+    fn add(&mut self, num: u8) {
+        let value = self.get(self.pointer).map(|value| value.wrapping_add(num));
+        self.set(self.pointer, value);
+    }
+
+    /// Update the tape for `expr`, returning `false` if the pointer can no longer be tracked.
+    fn apply(&mut self, expr: &Expr) -> bool {
+        match expr {
+            Expr::Block { exprs } => exprs.iter().all(|expr| self.apply(expr)),
+            Expr::Loop { expr, .. } => {
+                if self.get(self.pointer) == Some(0) {
+                    return true;
+                }
+
+                let mut written = HashSet::new();
+                if !loop_writes(expr, &mut 0, &mut written) {
+                    return false;
+                }
+
+                for offset in written {
+                    match offset_index(self.pointer, offset) {
+                        Some(index) => self.set(index, None),
+                        None => return false,
+                    }
+                }
+                self.set(self.pointer, Some(0));
+                true
+            }
+            Expr::Increment { num } => {
+                self.add(*num as u8);
+                true
+            }
+            Expr::Decrement { num } => {
+                self.add(0u8.wrapping_sub(*num as u8));
+                true
+            }
+            Expr::ShiftRight { num } => {
+                self.pointer += num;
+                true
+            }
+            Expr::ShiftLeft { num } => match self.pointer.checked_sub(*num) {
+                Some(pointer) => {
+                    self.pointer = pointer;
+                    true
+                }
+                None => false,
+            },
+            Expr::ReadChar => {
+                self.set(self.pointer, None);
+                true
+            }
+            Expr::Assign { index, value } => {
+                self.set(*index, Some(*value));
+                true
+            }
+            Expr::AssignCurrent { value } => {
+                self.set(self.pointer, Some(*value));
+                true
+            }
+            Expr::SetCellPointer { value } => {
+                self.pointer = *value;
+                true
+            }
+            Expr::PrintChar
+            | Expr::PrintString { .. }
+            | Expr::ReadCharForget
+            | Expr::Comment { .. }
+            | Expr::Breakpoint => true,
+            Expr::DivMod => self.apply(&Expr::div_mod_loop()),
+        }
+    }
+}
+
+/// Collect the offsets from the loop's starting cell that a loop body may write to.
+///
+/// Returns `false` if the body doesn't return the pointer to where it started.
+fn loop_writes(expr: &Expr, pointer: &mut isize, written: &mut HashSet<isize>) -> bool {
+    match expr {
+        Expr::Block { exprs } => exprs.iter().all(|expr| loop_writes(expr, pointer, written)),
+        Expr::Loop { expr, .. } => {
+            let start = *pointer;
+            loop_writes(expr, pointer, written) && *pointer == start
+        }
+        Expr::Increment { .. }
+        | Expr::Decrement { .. }
+        | Expr::ReadChar
+        | Expr::AssignCurrent { .. } => {
+            written.insert(*pointer);
+            true
+        }
+        Expr::ShiftRight { num } => {
+            *pointer += *num as isize;
+            true
+        }
+        Expr::ShiftLeft { num } => {
+            *pointer -= *num as isize;
+            true
+        }
+        Expr::Assign { .. } | Expr::SetCellPointer { .. } => false,
+        Expr::PrintChar
+        | Expr::PrintString { .. }
+        | Expr::ReadCharForget
+        | Expr::Comment { .. }
+        | Expr::Breakpoint => true,
+        Expr::DivMod => loop_writes(&Expr::div_mod_loop(), pointer, written),
+    }
+}
+
+fn offset_index(pointer: usize, offset: isize) -> Option<usize> {
+    if offset < 0 {
+        pointer.checked_sub(offset.unsigned_abs())
+    } else {
+        pointer.checked_add(offset as usize)
+    }
+}
+
+/// Evaluates loops in the top-level block whose iteration count and effects are known,
+/// replacing them with a `PrintString` of their output and assignments of the cells they write.
+///
+/// Only loops without reads or nested loops that finish within `max_unroll` iterations are folded.
+pub struct ConstantLoopOptimizer {
+    pub max_unroll: usize,
+}
+
+impl ConstantLoopOptimizer {
+    pub fn new(max_unroll: usize) -> Self {
+        Self { max_unroll }
+    }
+
+    /// Evaluate a loop entered with a known nonzero cell, returning its replacement.
+    fn fold(&self, tape: &mut KnownTape, body: &Expr) -> Option<Vec<Expr>> {
+        let body = match body {
+            Expr::Block { exprs } => exprs.as_slice(),
+            body => std::slice::from_ref(body),
+        };
+
+        let mut sim = tape.clone();
+        let mut output = Vec::new();
+        let mut written = BTreeSet::new();
+        let mut iterations = 0;
+
+        while sim.get(sim.pointer)? != 0 {
+            if iterations == self.max_unroll {
+                return None;
+            }
+            iterations += 1;
+
+            for expr in body {
+                match expr {
+                    Expr::Increment { .. }
+                    | Expr::Decrement { .. }
+                    | Expr::AssignCurrent { .. } => {
+                        written.insert(sim.pointer);
+                    }
+                    Expr::Assign { index, .. } => {
+                        written.insert(*index);
+                    }
+                    Expr::PrintChar => output.push(sim.get(sim.pointer)?),
+                    Expr::PrintString { value } => output.extend(value.bytes()),
+                    Expr::ShiftLeft { .. }
+                    | Expr::ShiftRight { .. }
+                    | Expr::SetCellPointer { .. }
+                    | Expr::Comment { .. } => {}
+                    Expr::Block { .. }
+                    | Expr::Loop { .. }
+                    | Expr::ReadChar
+                    | Expr::ReadCharForget
+                    | Expr::DivMod
+                    | Expr::Breakpoint => return None,
+                }
+
+                if !sim.apply(expr) {
+                    return None;
+                }
+            }
+        }
+
+        // `PrintString` writes its value as UTF-8
+        let output = String::from_utf8(output).ok()?;
+
+        let mut exprs = Vec::new();
+        if !output.is_empty() {
+            exprs.push(Expr::PrintString { value: output });
+        }
+        for index in written {
+            exprs.push(Expr::Assign {
+                index,
+                value: sim.get(index)?,
+            });
+        }
+        exprs.push(Expr::SetCellPointer { value: sim.pointer });
+
+        *tape = sim;
+        Some(exprs)
+    }
+}
+
+impl Default for ConstantLoopOptimizer {
+    fn default() -> Self {
+        Self::new(256)
+    }
+}
+
+impl OptimizePass for ConstantLoopOptimizer {
+    fn optimize(&mut self, expr: &mut Expr) {
+        let exprs = match expr {
+            Expr::Block { exprs } => exprs,
+            _ => return,
+        };
+
+        let mut tape = KnownTape::default();
+        let mut i = 0;
+        while i < exprs.len() {
+            let folded = match &exprs[i] {
+                Expr::Loop { expr, .. } if tape.get(tape.pointer).unwrap_or(0) != 0 => {
+                    self.fold(&mut tape, expr)
+                }
+                _ => None,
+            };
+
+            if let Some(folded) = folded {
+                let len = folded.len();
+                exprs.splice(i..i + 1, folded);
+                i += len;
+                continue;
+            }
+
+            if !tape.apply(&exprs[i]) {
+                return;
+            }
+            i += 1;
+        }
+    }
+}
+
+/// Removes code in the top-level block after a loop that is entered and never terminates.
+///
+/// Loops are only considered infinite if `Expr::is_obviously_infinite` holds
+/// and their cell is known to be nonzero when they are reached.
+#[derive(Default)]
+pub struct DeadCodeAfterInfiniteLoop {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl DeadCodeAfterInfiniteLoop {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl OptimizePass for DeadCodeAfterInfiniteLoop {
+    fn optimize(&mut self, expr: &mut Expr) {
+        let exprs = match expr {
+            Expr::Block { exprs } => exprs,
+            _ => return,
+        };
+
+        let mut tape = KnownTape::default();
+        for i in 0..exprs.len() {
+            let expr = &exprs[i];
+            if expr.is_obviously_infinite() && tape.get(tape.pointer).unwrap_or(0) != 0 {
+                let id = match expr {
+                    Expr::Loop { id, .. } => *id,
+                    _ => None,
+                };
+
+                let removed = exprs.len() - i - 1;
+                if removed > 0 {
+                    exprs.truncate(i + 1);
+                    self.diagnostics
+                        .push(Diagnostic::UnreachableCode { id, removed });
+                }
+                return;
+            }
+
+            if !tape.apply(expr) {
+                return;
+            }
+        }
+    }
+
+    fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+}
+
 pub struct SpecExecHandler {
-    out: Vec<String>,
+    out: Vec<Vec<u8>>,
     // dirty_cells: HashSet<usize>,
 }
 
 impl SpecExecHandler {
     fn new() -> Self {
         Self {
-            out: Vec::new(),
+            // Each skipped read starts a new segment, even before any output
+            out: vec![Vec::new()],
             // dirty_cells: HashSet::new(),
         }
     }
 
-    fn print(&mut self, c: char) {
-        if self.out.is_empty() {
-            self.out.push(String::new());
-        }
-
+    fn print(&mut self, c: u8) {
         self.out.last_mut().unwrap().push(c);
     }
 }
 
 impl Handler for SpecExecHandler {
-    fn write_char(&mut self, c: u8) {
-        self.print(char::from(c));
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.print(c);
+        Ok(())
+    }
+}
+
+/// Settings for `SpecExecOptimizer`.
+///
+/// Speculation runs the start of the program at compile time, so it stops before anything whose
+/// result depends on input. A read is only skipped when its value is overwritten or never used.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpecExecConfig {
+    /// Run loops that read input as if every read hit the end of input, instead of stopping before them.
+    ///
+    /// This is only sound for programs that are run without input.
+    pub speculate_loop_reads: bool,
+    /// The most steps to speculate for, counted like `Interpreter::set_fuel`.
+    /// The program is left as is if it runs longer.
+    pub max_steps: u64,
+}
+
+impl Default for SpecExecConfig {
+    fn default() -> Self {
+        Self {
+            speculate_loop_reads: false,
+            max_steps: 1_000_000,
+        }
     }
 }
 
-pub struct SpecExecOptimizer;
+/// Replaces the start of the program with the output and tape it produces.
+///
+/// `PrintString` writes its value as UTF-8, so the program is left as is if the output isn't valid UTF-8.
+#[derive(Default)]
+pub struct SpecExecOptimizer {
+    config: SpecExecConfig,
+}
+
+impl SpecExecOptimizer {
+    pub fn new(config: SpecExecConfig) -> Self {
+        Self { config }
+    }
+}
 
 impl OptimizePass for SpecExecOptimizer {
     fn optimize(&mut self, top_expr: &mut Expr) {
         if let Expr::Block { exprs } = top_expr {
             let mut vm = Interpreter::new(SpecExecHandler::new());
+            vm.set_fuel(Some(self.config.max_steps));
             let mut read_pos = None;
 
             for (i, expr) in exprs.iter().enumerate() {
-                if expr.contains_read() {
+                if expr.is_loop() && expr.contains_read() {
+                    if !self.config.speculate_loop_reads {
+                        read_pos = Some(i);
+                        break;
+                    }
+                } else if expr.contains_read() {
                     match exprs.get(i + 1) {
                         Some(Expr::AssignCurrent { .. }) => {
-                            vm.handler.out.push(String::new());
+                            vm.handler.out.push(Vec::new());
                         }
                         _ => {
                             if exprs.iter().skip(i + 1).all(|expr| !expr.uses_memory()) {
-                                vm.handler.out.push(String::new());
+                                vm.handler.out.push(Vec::new());
                             } else {
                                 read_pos = Some(i);
                                 break;
@@ -85,7 +594,7 @@ impl OptimizePass for SpecExecOptimizer {
                     }
                 }
 
-                match vm.run(&expr) {
+                match vm.run(expr) {
                     Ok(_) => {}
                     Err(_e) => {
                         return;
@@ -93,6 +602,15 @@ impl OptimizePass for SpecExecOptimizer {
                 }
             }
 
+            let out: Vec<String> = match std::mem::take(&mut vm.handler.out)
+                .into_iter()
+                .map(String::from_utf8)
+                .collect()
+            {
+                Ok(out) => out,
+                Err(_) => return,
+            };
+
             match read_pos {
                 Some(pos) => {
                     let mut new_exprs = Vec::new();
@@ -103,17 +621,23 @@ impl OptimizePass for SpecExecOptimizer {
                         });
                     }
 
-                    for value in vm.handler.out.iter().take(vm.handler.out.len() - 1) {
-                        new_exprs.push(Expr::PrintString {
-                            value: value.clone(),
-                        });
+                    if let Some((last, values)) = out.split_last() {
+                        for value in values {
+                            if !value.is_empty() {
+                                new_exprs.push(Expr::PrintString {
+                                    value: value.clone(),
+                                });
+                            }
 
-                        new_exprs.push(Expr::ReadCharForget);
-                    }
+                            new_exprs.push(Expr::ReadCharForget);
+                        }
 
-                    new_exprs.push(Expr::PrintString {
-                        value: vm.handler.out.last().unwrap().clone(),
-                    });
+                        if !last.is_empty() {
+                            new_exprs.push(Expr::PrintString {
+                                value: last.clone(),
+                            });
+                        }
+                    }
 
                     new_exprs.push(Expr::SetCellPointer {
                         value: vm.current_cell_index(),
@@ -127,17 +651,23 @@ impl OptimizePass for SpecExecOptimizer {
                 }
                 None => {
                     let mut new_exprs = Vec::new();
-                    for value in vm.handler.out.iter().take(vm.handler.out.len() - 1) {
-                        new_exprs.push(Expr::PrintString {
-                            value: value.clone(),
-                        });
+                    if let Some((last, values)) = out.split_last() {
+                        for value in values {
+                            if !value.is_empty() {
+                                new_exprs.push(Expr::PrintString {
+                                    value: value.clone(),
+                                });
+                            }
 
-                        new_exprs.push(Expr::ReadCharForget);
-                    }
+                            new_exprs.push(Expr::ReadCharForget);
+                        }
 
-                    new_exprs.push(Expr::PrintString {
-                        value: vm.handler.out.last().unwrap().clone(),
-                    });
+                        if !last.is_empty() {
+                            new_exprs.push(Expr::PrintString {
+                                value: last.clone(),
+                            });
+                        }
+                    }
 
                     *top_expr = Expr::Block { exprs: new_exprs };
                 }
@@ -146,50 +676,172 @@ impl OptimizePass for SpecExecOptimizer {
     }
 }
 
-/*
-fn overwrites_current_cell(expr: &Expr) -> bool {
-    match expr {
-        Expr::AssignCurrent { .. } => true,
-        //  Expr::ReadChar => true,
-        _ => false,
-    }
-}
-*/
-/*
+/// Removes writes to the current cell that are overwritten by an `AssignCurrent` before anything reads them.
 pub struct WriteMergeOptimizer;
 
+/// Whether `expr` neither reads the current cell nor moves the pointer.
+fn keeps_current_cell_unread(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::PrintString { .. } | Expr::ReadCharForget | Expr::Comment { .. }
+    )
+}
+
 impl OptimizePass for WriteMergeOptimizer {
     fn optimize(&mut self, top_expr: &mut Expr) {
         match top_expr {
             Expr::Block { exprs } => {
-                let mut new_exprs = Vec::with_capacity(exprs.len());
+                let mut new_exprs: Vec<Expr> = Vec::with_capacity(exprs.len());
 
-                for i in 0..exprs.len() {
-                    let expr1 = exprs.get(i);
-                    let expr2 = exprs.get(i + 1);
+                for expr in exprs.iter_mut() {
+                    self.optimize(expr);
 
-                    match (expr1, expr2) {
-                        (Some(expr1), _) => {
-                            new_exprs.push(expr1.clone());
+                    if let Expr::AssignCurrent { .. } = expr {
+                        let last_write = new_exprs
+                            .iter()
+                            .rposition(|expr| !keeps_current_cell_unread(expr));
+                        if let Some(i) = last_write {
+                            if let Expr::AssignCurrent { .. } = new_exprs[i] {
+                                new_exprs.remove(i);
+                            }
                         }
-                        _ => {}
                     }
+
+                    new_exprs.push(expr.clone());
                 }
 
                 if &mut new_exprs != exprs {
                     std::mem::swap(&mut new_exprs, exprs);
                 }
             }
-            Expr::Loop { expr } => self.optimize(expr),
+            Expr::Loop { expr, .. } => self.optimize(expr),
+            _ => {}
+        }
+    }
+}
+
+/// Merges runs of increments and decrements, and runs of shifts, into at most one expression each,
+/// dropping runs that cancel out like `+-` or `><`. Comments inside a run are moved after it.
+///
+/// This assumes cells wrap, and that the pointer doesn't leave the tape partway through a run of shifts.
+pub struct CombineOptimizer;
+
+/// A run of adjacent expressions that move the same thing, as counts in each direction.
+#[derive(Clone, Copy)]
+enum Run {
+    Cell { up: usize, down: usize },
+    Pointer { right: usize, left: usize },
+}
+
+impl Run {
+    fn from_expr(expr: &Expr) -> Option<Self> {
+        match *expr {
+            Expr::Increment { num } => Some(Run::Cell { up: num, down: 0 }),
+            Expr::Decrement { num } => Some(Run::Cell { up: 0, down: num }),
+            Expr::ShiftRight { num } => Some(Run::Pointer {
+                right: num,
+                left: 0,
+            }),
+            Expr::ShiftLeft { num } => Some(Run::Pointer {
+                right: 0,
+                left: num,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Add `other` to this run if it moves the same thing.
+    fn merge(&mut self, other: Run) -> bool {
+        match (self, other) {
+            (Run::Cell { up, down }, Run::Cell { up: u, down: d }) => {
+                *up += u;
+                *down += d;
+                true
+            }
+            (Run::Pointer { right, left }, Run::Pointer { right: r, left: l }) => {
+                *right += r;
+                *left += l;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The single expression with the same effect, if the run doesn't cancel out.
+    fn into_expr(self) -> Option<Expr> {
+        match self {
+            Run::Cell { up, down } if up > down => Some(Expr::Increment { num: up - down }),
+            Run::Cell { up, down } if down > up => Some(Expr::Decrement { num: down - up }),
+            Run::Pointer { right, left } if right > left => {
+                Some(Expr::ShiftRight { num: right - left })
+            }
+            Run::Pointer { right, left } if left > right => {
+                Some(Expr::ShiftLeft { num: left - right })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Push the net expression of `run` and the comments that were inside it.
+fn flush_run(run: Option<Run>, comments: &mut Vec<Expr>, out: &mut Vec<Expr>) {
+    out.extend(run.and_then(Run::into_expr));
+    out.append(comments);
+}
+
+impl OptimizePass for CombineOptimizer {
+    fn optimize(&mut self, top_expr: &mut Expr) {
+        match top_expr {
+            Expr::Block { exprs } => {
+                let mut new_exprs = Vec::with_capacity(exprs.len());
+                let mut run: Option<Run> = None;
+                let mut comments = Vec::new();
+
+                for mut expr in exprs.drain(..) {
+                    if let Some(next) = Run::from_expr(&expr) {
+                        let merged = match &mut run {
+                            Some(run) => run.merge(next),
+                            None => false,
+                        };
+                        if !merged {
+                            flush_run(run, &mut comments, &mut new_exprs);
+                            run = Some(next);
+                        }
+                    } else if run.is_some() && matches!(expr, Expr::Comment { .. }) {
+                        comments.push(expr);
+                    } else {
+                        flush_run(run.take(), &mut comments, &mut new_exprs);
+                        self.optimize(&mut expr);
+                        new_exprs.push(expr);
+                    }
+                }
+                flush_run(run, &mut comments, &mut new_exprs);
+
+                *exprs = new_exprs;
+            }
+            Expr::Loop { expr, .. } => self.optimize(expr),
             _ => {}
         }
     }
 }
-*/
+
+/// A preset group of passes for `Optimizer::add_passes`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptLevel {
+    /// Leaves out `CombineOptimizer`, which assumes cells wrap and the pointer stays on the tape,
+    /// so it can drop the failures a run with `OverflowPolicy::Error` or a bounded tape would report.
+    Default,
+    /// Also replace recognized subroutines with native ops, which only matches exact code, and combine adjacent ops.
+    Aggressive,
+}
+
 pub struct Optimizer {
     pub expr: Expr,
+    pub diagnostics: Vec<Diagnostic>,
 
     passes: Vec<Box<dyn OptimizePass>>,
+    // The pass `step` runs next
+    next_pass: usize,
 }
 
 impl Optimizer {
@@ -200,7 +852,9 @@ impl Optimizer {
 
         Optimizer {
             expr,
+            diagnostics: Vec::new(),
             passes: Vec::new(),
+            next_pass: 0,
         }
     }
 
@@ -208,20 +862,600 @@ impl Optimizer {
         self.passes.push(Box::new(pass));
     }
 
+    /// Add the passes for `level`.
+    pub fn add_passes(&mut self, level: OptLevel) {
+        if level == OptLevel::Aggressive {
+            self.add_pass(CombineOptimizer);
+            self.add_pass(DivModOptimizer);
+        }
+
+        self.add_pass(ZeroLoopOptimizer);
+        self.add_pass(WriteMergeOptimizer);
+        self.add_pass(SpecExecOptimizer::default());
+    }
+
+    pub fn add_closure<F: FnMut(&mut Expr) + 'static>(&mut self, f: F) {
+        self.add_pass(ClosurePass(f));
+    }
+
     pub fn optimize(&mut self) {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("optimize", passes = self.passes.len()).entered();
+
+        let limit = 3;
+
+        for _ in 0..limit {
+            let mut changed = false;
+            for i in 0..self.passes.len() {
+                changed |= self.run_pass(i);
+            }
+
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    /// Like `optimize`, but returns the name of every pass that changed the program
+    /// along with `Expr::pretty` output of the program after it ran.
+    pub fn optimize_verbose(&mut self) -> Vec<(String, String)> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("optimize", passes = self.passes.len()).entered();
+
         let limit = 3;
-        let mut old_expr = self.expr.clone();
+        let mut log = Vec::new();
 
         for _ in 0..limit {
-            for pass in self.passes.iter_mut() {
-                pass.optimize(&mut self.expr);
+            let mut changed = false;
+            for i in 0..self.passes.len() {
+                if self.run_pass(i) {
+                    changed = true;
+                    log.push((self.passes[i].name().to_string(), self.expr.pretty()));
+                }
             }
 
-            if self.expr == old_expr {
+            if !changed {
                 break;
-            } else {
-                old_expr = self.expr.clone();
             }
         }
+
+        log
+    }
+
+    /// Run the next registered pass once, cycling through them in order.
+    ///
+    /// Returns whether the pass changed the expression.
+    pub fn step(&mut self) -> bool {
+        if self.passes.is_empty() {
+            return false;
+        }
+
+        let index = self.next_pass % self.passes.len();
+        self.next_pass = index + 1;
+        self.run_pass(index)
+    }
+
+    fn run_pass(&mut self, index: usize) -> bool {
+        let old_expr = self.expr.clone();
+        let pass = &mut self.passes[index];
+        pass.optimize(&mut self.expr);
+
+        for diagnostic in pass.take_diagnostics() {
+            if !self.diagnostics.contains(&diagnostic) {
+                self.diagnostics.push(diagnostic);
+            }
+        }
+
+        let changed = self.expr != old_expr;
+        #[cfg(feature = "tracing")]
+        if changed {
+            tracing::debug!(pass = %pass.name(), "rewrote the program");
+        }
+
+        changed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        interpreter::RecordingHandler,
+        test_util::parse,
+        Lexer,
+        Parser,
+    };
+
+    #[test]
+    fn dead_code_after_infinite_loop() {
+        let mut o = Optimizer::new(parse("+[].>+."));
+        o.add_pass(DeadCodeAfterInfiniteLoop::new());
+        o.optimize();
+
+        assert_eq!(
+            o.expr,
+            Expr::Block {
+                exprs: vec![
+                    Expr::Increment { num: 1 },
+                    Expr::Loop {
+                        expr: Box::new(Expr::Block { exprs: vec![] }),
+                        id: Some(0),
+                    },
+                ],
+            }
+        );
+        assert_eq!(
+            o.diagnostics,
+            [Diagnostic::UnreachableCode {
+                id: Some(0),
+                removed: 4,
+            }]
+        );
+    }
+
+    #[test]
+    fn dead_code_kept_when_unproven() {
+        for src in [",[].", "[].", "+[-].", "+[->+<]."] {
+            let expr = parse(src);
+            let mut o = Optimizer::new(expr.clone());
+            o.add_pass(DeadCodeAfterInfiniteLoop::new());
+            o.optimize();
+
+            assert_eq!(o.expr, expr);
+            assert!(o.diagnostics.is_empty());
+        }
+    }
+
+    #[test]
+    fn combine() {
+        let mut o = Optimizer::new(parse("++-->><<+-+[<>-+++]>>><"));
+        o.add_pass(CombineOptimizer);
+        o.optimize();
+
+        assert_eq!(
+            o.expr,
+            Expr::Block {
+                exprs: vec![
+                    Expr::Increment { num: 1 },
+                    Expr::Loop {
+                        expr: Box::new(Expr::Block {
+                            exprs: vec![Expr::Increment { num: 2 }],
+                        }),
+                        id: Some(0),
+                    },
+                    Expr::ShiftRight { num: 2 },
+                ],
+            }
+        );
+
+        let mut l = Lexer::new("+ a -- b . > c <");
+        l.lex().unwrap();
+        let mut parser = Parser::new(l.tokens);
+        parser.set_keep_comments(true);
+        let mut o = Optimizer::new(parser.parse().unwrap());
+        o.add_pass(CombineOptimizer);
+        o.optimize();
+
+        let comment = |text: &str| Expr::Comment { text: text.into() };
+        assert_eq!(
+            o.expr,
+            Expr::Block {
+                exprs: vec![
+                    Expr::Decrement { num: 1 },
+                    comment(" a "),
+                    comment(" b "),
+                    Expr::PrintChar,
+                    comment(" "),
+                    comment(" c "),
+                ],
+            }
+        );
+    }
+
+    /// Print `n` as two digits using the divmod loop.
+    fn print_digits(n: usize) -> String {
+        format!(
+            "{}>>++++++++++<<{}>>>>{}.<{}.",
+            "+".repeat(n),
+            "[->+>-[>+>>]>[+[-<+>]>+>>]<<<<<<]",
+            "+".repeat(48),
+            "+".repeat(48)
+        )
+    }
+
+    #[test]
+    fn div_mod() {
+        for n in [0, 7, 10, 47, 99] {
+            let expr = parse(&print_digits(n));
+
+            let mut o = Optimizer::new(expr.clone());
+            o.add_pass(DivModOptimizer);
+            o.optimize();
+
+            assert_eq!(o.expr.op_histogram().get("DivMod"), Some(&1));
+            assert_eq!(run_output(&o.expr), format!("{:02}", n));
+            assert_eq!(run_output(&o.expr), run_output(&expr));
+        }
+
+        let expr = parse(include_str!("../test_data/squares.bf"));
+        let mut o = Optimizer::new(expr.clone());
+        o.add_passes(OptLevel::Aggressive);
+        o.optimize();
+        assert_eq!(run_output(&o.expr), run_output(&expr));
+    }
+
+    #[test]
+    fn div_mod_unusual_layout() {
+        // Either the cell after the divisor isn't 0, or the divisor is 1 and the loop moves 3 cells left
+        // after one iteration, so the loop has to run as written
+        let cases = [
+            (parse("+++++++>>+++>+++<<<"), &[0, 1, 5, 13][..]),
+            (parse(">>>>+>>+<<"), &[0][..]),
+        ];
+        for (setup, ns) in cases.iter() {
+            for &n in ns.iter() {
+                let expr = Expr::concat(&[setup.clone(), parse(&"+".repeat(n))]);
+
+                let mut expected = Interpreter::new(crate::interpreter::DefaultHandler);
+                expected.run(&expr).unwrap();
+                expected.run(&Expr::div_mod_loop()).unwrap();
+
+                let mut vm = Interpreter::new(crate::interpreter::DefaultHandler);
+                vm.run(&expr).unwrap();
+                vm.run(&Expr::DivMod).unwrap();
+
+                assert_eq!(vm.cells(), expected.cells());
+                assert_eq!(vm.current_cell_index(), expected.current_cell_index());
+            }
+        }
+    }
+
+    #[test]
+    fn optimize_verbose() {
+        let mut o = Optimizer::new(parse("[-]"));
+        o.add_pass(ZeroLoopOptimizer);
+        o.add_pass(EmptyLoopOptimizer::new());
+        o.add_closure(|_| {});
+
+        let log = o.optimize_verbose();
+        assert_eq!(
+            log,
+            [(
+                String::from("ZeroLoopOptimizer"),
+                String::from("Block\n    AssignCurrent 0\n")
+            )]
+        );
+        assert_eq!(o.passes[2].name(), "closure");
+    }
+
+    #[test]
+    fn write_merge() {
+        let mut o = Optimizer::new(Expr::Block {
+            exprs: vec![
+                Expr::ReadChar,
+                Expr::AssignCurrent { value: 0 },
+                Expr::PrintString { value: "a".into() },
+                Expr::AssignCurrent { value: 5 },
+                Expr::PrintChar,
+                Expr::AssignCurrent { value: 6 },
+                Expr::ShiftRight { num: 1 },
+                Expr::AssignCurrent { value: 7 },
+            ],
+        });
+        o.add_pass(WriteMergeOptimizer);
+        o.optimize();
+
+        assert_eq!(
+            o.expr,
+            Expr::Block {
+                exprs: vec![
+                    Expr::ReadChar,
+                    Expr::PrintString { value: "a".into() },
+                    Expr::AssignCurrent { value: 5 },
+                    Expr::PrintChar,
+                    Expr::AssignCurrent { value: 6 },
+                    Expr::ShiftRight { num: 1 },
+                    Expr::AssignCurrent { value: 7 },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn spec_exec_loop_reads() {
+        fn run_with_input(expr: &Expr, input: &[u8]) -> Vec<u8> {
+            let mut vm = Interpreter::new(crate::RecordingHandler::default());
+            vm.run_with_input(expr, input).unwrap();
+            vm.handler.output().to_vec()
+        }
+
+        let program = parse("++++++++[>++++++++<-]>+.[,.]<.");
+
+        let mut o = Optimizer::new(program.clone());
+        o.add_pass(SpecExecOptimizer::default());
+        o.optimize();
+
+        assert_eq!(o.expr.collect_print_strings(), ["A"]);
+        assert_eq!(o.expr.op_histogram()["Loop"], 1);
+        for input in [&b""[..], b"x", b"echo"] {
+            assert_eq!(
+                run_with_input(&o.expr, input),
+                run_with_input(&program, input)
+            );
+        }
+
+        let mut o = Optimizer::new(program.clone());
+        o.add_pass(SpecExecOptimizer::new(SpecExecConfig {
+            speculate_loop_reads: true,
+            ..SpecExecConfig::default()
+        }));
+        o.optimize();
+
+        assert_eq!(o.expr.op_histogram().get("Loop"), None);
+        assert_eq!(run_with_input(&o.expr, b""), run_with_input(&program, b""));
+    }
+
+    #[test]
+    fn spec_exec_skipped_reads() {
+        fn run_with_input(expr: &Expr, input: &[u8]) -> Vec<u8> {
+            let mut vm = Interpreter::new(RecordingHandler::new());
+            vm.set_fuel(Some(10_000));
+            // The last program ends in an infinite loop
+            let _ = vm.run_with_input(expr, input);
+            vm.handler.output().to_vec()
+        }
+
+        // A read before any output still needs its `ReadCharForget`
+        for src in [",[-],.", ",[-].", "->>>[-.+]<>,[-]-<.+>--,.-[][>+>+]."] {
+            let program = parse(src);
+            let mut o = Optimizer::new(program.clone());
+            o.add_passes(OptLevel::Default);
+            o.optimize();
+
+            assert_eq!(
+                run_with_input(&o.expr, b"AB"),
+                run_with_input(&program, b"AB"),
+                "{}",
+                src
+            );
+        }
+        assert_eq!(run_with_input(&parse(",[-],."), b"AB"), b"B");
+    }
+
+    #[test]
+    fn spec_exec_non_ascii() {
+        fn run_with_input(expr: &Expr, input: &[u8]) -> Vec<u8> {
+            let mut vm = Interpreter::new(RecordingHandler::new());
+            vm.run_with_input(expr, input).unwrap();
+            vm.handler.output().to_vec()
+        }
+
+        // 0xff on its own isn't UTF-8, so it can't become a `PrintString`
+        let program = parse(",[-]-.,.");
+        let mut o = Optimizer::new(program.clone());
+        o.add_passes(OptLevel::Default);
+        o.optimize();
+        assert_eq!(run_with_input(&o.expr, b"AB"), [0xff, b'B']);
+        assert_eq!(run_with_input(&program, b"AB"), [0xff, b'B']);
+
+        // "é" is 0xc3 0xa9, and stays the same over every round
+        let program = parse(&format!("{}.>{}.", "-".repeat(61), "-".repeat(87)));
+        let mut o = Optimizer::new(program.clone());
+        o.add_pass(SpecExecOptimizer::default());
+        o.optimize();
+        assert_eq!(o.expr.collect_print_strings(), ["é"]);
+        assert_eq!(run_with_input(&o.expr, b""), "é".as_bytes());
+    }
+
+    #[test]
+    fn spec_exec_max_steps() {
+        let program = parse("+[>+<+]");
+
+        let mut o = Optimizer::new(program.clone());
+        o.add_pass(SpecExecOptimizer::new(SpecExecConfig {
+            max_steps: 100,
+            ..SpecExecConfig::default()
+        }));
+        o.optimize();
+
+        assert_eq!(o.expr, program);
+    }
+
+    #[test]
+    fn step() {
+        let mut o = Optimizer::new(parse("[-]"));
+        o.add_pass(ZeroLoopOptimizer);
+
+        assert!(o.step());
+        assert!(!o.step());
+        assert!(!o.step());
+    }
+
+    #[test]
+    fn add_closure() {
+        fn zero_increments(expr: &mut Expr) {
+            match expr {
+                Expr::Block { exprs } => exprs.iter_mut().for_each(zero_increments),
+                Expr::Loop { expr, .. } => zero_increments(expr),
+                Expr::Increment { num } => *num = 0,
+                _ => {}
+            }
+        }
+
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let closure_calls = calls.clone();
+
+        let mut o = Optimizer::new(parse("+++[>++<-]"));
+        o.add_closure(move |expr| {
+            closure_calls.set(closure_calls.get() + 1);
+            zero_increments(expr);
+        });
+        o.optimize();
+
+        assert_eq!(
+            o.expr,
+            Expr::Block {
+                exprs: vec![
+                    Expr::Increment { num: 0 },
+                    Expr::Loop {
+                        expr: Box::new(Expr::Block {
+                            exprs: vec![
+                                Expr::ShiftRight { num: 1 },
+                                Expr::Increment { num: 0 },
+                                Expr::ShiftLeft { num: 1 },
+                                Expr::Decrement { num: 1 },
+                            ]
+                        }),
+                        id: Some(0),
+                    },
+                ]
+            }
+        );
+        assert_eq!(calls.get(), 2);
+    }
+
+    fn run_output(expr: &Expr) -> String {
+        let mut vm = Interpreter::new(RecordingHandler::new());
+        vm.run(expr).unwrap();
+        vm.handler.output().iter().map(|&c| char::from(c)).collect()
+    }
+
+    #[test]
+    fn constant_loop_folded() {
+        let program = parse("++++++++[>+<-]>[.-]");
+
+        let mut o = Optimizer::new(program.clone());
+        o.add_pass(ConstantLoopOptimizer::default());
+        o.optimize();
+
+        assert_eq!(
+            o.expr.collect_print_strings(),
+            ["\u{8}\u{7}\u{6}\u{5}\u{4}\u{3}\u{2}\u{1}"]
+        );
+        assert_eq!(o.expr.op_histogram().get("Loop"), None);
+        assert_eq!(run_output(&o.expr), run_output(&program));
+
+        let program = parse(",>+++++[>++<-]>[.-]<<.");
+
+        let mut o = Optimizer::new(program.clone());
+        o.add_pass(ConstantLoopOptimizer::default());
+        o.optimize();
+
+        assert_eq!(
+            o.expr.collect_print_strings(),
+            ["\n\u{9}\u{8}\u{7}\u{6}\u{5}\u{4}\u{3}\u{2}\u{1}"]
+        );
+        assert_eq!(o.expr.op_histogram().get("Loop"), None);
+        assert_eq!(run_output(&o.expr), run_output(&program));
+    }
+
+    #[test]
+    fn constant_loop_non_ascii() {
+        // Prints 0xff, which isn't UTF-8 on its own
+        let program = parse("-[.+]");
+
+        let mut o = Optimizer::new(program.clone());
+        o.add_pass(ConstantLoopOptimizer::default());
+        o.optimize();
+
+        assert_eq!(o.expr, program);
+
+        // Prints 0xc3 0xa9, "é"
+        let program = parse(&format!(">{}<++[>.{}<-]", "-".repeat(61), "-".repeat(26)));
+
+        let mut o = Optimizer::new(program.clone());
+        o.add_pass(ConstantLoopOptimizer::default());
+        o.optimize();
+
+        assert_eq!(o.expr.collect_print_strings(), ["é"]);
+        assert_eq!(run_output(&o.expr), run_output(&program));
+    }
+
+    #[test]
+    fn constant_loop_unroll_limit() {
+        let program = parse("++++++++[>+<-]>[.-]");
+
+        let mut o = Optimizer::new(program.clone());
+        o.add_pass(ConstantLoopOptimizer::new(4));
+        o.optimize();
+
+        assert_eq!(o.expr, program);
+
+        let program = parse("+++[>++++++++<-]>[.-]");
+
+        let mut o = Optimizer::new(program.clone());
+        o.add_pass(ConstantLoopOptimizer::new(8));
+        o.optimize();
+
+        assert_eq!(o.expr.op_histogram()["Loop"], 1);
+        assert_eq!(run_output(&o.expr), run_output(&program));
+    }
+
+    #[test]
+    fn collect_print_strings() {
+        let mut o = Optimizer::new(parse(include_str!("../test_data/hello_world1.bf")));
+        o.add_pass(ZeroLoopOptimizer);
+        o.add_pass(SpecExecOptimizer::default());
+        o.optimize();
+
+        assert_eq!(o.expr.collect_print_strings(), ["Hello World!\n"]);
+
+        let expr = Expr::Block {
+            exprs: vec![
+                Expr::PrintString { value: "a".into() },
+                Expr::Loop {
+                    expr: Box::new(Expr::PrintString { value: "b".into() }),
+                    id: None,
+                },
+                Expr::PrintChar,
+            ],
+        };
+        assert_eq!(expr.collect_print_strings(), ["a", "b"]);
+    }
+
+    #[test]
+    fn empty_loop_removed() {
+        let mut o = Optimizer::new(parse("[-][]"));
+        o.add_pass(EmptyLoopOptimizer::new());
+        o.optimize();
+
+        assert_eq!(o.expr, parse("[-]"));
+        assert!(o.diagnostics.is_empty());
+
+        let mut o = Optimizer::new(parse("[][-]+[-][][]."));
+        o.add_pass(ZeroLoopOptimizer);
+        o.add_pass(EmptyLoopOptimizer::new());
+        o.optimize();
+
+        assert_eq!(
+            o.expr,
+            Expr::Block {
+                exprs: vec![
+                    Expr::AssignCurrent { value: 0 },
+                    Expr::Increment { num: 1 },
+                    Expr::AssignCurrent { value: 0 },
+                    Expr::PrintChar,
+                ]
+            }
+        );
+        assert!(o.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn empty_loop_flagged() {
+        let mut o = Optimizer::new(parse("+[]"));
+        o.add_pass(EmptyLoopOptimizer::new());
+        o.optimize();
+
+        assert_eq!(o.expr, parse("+[]"));
+        assert_eq!(o.diagnostics, [Diagnostic::EmptyLoop { id: Some(0) }]);
+
+        let mut pass = EmptyLoopOptimizer::new();
+        let mut expr = parse("+[>[]<-]");
+        pass.optimize(&mut expr);
+        assert_eq!(
+            pass.take_diagnostics(),
+            [Diagnostic::EmptyLoop { id: Some(1) }]
+        );
     }
 }
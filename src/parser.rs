@@ -2,34 +2,85 @@ use crate::{
     Token,
     TokenData,
 };
+use std::{
+    collections::HashMap,
+    ops::Range,
+};
 
 #[derive(Debug)]
-pub enum ParseError {}
+pub enum ParseError {
+    /// Loops were nested deeper than the limit set with `Parser::set_max_depth`.
+    TooDeep { depth: usize },
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooDeep { depth } => write!(f, "loops are nested {} deep, past the limit", depth),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
-    Block { exprs: Vec<Expr> },
-    Increment { num: usize },
-    Decrement { num: usize },
+    Block {
+        exprs: Vec<Expr>,
+    },
+    Increment {
+        num: usize,
+    },
+    Decrement {
+        num: usize,
+    },
     PrintChar,
     ReadChar,
-    ShiftLeft { num: usize },
-    ShiftRight { num: usize },
-    Loop { expr: Box<Expr> },
-
-    Assign { index: usize, value: u8 },
-    AssignCurrent { value: u8 },
-    PrintString { value: String },
-    SetCellPointer { value: usize },
+    ShiftLeft {
+        num: usize,
+    },
+    ShiftRight {
+        num: usize,
+    },
+    Loop {
+        expr: Box<Expr>,
+        id: Option<usize>,
+    },
+
+    Assign {
+        index: usize,
+        value: u8,
+    },
+    AssignCurrent {
+        value: u8,
+    },
+    PrintString {
+        value: String,
+    },
+    SetCellPointer {
+        value: usize,
+    },
     ReadCharForget,
+    /// The divmod loop returned by `Expr::div_mod_loop`, run natively.
+    ///
+    /// The loop divides by whatever is in the divisor cell, not just the 10 of print-digit routines, so it isn't named `DivMod10`.
+    DivMod,
+    /// Text that isn't brainfuck, only produced if enabled with `Parser::set_keep_comments`.
+    Comment {
+        text: String,
+    },
+    /// A `#`, only produced if enabled with `Lexer::set_breakpoints`. Calls `Handler::on_breakpoint`.
+    Breakpoint,
 }
 
+/// Divides the current cell `n` by the cell 2 to the right `d`, leaving `0 n d-n%d n%d n/d`.
+///
+/// The 3 cells after those must be 0.
+const DIV_MOD_LOOP: &str = "[->+>-[>+>>]>[+[-<+>]>+>>]<<<<<<]";
+
 impl Expr {
     pub fn is_read(&self) -> bool {
-        match self {
-            Self::ReadChar { .. } => true,
-            _ => false,
-        }
+        matches!(self, Self::ReadChar { .. })
     }
 
     pub fn contains_read(&self) -> bool {
@@ -37,34 +88,705 @@ impl Expr {
             Self::ReadChar { .. } => true,
             Self::ReadCharForget { .. } => true,
             Self::Block { exprs } => exprs.iter().any(|expr| expr.contains_read()),
-            Self::Loop { expr } => expr.contains_read(),
+            Self::Loop { expr, .. } => expr.contains_read(),
             _ => false,
         }
     }
 
-    pub fn is_block(&self) -> bool {
+    /// The number of bytes this expr reads, or `None` if it reads inside a loop.
+    pub fn max_reads(&self) -> Option<usize> {
         match self {
-            Self::Block { .. } => true,
-            _ => false,
+            Self::ReadChar | Self::ReadCharForget => Some(1),
+            Self::Block { exprs } => exprs
+                .iter()
+                .try_fold(0, |reads, expr| Some(reads + expr.max_reads()?)),
+            Self::Loop { expr, .. } if expr.contains_read() => None,
+            _ => Some(0),
         }
     }
 
-    pub fn is_loop(&self) -> bool {
+    /// The first operation that isn't a block, looking into leading blocks.
+    pub fn first_op(&self) -> Option<&Expr> {
         match self {
-            Self::Loop { .. } => true,
-            _ => false,
+            Self::Block { exprs } => exprs.iter().find_map(Self::first_op),
+            expr => Some(expr),
+        }
+    }
+
+    /// The last operation that isn't a block, looking into trailing blocks.
+    pub fn last_op(&self) -> Option<&Expr> {
+        match self {
+            Self::Block { exprs } => exprs.iter().rev().find_map(Self::last_op),
+            expr => Some(expr),
         }
     }
 
+    pub fn is_block(&self) -> bool {
+        matches!(self, Self::Block { .. })
+    }
+
+    pub fn is_loop(&self) -> bool {
+        matches!(self, Self::Loop { .. })
+    }
+
     pub fn uses_memory(&self) -> bool {
         match self {
             Self::Block { exprs } => exprs.iter().any(|expr| expr.uses_memory()),
-            Self::Loop { expr } => expr.uses_memory(),
+            Self::Loop { expr, .. } => expr.uses_memory(),
             Self::PrintString { .. } => false,
             Self::ReadCharForget => false,
+            Self::Comment { .. } => false,
             _ => true,
         }
     }
+
+    /// The highest cell index this expr addresses directly, through `Assign` or `SetCellPointer`.
+    pub fn max_absolute_index(&self) -> Option<usize> {
+        match self {
+            Self::Block { exprs } => exprs.iter().filter_map(Self::max_absolute_index).max(),
+            Self::Loop { expr, .. } => expr.max_absolute_index(),
+            Self::Assign { index, .. } => Some(*index),
+            Self::SetCellPointer { value } => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The name of this expr's variant.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Block { .. } => "Block",
+            Self::Increment { .. } => "Increment",
+            Self::Decrement { .. } => "Decrement",
+            Self::PrintChar => "PrintChar",
+            Self::ReadChar => "ReadChar",
+            Self::ShiftLeft { .. } => "ShiftLeft",
+            Self::ShiftRight { .. } => "ShiftRight",
+            Self::Loop { .. } => "Loop",
+            Self::Assign { .. } => "Assign",
+            Self::AssignCurrent { .. } => "AssignCurrent",
+            Self::PrintString { .. } => "PrintString",
+            Self::SetCellPointer { .. } => "SetCellPointer",
+            Self::ReadCharForget => "ReadCharForget",
+            Self::DivMod => "DivMod",
+            Self::Comment { .. } => "Comment",
+            Self::Breakpoint => "Breakpoint",
+        }
+    }
+
+    /// Count the nodes of each variant in this tree, keyed by `Expr::name`.
+    ///
+    /// Every node counts once, so `Increment { num: 3 }` adds 1 to `"Increment"`.
+    /// Blocks and loops are counted along with their children.
+    pub fn op_histogram(&self) -> HashMap<&'static str, u64> {
+        let mut histogram = HashMap::new();
+        self.add_to_histogram(&mut histogram);
+        histogram
+    }
+
+    fn add_to_histogram(&self, histogram: &mut HashMap<&'static str, u64>) {
+        *histogram.entry(self.name()).or_insert(0) += 1;
+
+        match self {
+            Self::Block { exprs } => {
+                for expr in exprs {
+                    expr.add_to_histogram(histogram);
+                }
+            }
+            Self::Loop { expr, .. } => expr.add_to_histogram(histogram),
+            _ => {}
+        }
+    }
+
+    /// Every node of this tree in pre-order, starting with this one.
+    ///
+    /// The index of a node is its id, which stays the same as long as the tree isn't changed.
+    pub fn nodes(&self) -> Vec<&Expr> {
+        let mut nodes = Vec::new();
+        self.collect_nodes(&mut nodes);
+        nodes
+    }
+
+    /// The number of nodes in this tree, the length of `Expr::nodes`.
+    pub fn node_count(&self) -> usize {
+        1 + match self {
+            Self::Block { exprs } => exprs.iter().map(Self::node_count).sum(),
+            Self::Loop { expr, .. } => expr.node_count(),
+            _ => 0,
+        }
+    }
+
+    fn collect_nodes<'a>(&'a self, nodes: &mut Vec<&'a Expr>) {
+        nodes.push(self);
+        match self {
+            Self::Block { exprs } => {
+                for expr in exprs {
+                    expr.collect_nodes(nodes);
+                }
+            }
+            Self::Loop { expr, .. } => expr.collect_nodes(nodes),
+            _ => {}
+        }
+    }
+
+    /// All `PrintString` values in this tree, in program order.
+    pub fn collect_print_strings(&self) -> Vec<&str> {
+        let mut strings = Vec::new();
+        self.collect_print_strings_into(&mut strings);
+        strings
+    }
+
+    fn collect_print_strings_into<'a>(&'a self, strings: &mut Vec<&'a str>) {
+        match self {
+            Self::Block { exprs } => {
+                for expr in exprs {
+                    expr.collect_print_strings_into(strings);
+                }
+            }
+            Self::Loop { expr, .. } => expr.collect_print_strings_into(strings),
+            Self::PrintString { value } => strings.push(value),
+            _ => {}
+        }
+    }
+
+    /// Render this tree as a Graphviz DOT graph.
+    ///
+    /// A loop's body block is folded into the loop node. Child edges are labeled with their position.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph {\n");
+        self.write_dot(&mut dot, &mut 0);
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Render this tree with one node per line, indenting children.
+    pub fn pretty(&self) -> String {
+        let mut out = String::new();
+        self.write_pretty(&mut out, 0);
+        out
+    }
+
+    fn write_pretty(&self, out: &mut String, depth: usize) {
+        for _ in 0..depth {
+            out.push_str("    ");
+        }
+        out.push_str(&self.label());
+        out.push('\n');
+
+        match self {
+            Self::Block { exprs } => {
+                for expr in exprs {
+                    expr.write_pretty(out, depth + 1);
+                }
+            }
+            Self::Loop { expr, .. } => expr.write_pretty(out, depth + 1),
+            _ => {}
+        }
+    }
+
+    /// A short description of this node, without its children.
+    fn label(&self) -> String {
+        match self {
+            Self::Increment { num }
+            | Self::Decrement { num }
+            | Self::ShiftLeft { num }
+            | Self::ShiftRight { num } => format!("{} {}", self.name(), num),
+            Self::Loop {
+                id: Some(loop_id), ..
+            } => format!("Loop {}", loop_id),
+            Self::Assign { index, value } => format!("Assign [{}] = {}", index, value),
+            Self::AssignCurrent { value } => format!("AssignCurrent {}", value),
+            Self::PrintString { value } => format!("PrintString {:?}", value),
+            Self::SetCellPointer { value } => format!("SetCellPointer {}", value),
+            Self::Comment { text } => format!("Comment {:?}", text),
+            _ => self.name().to_string(),
+        }
+    }
+
+    /// Write this node and its children, returning this node's id.
+    fn write_dot(&self, dot: &mut String, next_id: &mut usize) -> usize {
+        let id = *next_id;
+        *next_id += 1;
+
+        let label = self.label().replace('\\', "\\\\").replace('"', "\\\"");
+        dot.push_str(&format!("    n{} [label=\"{}\"];\n", id, label));
+
+        let children = match self {
+            Self::Block { exprs } => exprs.as_slice(),
+            Self::Loop { expr, .. } => match &**expr {
+                Self::Block { exprs } => exprs.as_slice(),
+                expr => std::slice::from_ref(expr),
+            },
+            _ => &[],
+        };
+
+        for (i, child) in children.iter().enumerate() {
+            let child_id = child.write_dot(dot, next_id);
+            dot.push_str(&format!(
+                "    n{} -> n{} [label=\"{}\"];\n",
+                id, child_id, i
+            ));
+        }
+
+        id
+    }
+
+    /// Join several programs into one block, flattening top-level blocks.
+    pub fn concat(programs: &[Expr]) -> Expr {
+        let mut exprs = Vec::new();
+        for program in programs {
+            match program {
+                Self::Block { exprs: children } => exprs.extend(children.iter().cloned()),
+                expr => exprs.push(expr.clone()),
+            }
+        }
+
+        Self::Block { exprs }
+    }
+
+    /// Split counted ops like `Increment { num: 3 }` into that many unit ops.
+    pub fn expand_counts(&self) -> Expr {
+        match self {
+            Self::Block { exprs } => {
+                let mut expanded = Vec::new();
+                for expr in exprs {
+                    match expr.expand_counts() {
+                        Self::Block { exprs } if !expr.is_block() => expanded.extend(exprs),
+                        expr => expanded.push(expr),
+                    }
+                }
+                Self::Block { exprs: expanded }
+            }
+            Self::Loop { expr, id } => Self::Loop {
+                expr: Box::new(expr.expand_counts()),
+                id: *id,
+            },
+            Self::Increment { num } => Self::unit_ops(Self::Increment { num: 1 }, *num),
+            Self::Decrement { num } => Self::unit_ops(Self::Decrement { num: 1 }, *num),
+            Self::ShiftLeft { num } => Self::unit_ops(Self::ShiftLeft { num: 1 }, *num),
+            Self::ShiftRight { num } => Self::unit_ops(Self::ShiftRight { num: 1 }, *num),
+            expr => expr.clone(),
+        }
+    }
+
+    fn unit_ops(op: Expr, num: usize) -> Expr {
+        if num == 1 {
+            op
+        } else {
+            Self::Block {
+                exprs: vec![op; num],
+            }
+        }
+    }
+
+    /// Merge runs of the same counted op in each block, undoing `Expr::expand_counts`.
+    pub fn fold_counts(&self) -> Expr {
+        match self {
+            Self::Block { exprs } => {
+                let mut folded: Vec<Expr> = Vec::new();
+                for expr in exprs.iter().map(Self::fold_counts) {
+                    match (folded.last_mut(), &expr) {
+                        (Some(Self::Increment { num }), Self::Increment { num: n })
+                        | (Some(Self::Decrement { num }), Self::Decrement { num: n })
+                        | (Some(Self::ShiftLeft { num }), Self::ShiftLeft { num: n })
+                        | (Some(Self::ShiftRight { num }), Self::ShiftRight { num: n }) => {
+                            *num += n;
+                        }
+                        _ => folded.push(expr),
+                    }
+                }
+                Self::Block { exprs: folded }
+            }
+            Self::Loop { expr, id } => Self::Loop {
+                expr: Box::new(expr.fold_counts()),
+                id: *id,
+            },
+            expr => expr.clone(),
+        }
+    }
+
+    /// The common divmod loop that `Expr::DivMod` stands for, without loop ids.
+    pub fn div_mod_loop() -> Expr {
+        let mut lexer = crate::Lexer::new(DIV_MOD_LOOP);
+        lexer.lex().unwrap();
+        let expr = Parser::new(lexer.tokens).parse().unwrap();
+
+        match expr {
+            Self::Block { mut exprs } => exprs.remove(0).without_loop_ids(),
+            _ => unreachable!(),
+        }
+    }
+
+    /// A copy of this tree with every loop id set to `None`.
+    pub fn without_loop_ids(&self) -> Expr {
+        match self {
+            Self::Block { exprs } => Self::Block {
+                exprs: exprs.iter().map(Self::without_loop_ids).collect(),
+            },
+            Self::Loop { expr, .. } => Self::Loop {
+                expr: Box::new(expr.without_loop_ids()),
+                id: None,
+            },
+            expr => expr.clone(),
+        }
+    }
+
+    /// Render this tree as plain brainfuck, lowering the nodes that have no brainfuck syntax.
+    ///
+    /// `Assign`, `SetCellPointer`, `PrintString` and `ReadCharForget` need to know where the pointer is,
+    /// and use the first cell past the ones the program touches as scratch space.
+    /// Returns `None` if they appear in a program where `estimate_tape_size` can't find that cell.
+    /// Comments are dropped.
+    pub fn to_pure_brainfuck(&self) -> Option<String> {
+        let scratch = if self.has_absolute_ops() {
+            Some(crate::interpreter::estimate_tape_size(self)?)
+        } else {
+            None
+        };
+
+        let mut out = String::new();
+        self.write_pure_brainfuck(&mut out, &mut 0, scratch);
+        Some(out)
+    }
+
+    fn has_absolute_ops(&self) -> bool {
+        match self {
+            Self::Block { exprs } => exprs.iter().any(Self::has_absolute_ops),
+            Self::Loop { expr, .. } => expr.has_absolute_ops(),
+            Self::Assign { .. }
+            | Self::SetCellPointer { .. }
+            | Self::PrintString { .. }
+            | Self::ReadCharForget => true,
+            _ => false,
+        }
+    }
+
+    /// `pointer` is only accurate if `scratch` is known, which means every loop is balanced.
+    fn write_pure_brainfuck(&self, out: &mut String, pointer: &mut usize, scratch: Option<usize>) {
+        fn move_pointer(out: &mut String, from: usize, to: usize) {
+            if to > from {
+                out.push_str(&">".repeat(to - from));
+            } else {
+                out.push_str(&"<".repeat(from - to));
+            }
+        }
+
+        match self {
+            Self::Block { exprs } => {
+                for expr in exprs {
+                    expr.write_pure_brainfuck(out, pointer, scratch);
+                }
+            }
+            Self::Increment { num } => out.push_str(&"+".repeat(num % 256)),
+            Self::Decrement { num } => out.push_str(&"-".repeat(num % 256)),
+            Self::ShiftLeft { num } => {
+                out.push_str(&"<".repeat(*num));
+                *pointer = pointer.wrapping_sub(*num);
+            }
+            Self::ShiftRight { num } => {
+                out.push_str(&">".repeat(*num));
+                *pointer = pointer.wrapping_add(*num);
+            }
+            Self::PrintChar => out.push('.'),
+            Self::ReadChar => out.push(','),
+            Self::Loop { expr, .. } => {
+                out.push('[');
+                expr.write_pure_brainfuck(out, pointer, scratch);
+                out.push(']');
+            }
+            Self::Assign { index, value } => {
+                move_pointer(out, *pointer, *index);
+                out.push_str("[-]");
+                out.push_str(&"+".repeat(usize::from(*value)));
+                move_pointer(out, *index, *pointer);
+            }
+            Self::AssignCurrent { value } => {
+                out.push_str("[-]");
+                out.push_str(&"+".repeat(usize::from(*value)));
+            }
+            Self::PrintString { value } => {
+                let scratch = scratch.expect("scratch cell");
+                move_pointer(out, *pointer, scratch);
+
+                let mut cell = 0u8;
+                for b in value.bytes() {
+                    let diff = b.wrapping_sub(cell);
+                    if diff <= 128 {
+                        out.push_str(&"+".repeat(usize::from(diff)));
+                    } else {
+                        out.push_str(&"-".repeat(256 - usize::from(diff)));
+                    }
+                    out.push('.');
+                    cell = b;
+                }
+
+                if cell != 0 {
+                    out.push_str("[-]");
+                }
+                move_pointer(out, scratch, *pointer);
+            }
+            Self::SetCellPointer { value } => {
+                move_pointer(out, *pointer, *value);
+                *pointer = *value;
+            }
+            Self::ReadCharForget => {
+                let scratch = scratch.expect("scratch cell");
+                move_pointer(out, *pointer, scratch);
+                out.push_str(",[-]");
+                move_pointer(out, scratch, *pointer);
+            }
+            Self::DivMod => out.push_str(DIV_MOD_LOOP),
+            Self::Comment { .. } => {}
+            Self::Breakpoint => out.push('#'),
+        }
+    }
+
+    /// Whether this is a loop that never terminates once entered.
+    ///
+    /// Conservative: only loops whose body returns the pointer to where it started,
+    /// never writes the starting cell and contains no nested loops are detected.
+    pub fn is_obviously_infinite(&self) -> bool {
+        match self {
+            Self::Loop { expr, .. } => {
+                let mut offset = 0;
+                expr.keeps_cell_at(&mut offset) && offset == 0
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether this expr leaves the loop's starting cell untouched, tracking the pointer in `offset`.
+    fn keeps_cell_at(&self, offset: &mut isize) -> bool {
+        match self {
+            Self::Block { exprs } => exprs.iter().all(|expr| expr.keeps_cell_at(offset)),
+            Self::Increment { .. }
+            | Self::Decrement { .. }
+            | Self::ReadChar
+            | Self::AssignCurrent { .. } => *offset != 0,
+            Self::ShiftRight { num } => {
+                *offset += *num as isize;
+                true
+            }
+            Self::ShiftLeft { num } => {
+                *offset -= *num as isize;
+                true
+            }
+            Self::PrintChar
+            | Self::PrintString { .. }
+            | Self::ReadCharForget
+            | Self::Comment { .. }
+            | Self::Breakpoint => true,
+            Self::Loop { .. }
+            | Self::Assign { .. }
+            | Self::SetCellPointer { .. }
+            | Self::DivMod => false,
+        }
+    }
+
+    fn max_loop_id(&self) -> Option<usize> {
+        match self {
+            Self::Block { exprs } => exprs.iter().filter_map(|expr| expr.max_loop_id()).max(),
+            Self::Loop { expr, id } => expr.max_loop_id().max(*id),
+            _ => None,
+        }
+    }
+
+    /// Compare two exprs after normalizing optimizer-specific representations, assuming 8 bit cells.
+    ///
+    /// Loop ids are ignored. Clear loops become `AssignCurrent`, and prints of a known cell value become `PrintString`.
+    pub fn semantically_eq(&self, other: &Expr) -> bool {
+        self.canonical() == other.canonical()
+    }
+
+    fn canonical(&self) -> Vec<Expr> {
+        let mut exprs = Vec::new();
+        self.canonicalize_into(&mut exprs, &mut None);
+        exprs
+    }
+
+    fn canonicalize_into(&self, out: &mut Vec<Expr>, current: &mut Option<u8>) {
+        match self {
+            Self::Block { exprs } => {
+                for expr in exprs {
+                    expr.canonicalize_into(out, current);
+                }
+            }
+            Self::Loop { expr, .. } => {
+                let body = expr.canonical();
+                match body.as_slice() {
+                    [Self::Increment { num: 1 }] | [Self::Increment { num: 255 }] => {
+                        Self::AssignCurrent { value: 0 }.canonicalize_into(out, current);
+                    }
+                    _ => {
+                        out.push(Self::Loop {
+                            expr: Box::new(Self::Block { exprs: body }),
+                            id: None,
+                        });
+                        *current = Some(0);
+                    }
+                }
+            }
+            Self::Increment { num } => canonical_add(out, current, (*num % 256) as u8),
+            Self::Decrement { num } => {
+                canonical_add(out, current, 0u8.wrapping_sub((*num % 256) as u8))
+            }
+            Self::ShiftRight { num } => canonical_shift(out, current, *num as isize),
+            Self::ShiftLeft { num } => canonical_shift(out, current, -(*num as isize)),
+            Self::AssignCurrent { value } => {
+                if let Some(Self::AssignCurrent { .. }) | Some(Self::Increment { .. }) = out.last()
+                {
+                    out.pop();
+                }
+
+                out.push(Self::AssignCurrent { value: *value });
+                *current = Some(*value);
+            }
+            Self::PrintChar => match current {
+                Some(value) => {
+                    let value = char::from(*value).to_string();
+                    Self::PrintString { value }.canonicalize_into(out, current);
+                }
+                None => out.push(Self::PrintChar),
+            },
+            Self::PrintString { value } => {
+                if value.is_empty() {
+                    return;
+                }
+
+                match out.last_mut() {
+                    Some(Self::PrintString { value: last }) => last.push_str(value),
+                    _ => out.push(Self::PrintString {
+                        value: value.clone(),
+                    }),
+                }
+            }
+            Self::ReadChar | Self::Assign { .. } | Self::SetCellPointer { .. } => {
+                out.push(self.clone());
+                *current = None;
+            }
+            Self::ReadCharForget | Self::Breakpoint => out.push(self.clone()),
+            Self::DivMod => {
+                out.push(Self::DivMod);
+                *current = Some(0);
+            }
+            Self::Comment { .. } => {}
+        }
+    }
+}
+
+fn canonical_add(out: &mut Vec<Expr>, current: &mut Option<u8>, num: u8) {
+    if let Some(value) = current {
+        *value = value.wrapping_add(num);
+        let value = *value;
+        Expr::AssignCurrent { value }.canonicalize_into(out, current);
+        return;
+    }
+
+    let num = match out.last() {
+        Some(Expr::Increment { num: last }) => {
+            let num = (*last as u8).wrapping_add(num);
+            out.pop();
+            num
+        }
+        _ => num,
+    };
+
+    if num != 0 {
+        out.push(Expr::Increment { num: num.into() });
+    }
+}
+
+fn canonical_shift(out: &mut Vec<Expr>, current: &mut Option<u8>, num: isize) {
+    *current = None;
+
+    let num = match out.last() {
+        Some(Expr::ShiftRight { num: last }) => {
+            let num = *last as isize + num;
+            out.pop();
+            num
+        }
+        Some(Expr::ShiftLeft { num: last }) => {
+            let num = num - *last as isize;
+            out.pop();
+            num
+        }
+        _ => num,
+    };
+
+    if num > 0 {
+        out.push(Expr::ShiftRight { num: num as usize });
+    } else if num < 0 {
+        out.push(Expr::ShiftLeft {
+            num: num.unsigned_abs(),
+        });
+    }
+}
+
+/// A step of `Parser::parse_streaming`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseEvent {
+    EnterLoop {
+        id: usize,
+    },
+    ExitLoop {
+        id: usize,
+    },
+    /// An expr that isn't a block or a loop.
+    Op(Expr),
+}
+
+/// The body of a loop at the start of `expr` as text, since such a loop never runs.
+///
+/// Comment text is only available if `expr` was parsed with `Parser::set_keep_comments`,
+/// otherwise only the brainfuck in the loop is returned.
+pub fn collect_leading_comment(expr: &Expr) -> Option<String> {
+    let exprs = match expr {
+        Expr::Block { exprs } => exprs,
+        _ => return None,
+    };
+
+    let body = match exprs
+        .iter()
+        .find(|expr| !matches!(expr, Expr::Comment { .. }))?
+    {
+        Expr::Loop { expr, .. } => expr,
+        _ => return None,
+    };
+
+    let mut comment = String::new();
+    render_comment(body, &mut comment)?;
+    Some(comment)
+}
+
+/// Write `expr` back as the source it was parsed from, or return `None` for optimizer-only nodes.
+fn render_comment(expr: &Expr, out: &mut String) -> Option<()> {
+    match expr {
+        Expr::Block { exprs } => {
+            for expr in exprs {
+                render_comment(expr, out)?;
+            }
+        }
+        Expr::Loop { expr, .. } => {
+            out.push('[');
+            render_comment(expr, out)?;
+            out.push(']');
+        }
+        Expr::Increment { num } => out.push_str(&"+".repeat(*num)),
+        Expr::Decrement { num } => out.push_str(&"-".repeat(*num)),
+        Expr::ShiftLeft { num } => out.push_str(&"<".repeat(*num)),
+        Expr::ShiftRight { num } => out.push_str(&">".repeat(*num)),
+        Expr::PrintChar => out.push('.'),
+        Expr::ReadChar => out.push(','),
+        Expr::Comment { text } => out.push_str(text),
+        Expr::Breakpoint => out.push('#'),
+        Expr::Assign { .. }
+        | Expr::AssignCurrent { .. }
+        | Expr::PrintString { .. }
+        | Expr::SetCellPointer { .. }
+        | Expr::ReadCharForget
+        | Expr::DivMod => return None,
+    }
+
+    Some(())
 }
 
 pub struct Parser {
@@ -72,18 +794,105 @@ pub struct Parser {
     index: usize,
 
     loop_count: usize,
+    next_loop_id: usize,
+    max_depth: Option<usize>,
+    keep_comments: bool,
 }
 
 impl Parser {
+    /// The nesting limit a parser starts with, which is far deeper than real programs go
+    /// but keeps adversarial input from overflowing the stack, since parsing and dropping an `Expr` recurse.
+    pub const DEFAULT_MAX_DEPTH: usize = 1000;
+
     pub fn new(tokens: Vec<Token>) -> Self {
         Self {
             tokens,
             index: 0,
             loop_count: 0,
+            next_loop_id: 0,
+            max_depth: Some(Self::DEFAULT_MAX_DEPTH),
+            keep_comments: false,
         }
     }
 
+    /// Limit how deeply loops may be nested. Defaults to `Parser::DEFAULT_MAX_DEPTH`.
+    ///
+    /// Deeper loops fail with `ParseError::TooDeep`. `None` turns the guard off, so deep enough nesting overflows the stack.
+    pub fn set_max_depth(&mut self, depth: Option<usize>) {
+        self.max_depth = depth;
+    }
+
+    /// Keep text that isn't brainfuck as `Expr::Comment` nodes instead of dropping it.
+    pub fn set_keep_comments(&mut self, keep_comments: bool) {
+        self.keep_comments = keep_comments;
+    }
+
+    /// Parse without building a tree, passing each loop boundary and op to `f` in program order.
+    ///
+    /// Loops that are still open at the end of the tokens are closed, like `Parser::parse` does.
+    pub fn parse_streaming<F: FnMut(ParseEvent)>(&mut self, mut f: F) -> Result<(), ParseError> {
+        let mut open = Vec::new();
+
+        while self.index < self.tokens.len() {
+            let op = match &self.tokens[self.index].data {
+                TokenData::Increment(num) => Expr::Increment { num: *num },
+                TokenData::Decrement(num) => Expr::Decrement { num: *num },
+                TokenData::ShiftLeft(num) => Expr::ShiftLeft { num: *num },
+                TokenData::ShiftRight(num) => Expr::ShiftRight { num: *num },
+                TokenData::Print => Expr::PrintChar,
+                TokenData::Read => Expr::ReadChar,
+                TokenData::Breakpoint => Expr::Breakpoint,
+                TokenData::StartLoop => {
+                    let depth = self.loop_count + open.len();
+                    if let Some(max_depth) = self.max_depth {
+                        if depth >= max_depth {
+                            return Err(ParseError::TooDeep { depth: depth + 1 });
+                        }
+                    }
+
+                    let id = self.next_loop_id;
+                    self.next_loop_id += 1;
+                    open.push(id);
+                    self.index += 1;
+
+                    f(ParseEvent::EnterLoop { id });
+                    continue;
+                }
+                TokenData::EndLoop => {
+                    self.index += 1;
+                    if let Some(id) = open.pop() {
+                        f(ParseEvent::ExitLoop { id });
+                    }
+                    continue;
+                }
+                TokenData::Other(text) => {
+                    self.index += 1;
+                    if self.keep_comments {
+                        f(ParseEvent::Op(Expr::Comment { text: text.clone() }));
+                    }
+                    continue;
+                }
+            };
+
+            self.index += 1;
+            f(ParseEvent::Op(op));
+        }
+
+        while let Some(id) = open.pop() {
+            f(ParseEvent::ExitLoop { id });
+        }
+
+        Ok(())
+    }
+
     pub fn parse(&mut self) -> Result<Expr, ParseError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("parse", tokens = self.tokens.len()).entered();
+
+        self.parse_block()
+    }
+
+    fn parse_block(&mut self) -> Result<Expr, ParseError> {
         let mut exprs = Vec::new();
 
         while self.index < self.tokens.len() {
@@ -112,12 +921,29 @@ impl Parser {
                     exprs.push(Expr::ReadChar);
                     self.index += 1;
                 }
+                TokenData::Breakpoint => {
+                    exprs.push(Expr::Breakpoint);
+                    self.index += 1;
+                }
                 TokenData::StartLoop => {
+                    if let Some(max_depth) = self.max_depth {
+                        if self.loop_count >= max_depth {
+                            return Err(ParseError::TooDeep {
+                                depth: self.loop_count + 1,
+                            });
+                        }
+                    }
+
+                    let id = self.next_loop_id;
+                    self.next_loop_id += 1;
                     self.loop_count += 1;
                     self.index += 1;
 
-                    let expr = self.parse()?;
-                    exprs.push(Expr::Loop { expr: expr.into() });
+                    let expr = self.parse_block()?;
+                    exprs.push(Expr::Loop {
+                        expr: expr.into(),
+                        id: Some(id),
+                    });
                 }
                 TokenData::EndLoop => {
                     self.index += 1;
@@ -126,7 +952,10 @@ impl Parser {
                         break;
                     }
                 }
-                TokenData::Other(_) => {
+                TokenData::Other(ref text) => {
+                    if self.keep_comments {
+                        exprs.push(Expr::Comment { text: text.clone() });
+                    }
                     self.index += 1;
                 }
             }
@@ -134,4 +963,423 @@ impl Parser {
 
         Ok(Expr::Block { exprs })
     }
+
+    /// Reparse `tokens` after an edit, reusing top-level exprs of `prev` that lie outside of `changed_range`.
+    ///
+    /// Tokens before `changed_range` must match the start of the tokens `prev` was parsed from,
+    /// and tokens after it must match their end. Falls back to a full reparse if the brackets are unbalanced.
+    pub fn parse_incremental(
+        tokens: &[Token],
+        prev: &Expr,
+        changed_range: Range<usize>,
+    ) -> Result<Expr, ParseError> {
+        let (prev_exprs, segments) = match (prev, top_level_segments(tokens)) {
+            (Expr::Block { exprs }, Some(segments)) => (exprs, segments),
+            _ => return Parser::new(tokens.to_vec()).parse(),
+        };
+
+        let prefix = segments
+            .iter()
+            .take_while(|segment| segment.end <= changed_range.start)
+            .count();
+        let suffix = segments[prefix..]
+            .iter()
+            .rev()
+            .take_while(|segment| segment.start >= changed_range.end)
+            .count();
+
+        if prefix + suffix > prev_exprs.len() {
+            return Parser::new(tokens.to_vec()).parse();
+        }
+
+        let mut parser = Parser::new(Vec::new());
+        parser.next_loop_id = prev.max_loop_id().map_or(0, |id| id + 1);
+
+        let mut exprs = Vec::with_capacity(segments.len());
+        exprs.extend(prev_exprs[..prefix].iter().cloned());
+        for segment in &segments[prefix..segments.len() - suffix] {
+            parser.tokens = tokens[segment.clone()].to_vec();
+            parser.index = 0;
+            if let Expr::Block { exprs: new_exprs } = parser.parse()? {
+                exprs.extend(new_exprs);
+            }
+        }
+        exprs.extend(prev_exprs[prev_exprs.len() - suffix..].iter().cloned());
+
+        Ok(Expr::Block { exprs })
+    }
+}
+
+/// Split tokens into the ranges that each produce one top-level expr, or `None` if the brackets are unbalanced.
+fn top_level_segments(tokens: &[Token]) -> Option<Vec<Range<usize>>> {
+    let mut segments = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, token) in tokens.iter().enumerate() {
+        match token.data {
+            TokenData::StartLoop => {
+                if depth == 0 {
+                    start = i;
+                }
+                depth += 1;
+            }
+            TokenData::EndLoop => {
+                if depth == 0 {
+                    return None;
+                }
+
+                depth -= 1;
+                if depth == 0 {
+                    segments.push(start..i + 1);
+                }
+            }
+            TokenData::Other(_) => {}
+            _ => {
+                if depth == 0 {
+                    segments.push(i..i + 1);
+                }
+            }
+        }
+    }
+
+    if depth != 0 {
+        return None;
+    }
+
+    Some(segments)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Lexer;
+
+    fn lex(data: &str) -> Vec<Token> {
+        let mut l = Lexer::new(data);
+        l.lex().unwrap();
+        l.tokens
+    }
+
+    #[test]
+    fn max_reads() {
+        let parse = |data| Parser::new(lex(data)).parse().unwrap();
+
+        assert_eq!(parse(",,,").max_reads(), Some(3));
+        assert_eq!(parse(",[-]>,[+.]").max_reads(), Some(2));
+        assert_eq!(parse("").max_reads(), Some(0));
+        assert_eq!(parse("[,]").max_reads(), None);
+        assert_eq!(parse(",+[>[,]]").max_reads(), None);
+        assert_eq!(Expr::ReadCharForget.max_reads(), Some(1));
+    }
+
+    #[test]
+    fn first_and_last_op() {
+        let parse = |data| Parser::new(lex(data)).parse().unwrap();
+
+        assert_eq!(parse("+>.").first_op(), Some(&Expr::Increment { num: 1 }));
+        assert_eq!(parse("+>.").last_op(), Some(&Expr::PrintChar));
+        assert_eq!(parse("").first_op(), None);
+        assert!(parse("[-]+").first_op().unwrap().is_loop());
+
+        let nested = Expr::Block {
+            exprs: vec![
+                Expr::Block { exprs: vec![] },
+                Expr::Block {
+                    exprs: vec![Expr::ReadChar, Expr::PrintChar],
+                },
+                Expr::Block { exprs: vec![] },
+            ],
+        };
+        assert_eq!(nested.first_op(), Some(&Expr::ReadChar));
+        assert_eq!(nested.last_op(), Some(&Expr::PrintChar));
+
+        let mut o = crate::Optimizer::new(parse(include_str!("../test_data/hello_world1.bf")));
+        o.add_pass(crate::ZeroLoopOptimizer);
+        o.add_pass(crate::SpecExecOptimizer::default());
+        o.optimize();
+        assert!(matches!(
+            o.expr.last_op(),
+            Some(Expr::PrintString { .. }) | Some(Expr::PrintChar)
+        ));
+    }
+
+    #[test]
+    fn to_pure_brainfuck() {
+        let parse = |data: &str| Parser::new(lex(data)).parse().unwrap();
+        let run = |expr: &Expr, input: &[u8]| {
+            let mut vm = crate::Interpreter::new(crate::RecordingHandler::new());
+            vm.run_with_input(expr, input).unwrap();
+            vm.handler.output().to_vec()
+        };
+
+        for (program, input) in [
+            (include_str!("../test_data/aids.bf"), &b""[..]),
+            (include_str!("../test_data/hello_world1.bf"), b""),
+            ("+++++++[>++++++++++<-]>.,>,<[.,]>.", b"ab"),
+        ] {
+            let mut o = crate::Optimizer::new(parse(program));
+            o.add_passes(crate::OptLevel::Aggressive);
+            o.optimize();
+
+            let pure = o.expr.to_pure_brainfuck().unwrap();
+            assert!(pure.chars().all(|c| "+-<>[].,".contains(c)));
+            assert_eq!(run(&parse(&pure), input), run(&o.expr, input));
+        }
+
+        let expr = Expr::Block {
+            exprs: vec![
+                Expr::PrintString { value: "A".into() },
+                Expr::Assign { index: 1, value: 2 },
+                Expr::SetCellPointer { value: 1 },
+                Expr::ReadCharForget,
+                Expr::DivMod,
+                Expr::Comment { text: "x".into() },
+            ],
+        };
+        assert_eq!(
+            expr.to_pure_brainfuck().unwrap(),
+            format!(
+                "{}{}.[-]{}>[-]++<>{},[-]{}{}",
+                ">".repeat(8),
+                "+".repeat(65),
+                "<".repeat(8),
+                ">".repeat(7),
+                "<".repeat(7),
+                DIV_MOD_LOOP
+            )
+        );
+
+        assert_eq!(parse("[>]+[-]").to_pure_brainfuck().unwrap(), "[>]+[-]");
+        let mut unbalanced = parse("[>]");
+        if let Expr::Block { exprs } = &mut unbalanced {
+            exprs.push(Expr::PrintString { value: "a".into() });
+        }
+        assert_eq!(unbalanced.to_pure_brainfuck(), None);
+    }
+
+    #[test]
+    fn expand_counts() {
+        let parse = |data| Parser::new(lex(data)).parse().unwrap();
+
+        let expr = parse("+++");
+        let expanded = expr.expand_counts();
+        assert_eq!(
+            expanded,
+            Expr::Block {
+                exprs: vec![Expr::Increment { num: 1 }; 3],
+            }
+        );
+        assert_eq!(expanded.fold_counts(), expr);
+
+        let expr = parse(include_str!("../test_data/squares.bf"));
+        let expanded = expr.expand_counts();
+        assert_ne!(expanded, expr);
+        assert_eq!(expanded.fold_counts(), expr);
+        assert_eq!(
+            Expr::ShiftRight { num: 0 }.expand_counts(),
+            Expr::Block { exprs: vec![] }
+        );
+    }
+
+    #[test]
+    fn leading_comment() {
+        let parse = |data| {
+            let mut parser = Parser::new(lex(data));
+            parser.set_keep_comments(true);
+            parser.parse().unwrap()
+        };
+
+        let expr = parse("[this is a comment]+.");
+        assert_eq!(
+            collect_leading_comment(&expr).as_deref(),
+            Some("this is a comment")
+        );
+        assert_eq!(
+            collect_leading_comment(&parse("\n[ prints 1, [nested] ]\n+.")).as_deref(),
+            Some(" prints 1, [nested] ")
+        );
+        assert_eq!(collect_leading_comment(&parse("+[not a comment]")), None);
+        assert_eq!(
+            collect_leading_comment(&Parser::new(lex("[a+b]")).parse().unwrap()).as_deref(),
+            Some("+")
+        );
+
+        let mut vm = crate::Interpreter::new(crate::interpreter::RecordingHandler::new());
+        vm.run(&expr).unwrap();
+        assert_eq!(vm.handler.output(), [1]);
+    }
+
+    #[test]
+    fn parse_streaming() {
+        let mut events = Vec::new();
+        Parser::new(lex("[+]"))
+            .parse_streaming(|event| events.push(event))
+            .unwrap();
+        assert_eq!(
+            events,
+            [
+                ParseEvent::EnterLoop { id: 0 },
+                ParseEvent::Op(Expr::Increment { num: 1 }),
+                ParseEvent::ExitLoop { id: 0 },
+            ]
+        );
+
+        let mut events = Vec::new();
+        Parser::new(lex("-[.[,]]>["))
+            .parse_streaming(|event| events.push(event))
+            .unwrap();
+        assert_eq!(
+            events,
+            [
+                ParseEvent::Op(Expr::Decrement { num: 1 }),
+                ParseEvent::EnterLoop { id: 0 },
+                ParseEvent::Op(Expr::PrintChar),
+                ParseEvent::EnterLoop { id: 1 },
+                ParseEvent::Op(Expr::ReadChar),
+                ParseEvent::ExitLoop { id: 1 },
+                ParseEvent::ExitLoop { id: 0 },
+                ParseEvent::Op(Expr::ShiftRight { num: 1 }),
+                ParseEvent::EnterLoop { id: 2 },
+                ParseEvent::ExitLoop { id: 2 },
+            ]
+        );
+
+        let mut parser = Parser::new(lex("[[[-]]]"));
+        parser.set_max_depth(Some(2));
+        assert!(matches!(
+            parser.parse_streaming(|_| {}),
+            Err(ParseError::TooDeep { depth: 3 })
+        ));
+    }
+
+    #[test]
+    fn max_depth() {
+        let mut parser = Parser::new(lex("[[[-]]]"));
+        parser.set_max_depth(Some(3));
+        assert!(parser.parse().is_ok());
+
+        let mut parser = Parser::new(lex("[[[-]]][[[[-]]]]"));
+        parser.set_max_depth(Some(3));
+        assert!(matches!(
+            parser.parse(),
+            Err(ParseError::TooDeep { depth: 4 })
+        ));
+
+        let mut parser = Parser::new(lex(&format!("{}{}", "[".repeat(2000), "]".repeat(2000))));
+        parser.set_max_depth(None);
+        assert!(parser.parse().is_ok());
+
+        let data = "[".repeat(100_000);
+        let mut parser = Parser::new(lex(&data));
+        parser.set_max_depth(Some(100));
+        assert!(matches!(
+            parser.parse(),
+            Err(ParseError::TooDeep { depth: 101 })
+        ));
+
+        // Without a limit this would overflow the stack
+        assert!(matches!(
+            Parser::new(lex(&data)).parse(),
+            Err(ParseError::TooDeep { depth: 1001 })
+        ));
+
+        let depth = Parser::DEFAULT_MAX_DEPTH;
+        let data = format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(Parser::new(lex(&data)).parse().is_ok());
+    }
+
+    #[test]
+    fn loop_ids() {
+        let expr = Parser::new(lex("[][[]]")).parse().unwrap();
+        let ids: Vec<_> = match &expr {
+            Expr::Block { exprs } => exprs
+                .iter()
+                .map(|expr| match expr {
+                    Expr::Loop { id, .. } => *id,
+                    _ => panic!("expected loop"),
+                })
+                .collect(),
+            _ => panic!("expected block"),
+        };
+
+        assert_eq!(ids, [Some(0), Some(1)]);
+        assert_eq!(expr.max_loop_id(), Some(2));
+    }
+
+    #[test]
+    fn to_dot() {
+        let dot = Parser::new(lex("[+]")).parse().unwrap().to_dot();
+        assert_eq!(
+            dot,
+            "digraph {\n    n0 [label=\"Block\"];\n    n1 [label=\"Loop 0\"];\n    n2 [label=\"Increment 1\"];\n    n1 -> n2 [label=\"0\"];\n    n0 -> n1 [label=\"0\"];\n}\n"
+        );
+
+        let dot = Expr::PrintString {
+            value: "\"hi\"".into(),
+        }
+        .to_dot();
+        assert!(dot.contains(r#"n0 [label="PrintString \"\\\"hi\\\"\""];"#));
+    }
+
+    #[test]
+    fn op_histogram() {
+        let expr = Parser::new(lex(include_str!("../test_data/hello_world1.bf")))
+            .parse()
+            .unwrap();
+        let histogram = expr.op_histogram();
+
+        assert_eq!(histogram["Increment"], 19);
+        assert_eq!(histogram["Decrement"], 5);
+        assert_eq!(histogram["ShiftRight"], 10);
+        assert_eq!(histogram["ShiftLeft"], 2);
+        assert_eq!(histogram["PrintChar"], 13);
+        assert_eq!(histogram["Loop"], 1);
+        assert_eq!(histogram["Block"], 2);
+        assert_eq!(histogram.get("ReadChar"), None);
+    }
+
+    #[test]
+    fn semantically_eq() {
+        let clear_loop = Parser::new(lex("[-]")).parse().unwrap();
+        let assign = Expr::Block {
+            exprs: vec![Expr::AssignCurrent { value: 0 }],
+        };
+
+        assert_ne!(clear_loop, assign);
+        assert!(clear_loop.semantically_eq(&assign));
+
+        let print_chars = Parser::new(lex("[-]++..")).parse().unwrap();
+        let print_string = Expr::Block {
+            exprs: vec![
+                Expr::AssignCurrent { value: 2 },
+                Expr::PrintString {
+                    value: "\u{2}\u{2}".into(),
+                },
+            ],
+        };
+        assert!(print_chars.semantically_eq(&print_string));
+        assert!(!print_chars.semantically_eq(&assign));
+    }
+
+    #[test]
+    fn parse_incremental_reuses_siblings() {
+        let prev = Parser::new(lex("+[>+<-][->+<].")).parse().unwrap();
+
+        let tokens = lex("+[>+<-][-->>+<<].");
+        let expr = Parser::parse_incremental(&tokens, &prev, 8..13).unwrap();
+
+        assert!(expr.semantically_eq(&Parser::new(tokens).parse().unwrap()));
+        assert_eq!(expr.max_loop_id(), Some(2));
+
+        match (&expr, &prev) {
+            (Expr::Block { exprs }, Expr::Block { exprs: prev_exprs }) => {
+                assert_eq!(exprs.len(), prev_exprs.len());
+                assert_eq!(exprs[1], prev_exprs[1]);
+                assert_ne!(exprs[2], prev_exprs[2]);
+                assert_eq!(exprs[3], prev_exprs[3]);
+            }
+            _ => panic!("expected blocks"),
+        }
+    }
 }
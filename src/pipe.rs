@@ -0,0 +1,255 @@
+use crate::interpreter::{
+    BreakpointState,
+    Handler,
+    TapeView,
+};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io,
+    rc::Rc,
+};
+
+#[derive(Default)]
+struct PipeBuffer {
+    bytes: VecDeque<u8>,
+    closed: bool,
+}
+
+/// Connect two handlers so everything written through the first is read through the second.
+///
+/// `upstream` still answers the writer's reads and `downstream` still gets the reader's output.
+/// Run both with `Interpreter::step` to have the downstream interpreter block until upstream output arrives.
+pub fn pipe<A: Handler, B: Handler>(upstream: A, downstream: B) -> (PipeWriter<A>, PipeReader<B>) {
+    let buffer = Rc::new(RefCell::new(PipeBuffer::default()));
+    (
+        PipeWriter {
+            inner: upstream,
+            buffer: buffer.clone(),
+        },
+        PipeReader {
+            inner: downstream,
+            buffer,
+        },
+    )
+}
+
+/// The writing end of a `pipe`. The pipe is closed once its program finishes, when the handler is finalized.
+pub struct PipeWriter<H> {
+    inner: H,
+    buffer: Rc<RefCell<PipeBuffer>>,
+}
+
+impl<H: Handler> PipeWriter<H> {
+    /// Close the pipe early. The reader gets 0s once the bytes already written run out.
+    pub fn close(&mut self) {
+        self.buffer.borrow_mut().closed = true;
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    /// Close the pipe and return the wrapped handler.
+    pub fn into_inner(mut self) -> H {
+        self.close();
+        self.inner
+    }
+}
+
+impl<H: Handler> Handler for PipeWriter<H> {
+    fn read_char(&mut self) -> io::Result<u8> {
+        self.inner.read_char()
+    }
+
+    fn input_ready(&mut self) -> bool {
+        self.inner.input_ready()
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.buffer.borrow_mut().bytes.push_back(c);
+        Ok(())
+    }
+
+    fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
+        self.buffer.borrow_mut().bytes.extend(s);
+        Ok(())
+    }
+
+    fn mem_read(&mut self, index: usize) {
+        self.inner.mem_read(index);
+    }
+
+    fn loop_iteration(&mut self, pointer: usize, cell: u8) {
+        self.inner.loop_iteration(pointer, cell);
+    }
+
+    fn on_loop_enter(&mut self, id: Option<usize>) {
+        self.inner.on_loop_enter(id);
+    }
+
+    fn on_loop_exit(&mut self, id: Option<usize>) {
+        self.inner.on_loop_exit(id);
+    }
+
+    fn on_overflow(&mut self, index: usize) {
+        self.inner.on_overflow(index);
+    }
+
+    fn finalize(&mut self, cells: &[u8], pointer: usize) {
+        self.close();
+        self.inner.finalize(cells, pointer);
+    }
+
+    fn on_breakpoint(&mut self, state: &BreakpointState) {
+        self.inner.on_breakpoint(state);
+    }
+
+    fn on_tick(&mut self, tape: &mut TapeView) {
+        self.inner.on_tick(tape);
+    }
+}
+
+/// The reading end of a `pipe`.
+///
+/// Reads produce 0 once the pipe is closed and empty. Reading from an open, empty pipe outside of
+/// `Interpreter::step` fails with `io::ErrorKind::WouldBlock`.
+pub struct PipeReader<H> {
+    inner: H,
+    buffer: Rc<RefCell<PipeBuffer>>,
+}
+
+impl<H: Handler> PipeReader<H> {
+    /// The number of bytes written but not read yet.
+    pub fn available(&self) -> usize {
+        self.buffer.borrow().bytes.len()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.buffer.borrow().closed
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+}
+
+impl<H: Handler> Handler for PipeReader<H> {
+    fn read_char(&mut self) -> io::Result<u8> {
+        let mut buffer = self.buffer.borrow_mut();
+        match buffer.bytes.pop_front() {
+            Some(c) => Ok(c),
+            None if buffer.closed => Ok(0),
+            None => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "read from an empty pipe",
+            )),
+        }
+    }
+
+    fn input_ready(&mut self) -> bool {
+        let buffer = self.buffer.borrow();
+        !buffer.bytes.is_empty() || buffer.closed
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.inner.write_char(c)
+    }
+
+    fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
+        self.inner.write_str(s)
+    }
+
+    fn mem_read(&mut self, index: usize) {
+        self.inner.mem_read(index);
+    }
+
+    fn loop_iteration(&mut self, pointer: usize, cell: u8) {
+        self.inner.loop_iteration(pointer, cell);
+    }
+
+    fn on_loop_enter(&mut self, id: Option<usize>) {
+        self.inner.on_loop_enter(id);
+    }
+
+    fn on_loop_exit(&mut self, id: Option<usize>) {
+        self.inner.on_loop_exit(id);
+    }
+
+    fn on_overflow(&mut self, index: usize) {
+        self.inner.on_overflow(index);
+    }
+
+    fn finalize(&mut self, cells: &[u8], pointer: usize) {
+        self.inner.finalize(cells, pointer);
+    }
+
+    fn on_breakpoint(&mut self, state: &BreakpointState) {
+        self.inner.on_breakpoint(state);
+    }
+
+    fn on_tick(&mut self, tape: &mut TapeView) {
+        self.inner.on_tick(tape);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        interpreter::{
+            DefaultHandler,
+            RuntimeError,
+            RuntimeErrorKind,
+        },
+        test_util::parse,
+        *,
+    };
+
+    #[test]
+    fn step_through_pipe() {
+        let (writer, reader) = pipe(SliceInputHandler::new(b"HAL"), RecordingHandler::new());
+        let mut shift = Interpreter::new(writer);
+        let shift_program = Program::compile(&parse(",[+.,]"));
+        let mut reverse = Interpreter::new(reader);
+        let reverse_program = Program::compile(&parse(">,[>,]<[.<]"));
+
+        let mut shift_done = false;
+        let mut blocked = 0;
+        loop {
+            if !shift_done {
+                shift_done = shift.step(&shift_program).unwrap() == StepStatus::Done;
+            }
+
+            match reverse.step(&reverse_program).unwrap() {
+                StepStatus::Done => break,
+                StepStatus::Blocked => blocked += 1,
+                StepStatus::Running => {}
+            }
+        }
+
+        assert!(blocked > 0);
+        assert_eq!(reverse.handler.inner().output(), b"MBI");
+    }
+
+    #[test]
+    fn empty_pipe() {
+        let (mut writer, reader) = pipe(DefaultHandler, RecordingHandler::new());
+        let mut vm = Interpreter::new(reader);
+        assert!(matches!(
+            vm.run(&parse(",.")).as_ref().map_err(RuntimeError::kind),
+            Err(RuntimeErrorKind::Io(e)) if e.kind() == io::ErrorKind::WouldBlock
+        ));
+
+        writer.write_str(b"hi").unwrap();
+        writer.close();
+        vm.reset();
+        vm.run(&parse(",.,.,.")).unwrap();
+        assert_eq!(vm.handler.inner().output(), b"hi\0");
+        assert!(vm.handler.is_closed());
+    }
+}
@@ -0,0 +1,393 @@
+use crate::parser::Expr;
+use std::convert::TryFrom;
+
+/// A single instruction of a flattened `Program`.
+///
+/// Loops become a pair of jumps holding the index of their matching op.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Increment(usize),
+    Decrement(usize),
+    ShiftLeft(usize),
+    ShiftRight(usize),
+    PrintChar,
+    ReadChar,
+    /// Jump past `end` if the current cell is 0.
+    LoopStart {
+        end: usize,
+        id: Option<usize>,
+    },
+    /// Jump back past `start` if the current cell isn't 0.
+    LoopEnd {
+        start: usize,
+        id: Option<usize>,
+    },
+
+    Assign {
+        index: usize,
+        value: u8,
+    },
+    AssignCurrent(u8),
+    PrintString(String),
+    SetCellPointer(usize),
+    ReadCharForget,
+    DivMod,
+    Breakpoint,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    /// The data doesn't start with the expected header.
+    BadHeader,
+    UnexpectedEof,
+    UnknownTag(u8),
+    /// A number didn't fit in its type.
+    Overflow,
+    InvalidUtf8,
+    /// The loop op at `index` doesn't point at a matching op.
+    InvalidJump {
+        index: usize,
+    },
+}
+
+const MAGIC: &[u8; 4] = b"BFP\x01";
+
+const TAG_INCREMENT: u8 = 0;
+const TAG_DECREMENT: u8 = 1;
+const TAG_SHIFT_LEFT: u8 = 2;
+const TAG_SHIFT_RIGHT: u8 = 3;
+const TAG_PRINT_CHAR: u8 = 4;
+const TAG_READ_CHAR: u8 = 5;
+const TAG_LOOP_START: u8 = 6;
+const TAG_LOOP_END: u8 = 7;
+const TAG_ASSIGN: u8 = 8;
+const TAG_ASSIGN_CURRENT: u8 = 9;
+const TAG_PRINT_STRING: u8 = 10;
+const TAG_SET_CELL_POINTER: u8 = 11;
+const TAG_READ_CHAR_FORGET: u8 = 12;
+const TAG_DIV_MOD: u8 = 13;
+const TAG_BREAKPOINT: u8 = 14;
+
+/// A flat list of ops compiled from an `Expr`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Program {
+    pub ops: Vec<Op>,
+}
+
+impl Program {
+    pub fn compile(expr: &Expr) -> Self {
+        let mut program = Self::default();
+        program.compile_expr(expr);
+        program
+    }
+
+    fn compile_expr(&mut self, expr: &Expr) {
+        let op = match expr {
+            Expr::Block { exprs } => {
+                for expr in exprs {
+                    self.compile_expr(expr);
+                }
+                return;
+            }
+            Expr::Loop { expr, id } => {
+                let start = self.ops.len();
+                self.ops.push(Op::LoopStart { end: 0, id: *id });
+                self.compile_expr(expr);
+
+                let end = self.ops.len();
+                self.ops[start] = Op::LoopStart { end, id: *id };
+                Op::LoopEnd { start, id: *id }
+            }
+            Expr::Increment { num } => Op::Increment(*num),
+            Expr::Decrement { num } => Op::Decrement(*num),
+            Expr::ShiftLeft { num } => Op::ShiftLeft(*num),
+            Expr::ShiftRight { num } => Op::ShiftRight(*num),
+            Expr::PrintChar => Op::PrintChar,
+            Expr::ReadChar => Op::ReadChar,
+            Expr::Assign { index, value } => Op::Assign {
+                index: *index,
+                value: *value,
+            },
+            Expr::AssignCurrent { value } => Op::AssignCurrent(*value),
+            Expr::PrintString { value } => Op::PrintString(value.clone()),
+            Expr::SetCellPointer { value } => Op::SetCellPointer(*value),
+            Expr::ReadCharForget => Op::ReadCharForget,
+            Expr::DivMod => Op::DivMod,
+            Expr::Breakpoint => Op::Breakpoint,
+            Expr::Comment { .. } => return,
+        };
+
+        self.ops.push(op);
+    }
+
+    /// Encode this program as a header followed by a tag byte and LEB128 operands for each op.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        write_varint(&mut bytes, self.ops.len() as u64);
+
+        for op in &self.ops {
+            match op {
+                Op::Increment(num) => write_op(&mut bytes, TAG_INCREMENT, *num),
+                Op::Decrement(num) => write_op(&mut bytes, TAG_DECREMENT, *num),
+                Op::ShiftLeft(num) => write_op(&mut bytes, TAG_SHIFT_LEFT, *num),
+                Op::ShiftRight(num) => write_op(&mut bytes, TAG_SHIFT_RIGHT, *num),
+                Op::PrintChar => bytes.push(TAG_PRINT_CHAR),
+                Op::ReadChar => bytes.push(TAG_READ_CHAR),
+                Op::LoopStart { end, id } => {
+                    write_op(&mut bytes, TAG_LOOP_START, *end);
+                    write_id(&mut bytes, *id);
+                }
+                Op::LoopEnd { start, id } => {
+                    write_op(&mut bytes, TAG_LOOP_END, *start);
+                    write_id(&mut bytes, *id);
+                }
+                Op::Assign { index, value } => {
+                    write_op(&mut bytes, TAG_ASSIGN, *index);
+                    bytes.push(*value);
+                }
+                Op::AssignCurrent(value) => {
+                    bytes.push(TAG_ASSIGN_CURRENT);
+                    bytes.push(*value);
+                }
+                Op::PrintString(value) => {
+                    write_op(&mut bytes, TAG_PRINT_STRING, value.len());
+                    bytes.extend_from_slice(value.as_bytes());
+                }
+                Op::SetCellPointer(value) => write_op(&mut bytes, TAG_SET_CELL_POINTER, *value),
+                Op::ReadCharForget => bytes.push(TAG_READ_CHAR_FORGET),
+                Op::DivMod => bytes.push(TAG_DIV_MOD),
+                Op::Breakpoint => bytes.push(TAG_BREAKPOINT),
+            }
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(DecodeError::BadHeader);
+        }
+
+        let len = reader.read_usize()?;
+        // Every op is at least one byte, so don't trust a length that can't fit
+        let mut ops = Vec::with_capacity(len.min(bytes.len()));
+        for _ in 0..len {
+            let op = match reader.read_u8()? {
+                TAG_INCREMENT => Op::Increment(reader.read_usize()?),
+                TAG_DECREMENT => Op::Decrement(reader.read_usize()?),
+                TAG_SHIFT_LEFT => Op::ShiftLeft(reader.read_usize()?),
+                TAG_SHIFT_RIGHT => Op::ShiftRight(reader.read_usize()?),
+                TAG_PRINT_CHAR => Op::PrintChar,
+                TAG_READ_CHAR => Op::ReadChar,
+                TAG_LOOP_START => Op::LoopStart {
+                    end: reader.read_usize()?,
+                    id: reader.read_id()?,
+                },
+                TAG_LOOP_END => Op::LoopEnd {
+                    start: reader.read_usize()?,
+                    id: reader.read_id()?,
+                },
+                TAG_ASSIGN => Op::Assign {
+                    index: reader.read_usize()?,
+                    value: reader.read_u8()?,
+                },
+                TAG_ASSIGN_CURRENT => Op::AssignCurrent(reader.read_u8()?),
+                TAG_PRINT_STRING => {
+                    let len = reader.read_usize()?;
+                    let value = std::str::from_utf8(reader.take(len)?)
+                        .map_err(|_| DecodeError::InvalidUtf8)?;
+                    Op::PrintString(value.to_string())
+                }
+                TAG_SET_CELL_POINTER => Op::SetCellPointer(reader.read_usize()?),
+                TAG_READ_CHAR_FORGET => Op::ReadCharForget,
+                TAG_DIV_MOD => Op::DivMod,
+                TAG_BREAKPOINT => Op::Breakpoint,
+                tag => return Err(DecodeError::UnknownTag(tag)),
+            };
+            ops.push(op);
+        }
+
+        let program = Self { ops };
+        program.validate_jumps()?;
+        Ok(program)
+    }
+
+    fn validate_jumps(&self) -> Result<(), DecodeError> {
+        for (index, op) in self.ops.iter().enumerate() {
+            let valid = match op {
+                Op::LoopStart { end, .. } => {
+                    *end > index
+                        && matches!(self.ops.get(*end), Some(Op::LoopEnd { start, .. }) if *start == index)
+                }
+                Op::LoopEnd { start, .. } => {
+                    *start < index
+                        && matches!(self.ops.get(*start), Some(Op::LoopStart { end, .. }) if *end == index)
+                }
+                _ => true,
+            };
+
+            if !valid {
+                return Err(DecodeError::InvalidJump { index });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn write_varint(bytes: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn write_op(bytes: &mut Vec<u8>, tag: u8, operand: usize) {
+    bytes.push(tag);
+    write_varint(bytes, operand as u64);
+}
+
+/// Loop ids are stored offset by one, so 0 means no id.
+fn write_id(bytes: &mut Vec<u8>, id: Option<usize>) {
+    write_varint(bytes, id.map_or(0, |id| id as u64 + 1));
+}
+
+pub(crate) struct Reader<'a> {
+    pub(crate) bytes: &'a [u8],
+    pub(crate) pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or(DecodeError::UnexpectedEof)?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub(crate) fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn read_varint(&mut self) -> Result<u64, DecodeError> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_u8()?;
+            let bits = u64::from(byte & 0x7f);
+            if shift == 63 && bits > 1 {
+                return Err(DecodeError::Overflow);
+            }
+
+            n |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+
+        Err(DecodeError::Overflow)
+    }
+
+    pub(crate) fn read_usize(&mut self) -> Result<usize, DecodeError> {
+        usize::try_from(self.read_varint()?).map_err(|_| DecodeError::Overflow)
+    }
+
+    fn read_id(&mut self) -> Result<Option<usize>, DecodeError> {
+        match self.read_usize()? {
+            0 => Ok(None),
+            id => Ok(Some(id - 1)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        test_util::parse,
+        *,
+    };
+
+    #[test]
+    fn compile_loops() {
+        let program = Program::compile(&parse("+[->[-]<]."));
+        assert_eq!(
+            program.ops,
+            [
+                Op::Increment(1),
+                Op::LoopStart {
+                    end: 8,
+                    id: Some(0)
+                },
+                Op::Decrement(1),
+                Op::ShiftRight(1),
+                Op::LoopStart {
+                    end: 6,
+                    id: Some(1)
+                },
+                Op::Decrement(1),
+                Op::LoopEnd {
+                    start: 4,
+                    id: Some(1)
+                },
+                Op::ShiftLeft(1),
+                Op::LoopEnd {
+                    start: 1,
+                    id: Some(0)
+                },
+                Op::PrintChar,
+            ]
+        );
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let program = Program::compile(&parse(include_str!("../test_data/aids.bf")));
+        let bytes = program.to_bytes();
+        assert_eq!(Program::from_bytes(&bytes).unwrap(), program);
+
+        let mut o = Optimizer::new(parse(include_str!("../test_data/aids.bf")));
+        o.add_pass(ZeroLoopOptimizer);
+        o.add_pass(SpecExecOptimizer::default());
+        o.optimize();
+        let program = Program::compile(&o.expr);
+        assert_eq!(Program::from_bytes(&program.to_bytes()).unwrap(), program);
+    }
+
+    #[test]
+    fn bytes_invalid() {
+        let bytes = Program::compile(&parse("+[-]")).to_bytes();
+
+        assert_eq!(Program::from_bytes(b"nope"), Err(DecodeError::BadHeader));
+        assert_eq!(
+            Program::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(DecodeError::UnexpectedEof)
+        );
+
+        let mut unknown = bytes.clone();
+        unknown.push(0xff);
+        unknown[MAGIC.len()] += 1;
+        assert_eq!(
+            Program::from_bytes(&unknown),
+            Err(DecodeError::UnknownTag(0xff))
+        );
+
+        let mut bad_jump = MAGIC.to_vec();
+        write_varint(&mut bad_jump, 1);
+        write_op(&mut bad_jump, TAG_LOOP_END, 0);
+        write_id(&mut bad_jump, None);
+        assert_eq!(
+            Program::from_bytes(&bad_jump),
+            Err(DecodeError::InvalidJump { index: 0 })
+        );
+    }
+}
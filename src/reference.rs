@@ -0,0 +1,163 @@
+use crate::{
+    codegen::CellWidth,
+    v1,
+    Handler,
+    Interpreter,
+    Lexer,
+    Parser,
+};
+use std::{
+    cell::{
+        Cell,
+        RefCell,
+    },
+    io,
+};
+
+pub const TAPE_SIZE: usize = 30_000;
+
+/// A deliberately simple interpreter with wrapping 8 bit cells and a bounded tape.
+///
+/// Reads past the end of `input` produce 0. Panics on unbalanced brackets or if the pointer leaves the tape.
+pub fn run(src: &str, input: &[u8]) -> Vec<u8> {
+    run_with_cell_width(src, input, CellWidth::U8)
+}
+
+/// Like `run`, but with cells of the given width. Output is the low byte of a cell.
+pub fn run_with_cell_width(src: &str, input: &[u8], cell_width: CellWidth) -> Vec<u8> {
+    let mask = (cell_width.modulus() - 1) as u32;
+    let program: Vec<u8> = src.bytes().filter(|b| b"+-<>.,[]".contains(b)).collect();
+
+    let mut jumps = vec![0; program.len()];
+    let mut stack = Vec::new();
+    for (i, b) in program.iter().enumerate() {
+        match b {
+            b'[' => stack.push(i),
+            b']' => {
+                let start = stack.pop().expect("unbalanced ']'");
+                jumps[start] = i;
+                jumps[i] = start;
+            }
+            _ => {}
+        }
+    }
+    assert!(stack.is_empty(), "unbalanced '['");
+
+    let mut tape = vec![0u32; TAPE_SIZE];
+    let mut ptr = 0;
+    let mut pc = 0;
+    let mut input = input.iter();
+    let mut output = Vec::new();
+
+    while pc < program.len() {
+        match program[pc] {
+            b'+' => tape[ptr] = tape[ptr].wrapping_add(1) & mask,
+            b'-' => tape[ptr] = tape[ptr].wrapping_sub(1) & mask,
+            b'>' => {
+                ptr += 1;
+                assert!(ptr < TAPE_SIZE, "pointer moved past the end of the tape");
+            }
+            b'<' => {
+                ptr = ptr
+                    .checked_sub(1)
+                    .expect("pointer moved before the start of the tape");
+            }
+            b'.' => output.push(tape[ptr] as u8),
+            b',' => tape[ptr] = u32::from(input.next().copied().unwrap_or(0)),
+            b'[' => {
+                if tape[ptr] == 0 {
+                    pc = jumps[pc];
+                }
+            }
+            b']' => {
+                if tape[ptr] != 0 {
+                    pc = jumps[pc];
+                }
+            }
+            _ => unreachable!(),
+        }
+        pc += 1;
+    }
+
+    output
+}
+
+struct ReferenceHandler<'a> {
+    input: std::slice::Iter<'a, u8>,
+    output: Vec<u8>,
+}
+
+impl Handler for ReferenceHandler<'_> {
+    fn read_char(&mut self) -> io::Result<u8> {
+        Ok(self.input.next().copied().unwrap_or(0))
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.output.push(c);
+        Ok(())
+    }
+}
+
+/// Assert that `Interpreter` and `v1::Interpreter` produce the same output as the reference interpreter.
+pub fn assert_matches_reference(src: &str, input: &[u8]) {
+    let expected = run(src, input);
+
+    let mut l = Lexer::new(src);
+    l.lex().unwrap();
+    let expr = Parser::new(l.tokens).parse().unwrap();
+
+    let mut vm = Interpreter::new(ReferenceHandler {
+        input: input.iter(),
+        output: Vec::new(),
+    });
+    vm.run(&expr).unwrap();
+    assert_eq!(vm.handler.output, expected, "Interpreter output differs");
+
+    let instructions: Vec<_> = src.chars().filter_map(v1::Instruction::from_char).collect();
+    let output = RefCell::new(Vec::new());
+    let input_pos = Cell::new(0);
+    let output_func = |c| output.borrow_mut().push(c);
+    let input_func = || {
+        let c = input.get(input_pos.get()).copied().unwrap_or(0);
+        input_pos.set(input_pos.get() + 1);
+        c
+    };
+
+    let mut vm = v1::Interpreter::new();
+    vm.set_output_func(&output_func);
+    vm.set_input_func(&input_func);
+    vm.exec(&instructions);
+    assert_eq!(
+        output.into_inner(),
+        expected,
+        "v1::Interpreter output differs"
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_data_matches_reference() {
+        let programs = [
+            include_str!("../test_data/aids.bf"),
+            include_str!("../test_data/count_down.bf"),
+            include_str!("../test_data/factorial.bf"),
+            include_str!("../test_data/hello_world1.bf"),
+            include_str!("../test_data/hello_world2.bf"),
+            include_str!("../test_data/hello_world3.bf"),
+            include_str!("../test_data/squares.bf"),
+        ];
+
+        for program in programs.iter() {
+            assert_matches_reference(program, b"");
+        }
+    }
+
+    #[test]
+    fn echo_matches_reference() {
+        assert_matches_reference(",[.,]", b"echo");
+        assert_eq!(run(",[.,]", b"echo"), b"echo");
+    }
+}
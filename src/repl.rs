@@ -0,0 +1,121 @@
+use crate::{
+    interpreter::{
+        Handler,
+        Interpreter,
+        RuntimeError,
+    },
+    lexer::LexerError,
+    parser::ParseError,
+    Lexer,
+    Parser,
+};
+use std::io;
+
+#[derive(Debug)]
+pub enum RunError {
+    Lex(LexerError),
+    Parse(ParseError),
+    Runtime(RuntimeError),
+}
+
+impl std::fmt::Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Lex(e) => e.fmt(f),
+            Self::Parse(e) => e.fmt(f),
+            Self::Runtime(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for RunError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Lex(e) => Some(e),
+            Self::Parse(e) => Some(e),
+            Self::Runtime(e) => Some(e),
+        }
+    }
+}
+
+impl From<LexerError> for RunError {
+    fn from(e: LexerError) -> Self {
+        RunError::Lex(e)
+    }
+}
+
+impl From<ParseError> for RunError {
+    fn from(e: ParseError) -> Self {
+        RunError::Parse(e)
+    }
+}
+
+impl From<RuntimeError> for RunError {
+    fn from(e: RuntimeError) -> Self {
+        RunError::Runtime(e)
+    }
+}
+
+#[derive(Default)]
+pub struct ReplHandler {
+    output: String,
+}
+
+impl Handler for ReplHandler {
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.output.push(char::from(c));
+        Ok(())
+    }
+}
+
+/// Evaluates snippets one after another against the same tape.
+pub struct Repl {
+    vm: Interpreter<ReplHandler>,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Self {
+            vm: Interpreter::new(ReplHandler::default()),
+        }
+    }
+
+    pub fn interpreter(&self) -> &Interpreter<ReplHandler> {
+        &self.vm
+    }
+
+    /// Run `src`, returning the output it produced.
+    ///
+    /// The tape and cell pointer are kept from previous snippets.
+    pub fn eval(&mut self, src: &str) -> Result<String, RunError> {
+        let mut lexer = Lexer::new(src);
+        lexer.lex()?;
+        let expr = Parser::new(lexer.tokens).parse()?;
+
+        let result = self.vm.run(&expr);
+        let output = std::mem::take(&mut self.vm.handler.output);
+        result?;
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shared_tape() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.eval("+++").unwrap(), "");
+        assert_eq!(repl.eval(".").unwrap(), "\u{3}");
+        assert_eq!(repl.eval(">++.<.").unwrap(), "\u{2}\u{3}");
+        assert_eq!(repl.interpreter().cells(), [3, 2]);
+    }
+}
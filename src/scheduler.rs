@@ -0,0 +1,192 @@
+use crate::{
+    interpreter::{
+        Handler,
+        Interpreter,
+        RuntimeError,
+        StepStatus,
+    },
+    program::Program,
+    tape::{
+        Tape,
+        VecTape,
+    },
+};
+
+/// How a program added to a `Scheduler` is doing.
+#[derive(Debug)]
+pub enum TaskStatus {
+    Running,
+    /// Waiting for input, from `Interpreter::push_input` or the handler. It's tried again every round.
+    Blocked,
+    Done,
+    Failed(RuntimeError),
+}
+
+struct Task<T, S> {
+    vm: Interpreter<T, S>,
+    program: Program,
+    status: TaskStatus,
+}
+
+/// Runs several interpreters in one thread, taking turns a few steps at a time.
+pub struct Scheduler<T, S = VecTape> {
+    tasks: Vec<Task<T, S>>,
+    slice: u64,
+}
+
+impl<T: Handler, S: Tape> Scheduler<T, S> {
+    /// Let each program run `slice` steps per turn.
+    pub fn new(slice: u64) -> Self {
+        Self {
+            tasks: Vec::new(),
+            slice: slice.max(1),
+        }
+    }
+
+    /// Add `program` to run on `vm`, returning its id.
+    pub fn spawn(&mut self, vm: Interpreter<T, S>, program: Program) -> usize {
+        self.tasks.push(Task {
+            vm,
+            program,
+            status: TaskStatus::Running,
+        });
+        self.tasks.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    pub fn status(&self, id: usize) -> &TaskStatus {
+        &self.tasks[id].status
+    }
+
+    pub fn interpreter(&self, id: usize) -> &Interpreter<T, S> {
+        &self.tasks[id].vm
+    }
+
+    /// The interpreter of a task, for example to push input to a blocked one.
+    pub fn interpreter_mut(&mut self, id: usize) -> &mut Interpreter<T, S> {
+        &mut self.tasks[id].vm
+    }
+
+    /// Give every running or blocked program a turn, in the order they were added.
+    ///
+    /// Returns whether any program made progress.
+    pub fn run_round(&mut self) -> bool {
+        let mut progress = false;
+        for task in &mut self.tasks {
+            if !matches!(task.status, TaskStatus::Running | TaskStatus::Blocked) {
+                continue;
+            }
+
+            for i in 0..self.slice {
+                match task.vm.step(&task.program) {
+                    Ok(StepStatus::Running) => task.status = TaskStatus::Running,
+                    Ok(StepStatus::Done) => task.status = TaskStatus::Done,
+                    Ok(StepStatus::Blocked) => task.status = TaskStatus::Blocked,
+                    Err(e) => task.status = TaskStatus::Failed(e),
+                }
+
+                let blocked = matches!(task.status, TaskStatus::Blocked);
+                progress |= i > 0 || !blocked;
+                if !matches!(task.status, TaskStatus::Running) {
+                    break;
+                }
+            }
+        }
+
+        progress
+    }
+
+    /// Run rounds until every program is done, failed or blocked.
+    pub fn run(&mut self) {
+        while self.run_round() {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        interpreter::RuntimeErrorKind,
+        test_util::parse,
+        *,
+    };
+    use std::{
+        cell::RefCell,
+        io,
+        rc::Rc,
+    };
+
+    /// Writes output to a log shared by every task, tagged with the task's id.
+    struct SharedLogHandler {
+        id: usize,
+        log: Rc<RefCell<Vec<(usize, u8)>>>,
+    }
+
+    impl Handler for SharedLogHandler {
+        fn write_char(&mut self, c: u8) -> io::Result<()> {
+            self.log.borrow_mut().push((self.id, c));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn round_robin() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut scheduler = Scheduler::new(2);
+        for id in 0..2 {
+            let vm = Interpreter::new(SharedLogHandler {
+                id,
+                log: log.clone(),
+            });
+            scheduler.spawn(vm, Program::compile(&parse("+.+.+.")));
+        }
+        scheduler.run();
+
+        assert_eq!(
+            *log.borrow(),
+            [(0, 1), (1, 1), (0, 2), (1, 2), (0, 3), (1, 3)]
+        );
+        assert!(matches!(scheduler.status(0), TaskStatus::Done));
+        assert!(matches!(scheduler.status(1), TaskStatus::Done));
+    }
+
+    #[test]
+    fn blocked_and_failed() {
+        let mut scheduler = Scheduler::new(10);
+        let mut echo = Interpreter::new(RecordingHandler::new());
+        echo.push_input(b"");
+        let echo = scheduler.spawn(echo, Program::compile(&parse(",[.,]")));
+        let fail = scheduler.spawn(
+            Interpreter::new(RecordingHandler::new()),
+            Program::compile(&parse("+<")),
+        );
+        let hello = scheduler.spawn(
+            Interpreter::new(RecordingHandler::new()),
+            Program::compile(&parse(include_str!("../test_data/hello_world1.bf"))),
+        );
+        scheduler.run();
+
+        assert!(matches!(scheduler.status(echo), TaskStatus::Blocked));
+        assert!(matches!(
+            scheduler.status(fail),
+            TaskStatus::Failed(e) if matches!(e.kind(), RuntimeErrorKind::PointerUnderflow { at: 0 })
+        ));
+        assert!(matches!(scheduler.status(hello), TaskStatus::Done));
+        assert_eq!(
+            scheduler.interpreter(hello).handler.output(),
+            b"Hello World!\n"
+        );
+
+        scheduler.interpreter_mut(echo).push_input(b"hi\0");
+        scheduler.run();
+        assert!(matches!(scheduler.status(echo), TaskStatus::Done));
+        assert_eq!(scheduler.interpreter(echo).handler.output(), b"hi");
+    }
+}
@@ -0,0 +1,596 @@
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Debug,
+    rc::Rc,
+};
+
+/// The type of a single cell. Arithmetic on cells wraps.
+pub trait TapeCell: Copy + Default + PartialEq + Debug {
+    /// The largest value a cell can hold.
+    const MAX: u64;
+
+    /// Convert `value` to a cell, keeping only the bits that fit.
+    fn from_u64(value: u64) -> Self;
+
+    fn to_u64(self) -> u64;
+
+    fn from_u8(value: u8) -> Self {
+        Self::from_u64(u64::from(value))
+    }
+
+    /// The low byte of this cell, which is what gets printed.
+    fn low_byte(self) -> u8 {
+        self.to_u64() as u8
+    }
+
+    /// The low byte of every cell, borrowing `cells` if they already are bytes.
+    fn to_bytes(cells: &[Self]) -> Cow<'_, [u8]> {
+        Cow::Owned(cells.iter().map(|cell| cell.low_byte()).collect())
+    }
+}
+
+impl TapeCell for u8 {
+    const MAX: u64 = u8::MAX as u64;
+
+    fn from_u64(value: u64) -> Self {
+        value as u8
+    }
+
+    fn to_u64(self) -> u64 {
+        u64::from(self)
+    }
+
+    fn to_bytes(cells: &[Self]) -> Cow<'_, [u8]> {
+        Cow::Borrowed(cells)
+    }
+}
+
+impl TapeCell for u16 {
+    const MAX: u64 = u16::MAX as u64;
+
+    fn from_u64(value: u64) -> Self {
+        value as u16
+    }
+
+    fn to_u64(self) -> u64 {
+        u64::from(self)
+    }
+}
+
+impl TapeCell for u32 {
+    const MAX: u64 = u32::MAX as u64;
+
+    fn from_u64(value: u64) -> Self {
+        value as u32
+    }
+
+    fn to_u64(self) -> u64 {
+        u64::from(self)
+    }
+}
+
+impl TapeCell for u64 {
+    const MAX: u64 = u64::MAX;
+
+    fn from_u64(value: u64) -> Self {
+        value
+    }
+
+    fn to_u64(self) -> u64 {
+        self
+    }
+}
+
+/// Storage for the cells of an `Interpreter`.
+///
+/// Every cell starts out as the fill value, which is 0 unless changed with `Tape::set_fill_value`.
+/// Reading a cell must not change how much is allocated.
+pub trait Tape {
+    type Cell: TapeCell;
+
+    fn get(&self, index: usize) -> Self::Cell;
+
+    fn set(&mut self, index: usize, value: Self::Cell);
+
+    /// One past the highest cell that was written to.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Hint that cells up to `len` are about to be used, so they can be allocated at once.
+    fn reserve(&mut self, _len: usize) {}
+
+    /// Set the value of cells that haven't been written to yet.
+    fn set_fill_value(&mut self, value: Self::Cell);
+
+    /// Forget every cell written to, so they all read as the fill value again.
+    fn clear(&mut self);
+
+    /// Roughly how many bytes the cells take up.
+    fn memory_usage(&self) -> usize {
+        self.len() * std::mem::size_of::<Self::Cell>()
+    }
+
+    /// What `memory_usage` would be after writing the cell at `index`.
+    fn memory_usage_after_set(&self, index: usize) -> usize {
+        self.len().max(index.saturating_add(1)) * std::mem::size_of::<Self::Cell>()
+    }
+
+    /// The cells up to `len` as a slice, if they are stored contiguously.
+    fn as_slice(&self) -> Option<&[Self::Cell]> {
+        None
+    }
+
+    /// The most cells the tape can hold, if it can't grow.
+    ///
+    /// The interpreter fails with `RuntimeErrorKind::TapeOverflow` instead of reaching past it.
+    fn max_len(&self) -> Option<usize> {
+        None
+    }
+
+    /// Whether indices from `usize::MAX` down are cells left of 0, so `index as isize` is the position.
+    ///
+    /// If not, moving the pointer left of 0 fails with `RuntimeErrorKind::PointerUnderflow`.
+    fn allows_negative(&self) -> bool {
+        false
+    }
+}
+
+/// A tape backed by a `Vec`, growing to fit the highest cell written to.
+#[derive(Debug, Clone, Default)]
+pub struct VecTape<C = u8> {
+    cells: Vec<C>,
+    fill: C,
+}
+
+impl VecTape {
+    /// Create a tape of 8 bit cells. Use `VecTape::default` for other widths.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<C: TapeCell> VecTape<C> {
+    pub fn cells(&self) -> &[C] {
+        &self.cells
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.cells.capacity()
+    }
+}
+
+impl<C: TapeCell> From<Vec<C>> for VecTape<C> {
+    /// A tape that starts out with `cells` written to it.
+    fn from(cells: Vec<C>) -> Self {
+        Self {
+            cells,
+            fill: C::default(),
+        }
+    }
+}
+
+impl<C: TapeCell> Tape for VecTape<C> {
+    type Cell = C;
+
+    fn get(&self, index: usize) -> C {
+        self.cells.get(index).copied().unwrap_or(self.fill)
+    }
+
+    fn set(&mut self, index: usize, value: C) {
+        if index >= self.cells.len() {
+            self.cells.resize(index + 1, self.fill);
+        }
+
+        self.cells[index] = value;
+    }
+
+    fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    fn reserve(&mut self, len: usize) {
+        self.cells.reserve(len.saturating_sub(self.cells.len()));
+    }
+
+    fn as_slice(&self) -> Option<&[C]> {
+        Some(&self.cells)
+    }
+
+    fn set_fill_value(&mut self, value: C) {
+        self.fill = value;
+    }
+
+    fn clear(&mut self) {
+        self.cells.clear();
+    }
+}
+
+/// A tape with a fixed number of cells, all allocated up front.
+///
+/// Writing past the end panics, so use it with an interpreter, which checks `Tape::max_len`.
+#[derive(Debug, Clone)]
+pub struct FixedTape<C = u8> {
+    cells: Box<[C]>,
+    len: usize,
+    fill: C,
+}
+
+impl FixedTape {
+    /// Create a tape of `len` 8 bit cells. Use `FixedTape::with_len` for other widths.
+    pub fn new(len: usize) -> Self {
+        Self::with_len(len)
+    }
+}
+
+impl<C: TapeCell> FixedTape<C> {
+    pub fn with_len(len: usize) -> Self {
+        Self {
+            cells: vec![C::default(); len].into_boxed_slice(),
+            len: 0,
+            fill: C::default(),
+        }
+    }
+}
+
+impl<C: TapeCell> Tape for FixedTape<C> {
+    type Cell = C;
+
+    fn get(&self, index: usize) -> C {
+        self.cells.get(index).copied().unwrap_or(self.fill)
+    }
+
+    fn set(&mut self, index: usize, value: C) {
+        self.cells[index] = value;
+        self.len = self.len.max(index + 1);
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.cells.len() * std::mem::size_of::<C>()
+    }
+
+    fn memory_usage_after_set(&self, _index: usize) -> usize {
+        self.memory_usage()
+    }
+
+    fn as_slice(&self) -> Option<&[C]> {
+        Some(&self.cells[..self.len])
+    }
+
+    fn max_len(&self) -> Option<usize> {
+        Some(self.cells.len())
+    }
+
+    fn set_fill_value(&mut self, value: C) {
+        self.fill = value;
+        for cell in &mut self.cells[self.len..] {
+            *cell = value;
+        }
+    }
+
+    fn clear(&mut self) {
+        let fill = self.fill;
+        for cell in self.cells.iter_mut() {
+            *cell = fill;
+        }
+        self.len = 0;
+    }
+}
+
+/// A sparse tape that only stores cells that were written to.
+///
+/// Useful for programs that touch cells far apart from each other.
+#[derive(Debug, Clone, Default)]
+pub struct HashMapTape<C = u8> {
+    cells: HashMap<usize, C>,
+    len: usize,
+    fill: C,
+}
+
+impl HashMapTape {
+    /// Create a tape of 8 bit cells. Use `HashMapTape::default` for other widths.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<C: TapeCell> HashMapTape<C> {
+    /// The bytes counted per stored cell, for its key and value.
+    const ENTRY_SIZE: usize = std::mem::size_of::<(usize, C)>();
+
+    /// The number of cells actually stored.
+    pub fn stored(&self) -> usize {
+        self.cells.len()
+    }
+}
+
+impl<C: TapeCell> Tape for HashMapTape<C> {
+    type Cell = C;
+
+    fn get(&self, index: usize) -> C {
+        self.cells.get(&index).copied().unwrap_or(self.fill)
+    }
+
+    fn set(&mut self, index: usize, value: C) {
+        self.cells.insert(index, value);
+        self.len = self.len.max(index + 1);
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.cells.len() * Self::ENTRY_SIZE
+    }
+
+    fn memory_usage_after_set(&self, index: usize) -> usize {
+        let new = usize::from(!self.cells.contains_key(&index));
+        (self.cells.len() + new) * Self::ENTRY_SIZE
+    }
+
+    fn set_fill_value(&mut self, value: C) {
+        self.fill = value;
+    }
+
+    fn clear(&mut self) {
+        self.cells.clear();
+        self.len = 0;
+    }
+}
+
+/// A tape that grows in both directions, for programs that move the pointer left of cell 0.
+///
+/// `len` only counts cells from 0 on.
+#[derive(Debug, Clone, Default)]
+pub struct BidirectionalTape<C = u8> {
+    // Cells from 0 on
+    right: Vec<C>,
+    // Cells from -1 down
+    left: Vec<C>,
+    fill: C,
+}
+
+impl BidirectionalTape {
+    /// Create a tape of 8 bit cells. Use `BidirectionalTape::default` for other widths.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<C: TapeCell> BidirectionalTape<C> {
+    /// The cells written from 0 on.
+    pub fn cells(&self) -> &[C] {
+        &self.right
+    }
+
+    /// The cells written left of 0, starting at -1.
+    pub fn negative_cells(&self) -> &[C] {
+        &self.left
+    }
+
+    /// The side `index` is on and its position there.
+    fn side(&self, index: usize) -> (bool, usize) {
+        let index = index as isize;
+        if index < 0 {
+            (true, (-(index + 1)) as usize)
+        } else {
+            (false, index as usize)
+        }
+    }
+}
+
+impl<C: TapeCell> Tape for BidirectionalTape<C> {
+    type Cell = C;
+
+    fn get(&self, index: usize) -> C {
+        let (negative, index) = self.side(index);
+        let cells = if negative { &self.left } else { &self.right };
+        cells.get(index).copied().unwrap_or(self.fill)
+    }
+
+    fn set(&mut self, index: usize, value: C) {
+        let (negative, index) = self.side(index);
+        let cells = if negative {
+            &mut self.left
+        } else {
+            &mut self.right
+        };
+
+        if index >= cells.len() {
+            cells.resize(index + 1, self.fill);
+        }
+
+        cells[index] = value;
+    }
+
+    fn len(&self) -> usize {
+        self.right.len()
+    }
+
+    fn memory_usage(&self) -> usize {
+        (self.left.len() + self.right.len()) * std::mem::size_of::<C>()
+    }
+
+    fn memory_usage_after_set(&self, index: usize) -> usize {
+        let (negative, index) = self.side(index);
+        let (left, right) = if negative {
+            (self.left.len().max(index + 1), self.right.len())
+        } else {
+            (self.left.len(), self.right.len().max(index + 1))
+        };
+
+        (left + right) * std::mem::size_of::<C>()
+    }
+
+    fn as_slice(&self) -> Option<&[C]> {
+        Some(&self.right)
+    }
+
+    fn allows_negative(&self) -> bool {
+        true
+    }
+
+    fn set_fill_value(&mut self, value: C) {
+        self.fill = value;
+    }
+
+    fn clear(&mut self) {
+        self.left.clear();
+        self.right.clear();
+    }
+}
+
+/// A tape that can be shared between several interpreters.
+///
+/// Cloning it gives another handle to the same cells.
+#[derive(Debug, Clone, Default)]
+pub struct SharedTape {
+    cells: Rc<RefCell<Vec<u8>>>,
+    fill: u8,
+}
+
+impl SharedTape {
+    pub fn new(cells: Rc<RefCell<Vec<u8>>>) -> Self {
+        Self { cells, fill: 0 }
+    }
+
+    pub fn cells(&self) -> &Rc<RefCell<Vec<u8>>> {
+        &self.cells
+    }
+}
+
+impl Tape for SharedTape {
+    type Cell = u8;
+
+    fn get(&self, index: usize) -> u8 {
+        self.cells.borrow().get(index).copied().unwrap_or(self.fill)
+    }
+
+    fn set(&mut self, index: usize, value: u8) {
+        let mut cells = self.cells.borrow_mut();
+        if index >= cells.len() {
+            cells.resize(index + 1, self.fill);
+        }
+
+        cells[index] = value;
+    }
+
+    fn len(&self) -> usize {
+        self.cells.borrow().len()
+    }
+
+    fn reserve(&mut self, len: usize) {
+        let mut cells = self.cells.borrow_mut();
+        let additional = len.saturating_sub(cells.len());
+        cells.reserve(additional);
+    }
+
+    fn set_fill_value(&mut self, value: u8) {
+        self.fill = value;
+    }
+
+    fn clear(&mut self) {
+        self.cells.borrow_mut().clear();
+    }
+}
+
+/// A fixed size tape of 8 bit cells in memory mapped storage.
+///
+/// An anonymous map only takes up memory for the pages that are written to, so a huge tape is cheap until used.
+/// A file backed map keeps the cells in the file, which has to be at least as long as the tape.
+#[cfg(feature = "memmap2")]
+#[derive(Debug)]
+pub struct MmapTape {
+    map: memmap2::MmapMut,
+    len: usize,
+    fill: u8,
+}
+
+#[cfg(feature = "memmap2")]
+impl MmapTape {
+    /// Map `len` zeroed cells that aren't backed by a file.
+    pub fn anonymous(len: usize) -> std::io::Result<Self> {
+        Ok(Self {
+            map: memmap2::MmapMut::map_anon(len)?,
+            len: 0,
+            fill: 0,
+        })
+    }
+
+    /// Map the first `len` bytes of `file` as cells. Cells start out with the bytes already in the file.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may change the file while it's mapped, see `memmap2::MmapMut::map_mut`.
+    pub unsafe fn from_file(file: &std::fs::File, len: usize) -> std::io::Result<Self> {
+        let map = memmap2::MmapOptions::new().len(len).map_mut(file)?;
+        let written = map.iter().rposition(|&c| c != 0).map_or(0, |i| i + 1);
+        Ok(Self {
+            map,
+            len: written,
+            fill: 0,
+        })
+    }
+
+    /// Write the cells back to the file, if there is one.
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.map.flush()
+    }
+}
+
+#[cfg(feature = "memmap2")]
+impl Tape for MmapTape {
+    type Cell = u8;
+
+    fn get(&self, index: usize) -> u8 {
+        if index < self.len {
+            self.map[index]
+        } else {
+            self.fill
+        }
+    }
+
+    fn set(&mut self, index: usize, value: u8) {
+        // Cells past `len` are kept at 0, so only a different fill value has to be written out
+        if index > self.len && self.fill != 0 {
+            self.map[self.len..index].fill(self.fill);
+        }
+
+        self.map[index] = value;
+        self.len = self.len.max(index + 1);
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.len
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        Some(&self.map[..self.len])
+    }
+
+    fn max_len(&self) -> Option<usize> {
+        Some(self.map.len())
+    }
+
+    fn set_fill_value(&mut self, value: u8) {
+        self.fill = value;
+    }
+
+    fn clear(&mut self) {
+        self.map[..self.len].fill(0);
+        self.len = 0;
+    }
+}
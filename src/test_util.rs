@@ -0,0 +1,12 @@
+use crate::{
+    parser::Expr,
+    Lexer,
+    Parser,
+};
+
+/// Lex and parse `data`, panicking if it isn't a valid program.
+pub(crate) fn parse(data: &str) -> Expr {
+    let mut l = Lexer::new(data);
+    l.lex().unwrap();
+    Parser::new(l.tokens).parse().unwrap()
+}
@@ -0,0 +1,195 @@
+use crate::program::{
+    write_varint,
+    DecodeError,
+    Reader,
+};
+use std::borrow::Cow;
+
+const MAGIC: &[u8; 4] = b"BFT\x01";
+
+/// Every name `Expr::name` can return except `Block`, indexed by their tag in the binary format.
+const EXPR_NAMES: [&str; 15] = [
+    "Increment",
+    "Decrement",
+    "PrintChar",
+    "ReadChar",
+    "ShiftLeft",
+    "ShiftRight",
+    "Loop",
+    "Assign",
+    "AssignCurrent",
+    "PrintString",
+    "SetCellPointer",
+    "ReadCharForget",
+    "DivMod",
+    "Comment",
+    "Breakpoint",
+];
+
+/// An expression that ran, along with the pointer and current cell right before it did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TraceEntry {
+    /// The `Expr::name` of the expression.
+    pub expr: Cow<'static, str>,
+    pub pointer: usize,
+    pub cell: u64,
+}
+
+/// Every expression run while tracing was on, see `Interpreter::set_tracing`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Trace {
+    pub entries: Vec<TraceEntry>,
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn record(&mut self, expr: &'static str, pointer: usize, cell: u64) {
+        self.entries.push(TraceEntry {
+            expr: Cow::Borrowed(expr),
+            pointer,
+            cell,
+        });
+    }
+
+    /// The index of the first entry that differs from `other`, or `None` if the traces are the same.
+    ///
+    /// If one trace is a prefix of the other, this is the length of the shorter one.
+    pub fn first_difference(&self, other: &Trace) -> Option<usize> {
+        let differs = self
+            .entries
+            .iter()
+            .zip(&other.entries)
+            .position(|(a, b)| a != b);
+
+        match differs {
+            Some(index) => Some(index),
+            None if self.entries.len() != other.entries.len() => {
+                Some(self.entries.len().min(other.entries.len()))
+            }
+            None => None,
+        }
+    }
+
+    /// Encode this trace as a header followed by a tag byte and LEB128 pointer and cell for each entry.
+    ///
+    /// Entries with names that aren't expressions are skipped.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let entries: Vec<_> = self
+            .entries
+            .iter()
+            .filter_map(|entry| {
+                let tag = EXPR_NAMES.iter().position(|name| *name == entry.expr)?;
+                Some((tag as u8, entry))
+            })
+            .collect();
+
+        let mut bytes = MAGIC.to_vec();
+        write_varint(&mut bytes, entries.len() as u64);
+        for (tag, entry) in entries {
+            bytes.push(tag);
+            write_varint(&mut bytes, entry.pointer as u64);
+            write_varint(&mut bytes, entry.cell);
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(DecodeError::BadHeader);
+        }
+
+        let len = reader.read_usize()?;
+        // Every entry is at least three bytes, so don't trust a length that can't fit
+        let mut entries = Vec::with_capacity(len.min(bytes.len() / 3));
+        for _ in 0..len {
+            let tag = reader.read_u8()?;
+            let expr = EXPR_NAMES
+                .get(usize::from(tag))
+                .ok_or(DecodeError::UnknownTag(tag))?;
+            entries.push(TraceEntry {
+                expr: Cow::Borrowed(expr),
+                pointer: reader.read_usize()?,
+                cell: reader.read_varint()?,
+            });
+        }
+
+        Ok(Self { entries })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        interpreter::DefaultHandler,
+        test_util::parse,
+        *,
+    };
+
+    fn trace(data: &str, input: &[u8]) -> Trace {
+        let mut vm = Interpreter::new(DefaultHandler);
+        vm.set_tracing(true);
+        vm.run_with_input(&parse(data), input).unwrap();
+        vm.take_trace().unwrap()
+    }
+
+    #[test]
+    fn record() {
+        let trace = trace("++[->+<]", b"");
+        let entries: Vec<_> = trace
+            .entries
+            .iter()
+            .map(|entry| (&*entry.expr, entry.pointer, entry.cell))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("Increment", 0, 0),
+                ("Loop", 0, 2),
+                ("Decrement", 0, 2),
+                ("ShiftRight", 0, 1),
+                ("Increment", 1, 0),
+                ("ShiftLeft", 1, 1),
+                ("Decrement", 0, 1),
+                ("ShiftRight", 0, 0),
+                ("Increment", 1, 1),
+                ("ShiftLeft", 1, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let trace = trace(include_str!("../test_data/squares.bf"), b"");
+        assert_eq!(Trace::from_bytes(&trace.to_bytes()).unwrap(), trace);
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&trace).unwrap();
+            assert_eq!(serde_json::from_str::<Trace>(&json).unwrap(), trace);
+        }
+
+        assert_eq!(Trace::from_bytes(b"nope"), Err(DecodeError::BadHeader));
+        assert_eq!(
+            Trace::from_bytes(b"BFT\x01\x01\xff\x00\x00"),
+            Err(DecodeError::UnknownTag(0xff))
+        );
+    }
+
+    #[test]
+    fn first_difference() {
+        let a = trace(",[->+<]", b"\x02");
+        let b = trace(",[->+<]", b"\x03");
+        assert_eq!(a.first_difference(&a), None);
+        // The read itself matches, but the loop sees a different cell
+        assert_eq!(a.first_difference(&b), Some(1));
+        assert_eq!(a.first_difference(&Trace::new()), Some(0));
+    }
+}
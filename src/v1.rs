@@ -31,17 +31,11 @@ impl Instruction {
     }
 
     pub fn is_end_loop(&self) -> bool {
-        match self {
-            Instruction::EndLoop => true,
-            _ => false,
-        }
+        matches!(self, Instruction::EndLoop)
     }
 
     pub fn is_start_loop(&self) -> bool {
-        match self {
-            Instruction::StartLoop => true,
-            _ => false,
-        }
+        matches!(self, Instruction::StartLoop)
     }
 }
 
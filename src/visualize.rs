@@ -0,0 +1,32 @@
+/// Render a tape as an 8 bit grayscale image, one pixel per cell, row-major.
+///
+/// The image is `width` pixels wide and `ceil(cells.len() / width)` pixels tall.
+/// The last row is padded with black pixels.
+pub fn tape_heatmap(cells: &[u8], width: usize) -> Vec<u8> {
+    assert!(width > 0, "heatmap width must be nonzero");
+
+    let height = cells.len().div_ceil(width);
+    let mut image = Vec::with_capacity(width * height);
+    image.extend_from_slice(cells);
+    image.resize(width * height, 0);
+
+    image
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn heatmap_dimensions() {
+        let cells = [0, 64, 128, 255, 1, 2, 3];
+
+        let image = tape_heatmap(&cells, 3);
+        assert_eq!(image.len(), 3 * 3);
+        assert_eq!(&image[..7], &cells);
+        assert_eq!(&image[7..], &[0, 0]);
+
+        assert_eq!(tape_heatmap(&cells, 7).len(), 7);
+        assert!(tape_heatmap(&[], 4).is_empty());
+    }
+}